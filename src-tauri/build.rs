@@ -1,3 +1,8 @@
 fn main() {
+  // Exposes the target triple so bundled sidecars can be located at runtime
+  println!(
+    "cargo:rustc-env=TARGET_TRIPLE={}",
+    std::env::var("TARGET").unwrap()
+  );
   tauri_build::build()
 }
@@ -0,0 +1,274 @@
+use crate::command::Output;
+use crate::probe::{MediaInfo, StreamInfo, StreamKind};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioOptions {
+    // Drops all audio from video outputs
+    pub disabled: bool,
+    // "mp3", "aac", "alac", "opus", "vorbis", "flac", "pcm" or "wma";
+    // defaults to the usual codec of the output container
+    pub codec: Option<String>,
+    // Target bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    // Encoder specific VBR quality (libmp3lame 0-9, lower is better; libvorbis -1-10)
+    pub vbr_quality: Option<f32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    // 16, 24 or 32 bits for lossless codecs
+    pub bit_depth: Option<u32>,
+    // Input stream index (as reported by the probe) of the audio track to keep;
+    // defaults to the track marked as default, then the first one
+    pub stream_index: Option<u32>,
+    // `Some(true)` requires copying the track as is, `Some(false)` always transcodes,
+    // `None` copies whenever the output container can hold the original codec
+    pub stream_copy: Option<bool>,
+}
+
+impl AudioOptions {
+    // Options that only an encoder can apply
+    pub fn requires_encoding(&self) -> bool {
+        self.codec.is_some()
+            || self.bitrate.is_some()
+            || self.vbr_quality.is_some()
+            || self.sample_rate.is_some()
+            || self.channels.is_some()
+            || self.bit_depth.is_some()
+    }
+}
+
+pub fn is_audio_format(format: &str) -> bool {
+    matches!(
+        format.to_lowercase().as_str(),
+        "mp3" | "wav" | "flac" | "aac" | "ogg" | "opus" | "wma" | "m4a"
+    )
+}
+
+// Codecs each audio container can hold, the first one is the default
+fn container_codecs(format: &str) -> &'static [&'static str] {
+    match format {
+        "mp3" => &["mp3"],
+        "wav" => &["pcm"],
+        "flac" => &["flac"],
+        "aac" => &["aac"],
+        "m4a" => &["aac", "alac"],
+        "ogg" => &["vorbis", "opus", "flac"],
+        "opus" => &["opus"],
+        "wma" => &["wma"],
+        _ => &[],
+    }
+}
+
+// Whether a stream with this codec can be muxed into the container without re-encoding
+pub fn can_copy_into(format: &str, source_codec: &str) -> bool {
+    match format {
+        "mp3" => source_codec == "mp3",
+        "wav" => source_codec.starts_with("pcm_"),
+        "flac" => source_codec == "flac",
+        "aac" => source_codec == "aac",
+        "m4a" => matches!(source_codec, "aac" | "alac"),
+        "ogg" => matches!(source_codec, "vorbis" | "opus" | "flac"),
+        "opus" => source_codec == "opus",
+        "wma" => matches!(source_codec, "wmav1" | "wmav2"),
+        _ => false,
+    }
+}
+
+/// Picks the audio track to convert from the probed input streams.
+pub fn select_audio_stream(info: &MediaInfo, stream_index: Option<u32>) -> Result<&StreamInfo, String> {
+    let mut audio_streams = info.streams.iter().filter(|s| s.kind == StreamKind::Audio);
+
+    match stream_index {
+        Some(index) => info
+            .streams
+            .iter()
+            .find(|s| s.index == index)
+            .filter(|s| s.kind == StreamKind::Audio)
+            .ok_or_else(|| format!("Input has no audio stream with index {}", index)),
+        None => audio_streams
+            .clone()
+            .find(|s| s.is_default)
+            .or_else(|| audio_streams.next())
+            .ok_or_else(|| "Input has no audio stream".to_string()),
+    }
+}
+
+/// Configures an audio-only output from the selected input track. Audio filters
+/// already on the output rule out stream copy.
+pub fn apply_audio_output(
+    output: &mut Output,
+    format: &str,
+    quality: Option<&str>,
+    options: &AudioOptions,
+    source: &StreamInfo,
+) -> Result<(), String> {
+    let format = format.to_lowercase();
+    let filtered = output.has_audio_filters();
+    output.map(format!("0:{}", source.index)).option(["-vn"]);
+
+    let source_codec = source.codec.as_deref().unwrap_or("");
+    let copy_possible = can_copy_into(&format, source_codec) && !options.requires_encoding() && !filtered;
+    match options.stream_copy {
+        Some(true) if !copy_possible => {
+            return Err(if filtered || options.requires_encoding() {
+                "Stream copy can't be combined with options that change the audio".to_string()
+            } else {
+                format!("{} audio can't be copied into .{} files without transcoding", source_codec, format)
+            });
+        }
+        Some(false) => {}
+        _ if copy_possible => {
+            log::info!("Copying {} audio stream {} without re-encoding", source_codec, source.index);
+            output.option(["-c:a", "copy"]);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    output.option(encoder_args(&format, quality, options)?);
+    Ok(())
+}
+
+/// Encoder, rate and layout arguments of an audio output format.
+pub fn encoder_args(format: &str, quality: Option<&str>, options: &AudioOptions) -> Result<Vec<String>, String> {
+    let format = format.to_lowercase();
+    let allowed = container_codecs(&format);
+    let codec = match options.codec.as_deref() {
+        Some(codec) if allowed.contains(&codec) => codec,
+        Some(codec) => {
+            return Err(format!(
+                "{} audio can't be stored in .{} files (supported: {})",
+                codec,
+                format,
+                allowed.join(", ")
+            ))
+        }
+        None => allowed
+            .first()
+            .ok_or_else(|| format!("{} is not an audio format", format))?,
+    };
+
+    let mut args = codec_args(codec, quality, options)?;
+
+    if let Some(sample_rate) = options.sample_rate {
+        // libopus only accepts the rates Opus is defined for
+        if codec == "opus" && ![8000, 12000, 16000, 24000, 48000].contains(&sample_rate) {
+            return Err(format!(
+                "Opus doesn't support a {} Hz sample rate (use 8000, 12000, 16000, 24000 or 48000)",
+                sample_rate
+            ));
+        }
+        args.extend(["-ar".to_string(), sample_rate.to_string()]);
+    }
+
+    if let Some(channels) = options.channels {
+        if channels == 0 || channels > 8 {
+            return Err(format!("Invalid channel count: {}", channels));
+        }
+        args.extend(["-ac".to_string(), channels.to_string()]);
+    }
+
+    Ok(args)
+}
+
+fn codec_args(codec: &str, quality: Option<&str>, options: &AudioOptions) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+
+    if options.bit_depth.is_some() && !matches!(codec, "pcm" | "flac" | "alac") {
+        return Err(format!("Bit depth can only be set for lossless codecs, not {}", codec));
+    }
+
+    match codec {
+        "mp3" => {
+            args.extend(["-c:a".to_string(), "libmp3lame".to_string()]);
+            match (options.vbr_quality, options.bitrate) {
+                (Some(q), _) => {
+                    if !(0.0..=9.0).contains(&q) {
+                        return Err(format!("MP3 VBR quality must be between 0 and 9, got {}", q));
+                    }
+                    args.extend(["-q:a".to_string(), q.to_string()]);
+                }
+                (None, bitrate) => {
+                    let kbps = bitrate.unwrap_or_else(|| preset_bitrate(quality, [128, 192, 320]));
+                    args.extend(["-b:a".to_string(), format!("{}k", kbps)]);
+                }
+            }
+        }
+        "vorbis" => {
+            args.extend(["-c:a".to_string(), "libvorbis".to_string()]);
+            match (options.vbr_quality, options.bitrate) {
+                (Some(q), _) => {
+                    if !(-1.0..=10.0).contains(&q) {
+                        return Err(format!("Vorbis quality must be between -1 and 10, got {}", q));
+                    }
+                    args.extend(["-q:a".to_string(), q.to_string()]);
+                }
+                (None, Some(kbps)) => args.extend(["-b:a".to_string(), format!("{}k", kbps)]),
+                (None, None) => {
+                    let q = preset_bitrate(quality, [3, 5, 8]);
+                    args.extend(["-q:a".to_string(), q.to_string()]);
+                }
+            }
+        }
+        "aac" | "opus" | "wma" => {
+            let (encoder, name, presets) = match codec {
+                "aac" => ("aac", "AAC", [96, 160, 256]),
+                "opus" => ("libopus", "Opus", [64, 128, 192]),
+                _ => ("wmav2", "WMA", [96, 128, 192]),
+            };
+            if options.vbr_quality.is_some() {
+                return Err(format!("The {} encoder is bitrate driven, set a bitrate instead of a VBR quality", name));
+            }
+            let kbps = options.bitrate.unwrap_or_else(|| preset_bitrate(quality, presets));
+            args.extend([
+                "-c:a".to_string(),
+                encoder.to_string(),
+                "-b:a".to_string(),
+                format!("{}k", kbps),
+            ]);
+        }
+        "flac" | "alac" => {
+            args.extend(["-c:a".to_string(), codec.to_string()]);
+            // Both store 24 bit audio in 32 bit samples and need the real depth as a hint
+            match options.bit_depth {
+                None => {}
+                Some(16) => args.extend(["-sample_fmt".to_string(), sample_format(codec, 16)]),
+                Some(depth @ (24 | 32)) => args.extend([
+                    "-sample_fmt".to_string(),
+                    sample_format(codec, 32),
+                    "-bits_per_raw_sample".to_string(),
+                    depth.to_string(),
+                ]),
+                Some(depth) => return Err(format!("Unsupported bit depth for {}: {}", codec, depth)),
+            }
+        }
+        "pcm" => {
+            let encoder = match options.bit_depth.unwrap_or(16) {
+                16 => "pcm_s16le",
+                24 => "pcm_s24le",
+                32 => "pcm_s32le",
+                depth => return Err(format!("Unsupported bit depth for WAV: {}", depth)),
+            };
+            args.extend(["-c:a".to_string(), encoder.to_string()]);
+        }
+        _ => return Err(format!("Unsupported audio codec: {}", codec)),
+    }
+
+    Ok(args)
+}
+
+// FLAC encodes from packed samples, ALAC from planar ones
+fn sample_format(codec: &str, bits: u32) -> String {
+    let planar = if codec == "alac" { "p" } else { "" };
+    format!("s{}{}", bits, planar)
+}
+
+// Maps the low/medium/high quality presets onto encoder specific values
+fn preset_bitrate(quality: Option<&str>, [low, medium, high]: [u32; 3]) -> u32 {
+    match quality {
+        Some("low") => low,
+        Some("high") => high,
+        _ => medium,
+    }
+}
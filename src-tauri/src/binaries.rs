@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+// Windows-specific imports for hiding CMD windows
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+
+// Windows constant for hiding console window
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+// Target triple the app was built for, used in bundled sidecar names
+const TARGET_TRIPLE: &str = env!("TARGET_TRIPLE");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binary {
+    Ffmpeg,
+    Ffprobe,
+}
+
+impl Binary {
+    fn name(self) -> &'static str {
+        match self {
+            Binary::Ffmpeg => "ffmpeg",
+            Binary::Ffprobe => "ffprobe",
+        }
+    }
+
+    fn env_var(self) -> &'static str {
+        match self {
+            Binary::Ffmpeg => "FFMPEG_PATH",
+            Binary::Ffprobe => "FFPROBE_PATH",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinarySource {
+    Override,
+    Environment,
+    Bundled,
+    System,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryInfo {
+    pub path: PathBuf,
+    pub source: BinarySource,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FfmpegInfo {
+    pub ffmpeg: BinaryInfo,
+    pub ffprobe: BinaryInfo,
+}
+
+struct BinaryCache {
+    ffmpeg: Option<BinaryInfo>,
+    ffprobe: Option<BinaryInfo>,
+    ffmpeg_override: Option<PathBuf>,
+    ffprobe_override: Option<PathBuf>,
+    // Bumped by every override change, so a lookup that started before it
+    // doesn't cache its outdated result
+    generation: u64,
+}
+
+impl BinaryCache {
+    fn resolved(&mut self, binary: Binary) -> &mut Option<BinaryInfo> {
+        match binary {
+            Binary::Ffmpeg => &mut self.ffmpeg,
+            Binary::Ffprobe => &mut self.ffprobe,
+        }
+    }
+
+    fn override_path(&mut self, binary: Binary) -> &mut Option<PathBuf> {
+        match binary {
+            Binary::Ffmpeg => &mut self.ffmpeg_override,
+            Binary::Ffprobe => &mut self.ffprobe_override,
+        }
+    }
+}
+
+static BINARIES: Mutex<BinaryCache> = Mutex::new(BinaryCache {
+    ffmpeg: None,
+    ffprobe: None,
+    ffmpeg_override: None,
+    ffprobe_override: None,
+    generation: 0,
+});
+
+// Lets one async caller at a time resolve, the others wait for its result
+static RESOLVING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub fn get_ffmpeg_path() -> Result<PathBuf, String> {
+    resolve(Binary::Ffmpeg).map(|info| info.path)
+}
+
+/// Finds a working binary, in order: settings override, environment variable,
+/// bundled sidecar next to the executable, then `PATH`. The result is cached.
+/// Blocks while the candidates run `-version`; async code uses `resolve_async`.
+pub fn resolve(binary: Binary) -> Result<BinaryInfo, String> {
+    let (override_path, generation) = {
+        let mut cache = BINARIES.lock().unwrap();
+        if let Some(info) = cache.resolved(binary).clone() {
+            return Ok(info);
+        }
+        (cache.override_path(binary).clone(), cache.generation)
+    };
+
+    // The lock isn't held while the candidates run
+    let info = locate(binary, override_path)?;
+    log::info!(
+        "Using {} from {} ({:?}): {}",
+        binary.name(),
+        info.path.display(),
+        info.source,
+        info.version
+    );
+    let mut cache = BINARIES.lock().unwrap();
+    if cache.generation == generation {
+        *cache.resolved(binary) = Some(info.clone());
+    }
+    Ok(info)
+}
+
+/// `resolve` on the blocking pool, returning right away once cached.
+pub async fn resolve_async(binary: Binary) -> Result<BinaryInfo, String> {
+    if let Some(info) = cached(binary) {
+        return Ok(info);
+    }
+    let _resolving = RESOLVING.lock().await;
+    if let Some(info) = cached(binary) {
+        return Ok(info);
+    }
+    tauri::async_runtime::spawn_blocking(move || resolve(binary))
+        .await
+        .map_err(|e| format!("Failed to resolve {}: {}", binary.name(), e))?
+}
+
+pub async fn ffprobe_path() -> Result<PathBuf, String> {
+    resolve_async(Binary::Ffprobe).await.map(|info| info.path)
+}
+
+fn cached(binary: Binary) -> Option<BinaryInfo> {
+    BINARIES.lock().unwrap().resolved(binary).clone()
+}
+
+/// Sets or clears the user-chosen binary path and drops the cached resolution.
+pub fn set_override(binary: Binary, path: Option<PathBuf>) {
+    let mut cache = BINARIES.lock().unwrap();
+    *cache.override_path(binary) = path;
+    *cache.resolved(binary) = None;
+    cache.generation += 1;
+}
+
+fn locate(binary: Binary, override_path: Option<PathBuf>) -> Result<BinaryInfo, String> {
+    // An explicitly configured binary must work, silently using another one
+    // would hide the misconfiguration
+    if let Some(path) = override_path {
+        return validate(&path, BinarySource::Override).ok_or_else(|| {
+            format!("Configured {} at {} is not working", binary.name(), path.display())
+        });
+    }
+
+    if let Some(path) = std::env::var_os(binary.env_var()).filter(|p| !p.is_empty()) {
+        let path = PathBuf::from(path);
+        return validate(&path, BinarySource::Environment).ok_or_else(|| {
+            format!("{} points to {}, which is not a working {}", binary.env_var(), path.display(), binary.name())
+        });
+    }
+
+    for path in bundled_candidates(binary) {
+        if path.is_file() {
+            if let Some(info) = validate(&path, BinarySource::Bundled) {
+                return Ok(info);
+            }
+            log::warn!("Bundled {} at {} is not working", binary.name(), path.display());
+        }
+    }
+
+    for path in path_candidates(binary) {
+        if let Some(info) = validate(&path, BinarySource::System) {
+            return Ok(info);
+        }
+    }
+
+    Err(format!(
+        "{} not found. Install it, add it to PATH or set {}.",
+        binary.name(),
+        binary.env_var()
+    ))
+}
+
+// Sidecars are bundled as `<name>-<target triple>`, either next to the executable
+// or in its `bin` folder
+fn bundled_candidates(binary: Binary) -> Vec<PathBuf> {
+    let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    else {
+        return Vec::new();
+    };
+
+    let exe_suffix = std::env::consts::EXE_SUFFIX;
+    let names = [
+        format!("{}-{}{}", binary.name(), TARGET_TRIPLE, exe_suffix),
+        format!("{}-{}", binary.name(), TARGET_TRIPLE),
+        format!("{}{}", binary.name(), exe_suffix),
+    ];
+
+    [exe_dir.clone(), exe_dir.join("bin")]
+        .iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .collect()
+}
+
+fn path_candidates(binary: Binary) -> Vec<PathBuf> {
+    let file_name = format!("{}{}", binary.name(), std::env::consts::EXE_SUFFIX);
+    std::env::var_os("PATH")
+        .map(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(&file_name))
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Runs `-version` and returns the first line of its output if the binary works
+fn validate(path: &Path, source: BinarySource) -> Option<BinaryInfo> {
+    let mut cmd = Command::new(path);
+    cmd.arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    // Hide CMD window on Windows
+    #[cfg(windows)]
+    cmd.creation_flags(CREATE_NO_WINDOW);
+
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let version = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or("Unknown version")
+        .to_string();
+
+    Some(BinaryInfo {
+        path: path.to_path_buf(),
+        source,
+        version,
+    })
+}
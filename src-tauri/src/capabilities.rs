@@ -0,0 +1,427 @@
+use crate::binaries::get_ffmpeg_path;
+use crate::video;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+// Windows-specific imports for hiding CMD windows
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+
+// Windows constant for hiding console window
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncoderKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Encoder {
+    pub name: String,
+    pub kind: EncoderKind,
+    pub description: String,
+}
+
+/// What the detected FFmpeg build can produce
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub encoders: Vec<Encoder>,
+    pub muxers: BTreeSet<String>,
+    pub filters: BTreeSet<String>,
+    pub hwaccels: BTreeSet<String>,
+    // Output formats from `OUTPUT_FORMATS` this build can write
+    pub formats: Vec<String>,
+}
+
+// Output format, the muxer it needs and the encoders (any of them) that can
+// fill it. Video formats take any encoder of a codec the container holds,
+// which `video::container_encoders` adds
+const OUTPUT_FORMATS: &[(&str, &str, &[&str])] = &[
+    // Video formats
+    ("mp4", "mp4", &[]),
+    ("avi", "avi", &[]),
+    ("mov", "mov", &[]),
+    ("mkv", "matroska", &[]),
+    ("webm", "webm", &[]),
+    ("flv", "flv", &[]),
+    ("wmv", "asf", &[]),
+    ("m4v", "ipod", &[]),
+    // Audio formats
+    ("mp3", "mp3", &["libmp3lame"]),
+    ("wav", "wav", &["pcm_s16le"]),
+    ("flac", "flac", &["flac"]),
+    ("aac", "adts", &["aac"]),
+    ("ogg", "ogg", &["libvorbis", "libopus"]),
+    ("opus", "opus", &["libopus"]),
+    ("wma", "asf", &["wmav2"]),
+    ("m4a", "ipod", &["aac"]),
+    // Image formats
+    ("jpg", "image2", &["mjpeg"]),
+    ("jpeg", "image2", &["mjpeg"]),
+    ("png", "image2", &["png"]),
+    ("webp", "webp", &["libwebp"]),
+    ("bmp", "image2", &["bmp"]),
+    ("gif", "gif", &["gif"]),
+    ("tiff", "image2", &["tiff"]),
+    ("ico", "ico", &["png"]),
+];
+
+struct CapabilityCache {
+    capabilities: Option<Arc<Capabilities>>,
+    // Bumped by `invalidate`, so a detection that started before it doesn't
+    // cache what the previous binary supports
+    generation: u64,
+}
+
+static CAPABILITIES: Mutex<CapabilityCache> = Mutex::new(CapabilityCache {
+    capabilities: None,
+    generation: 0,
+});
+
+// Lets one async caller at a time detect, the others wait for its result
+static DETECTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+impl Capabilities {
+    pub fn has_encoder(&self, name: &str) -> bool {
+        self.encoders.iter().any(|encoder| encoder.name == name)
+    }
+
+    pub fn has_muxer(&self, name: &str) -> bool {
+        self.muxers.contains(name)
+    }
+
+    fn can_write(&self, format: &str) -> bool {
+        OUTPUT_FORMATS
+            .iter()
+            .find(|(name, _, _)| *name == format)
+            .is_some_and(|(_, muxer, encoders)| {
+                self.has_muxer(muxer)
+                    && encoders
+                        .iter()
+                        .copied()
+                        .chain(video::container_encoders(format))
+                        .any(|encoder| self.has_encoder(encoder))
+            })
+    }
+}
+
+/// Returns the cached capabilities, detecting them on first use. Detection
+/// runs FFmpeg and blocks; async code uses `load`.
+pub fn capabilities() -> Result<Arc<Capabilities>, String> {
+    let generation = {
+        let cache = CAPABILITIES.lock().unwrap();
+        if let Some(capabilities) = cache.capabilities.as_ref() {
+            return Ok(capabilities.clone());
+        }
+        cache.generation
+    };
+
+    // The lock isn't held while FFmpeg runs
+    let capabilities = Arc::new(detect()?);
+    log::info!(
+        "Detected FFmpeg capabilities: {} encoders, {} muxers, {} filters, hwaccels: {:?}",
+        capabilities.encoders.len(),
+        capabilities.muxers.len(),
+        capabilities.filters.len(),
+        capabilities.hwaccels
+    );
+    let mut cache = CAPABILITIES.lock().unwrap();
+    if cache.generation == generation {
+        cache.capabilities = Some(capabilities.clone());
+    }
+    Ok(capabilities)
+}
+
+/// `capabilities` on the blocking pool, returning right away once cached.
+pub async fn load() -> Result<Arc<Capabilities>, String> {
+    if let Some(capabilities) = cached() {
+        return Ok(capabilities);
+    }
+    let _detecting = DETECTING.lock().await;
+    if let Some(capabilities) = cached() {
+        return Ok(capabilities);
+    }
+    tauri::async_runtime::spawn_blocking(capabilities)
+        .await
+        .map_err(|e| format!("Capability detection failed: {}", e))?
+}
+
+/// The capabilities if they were already detected, never runs FFmpeg.
+pub fn cached() -> Option<Arc<Capabilities>> {
+    CAPABILITIES.lock().unwrap().capabilities.clone()
+}
+
+/// Forgets the cached capabilities, e.g. after another FFmpeg binary was selected.
+pub fn invalidate() {
+    let mut cache = CAPABILITIES.lock().unwrap();
+    cache.capabilities = None;
+    cache.generation += 1;
+}
+
+/// Fails with a readable message when the FFmpeg build lacks `encoder`. If the
+/// capabilities can't be detected the check is skipped and FFmpeg reports the problem.
+pub async fn require_encoder(encoder: &str, purpose: &str) -> Result<(), String> {
+    match load().await {
+        Ok(capabilities) if !capabilities.has_encoder(encoder) => Err(format!(
+            "The installed FFmpeg has no {} encoder, which is required for {}",
+            encoder, purpose
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn supported_formats() -> Vec<&'static str> {
+    match capabilities() {
+        Ok(capabilities) => OUTPUT_FORMATS
+            .iter()
+            .map(|(format, _, _)| *format)
+            .filter(|format| capabilities.can_write(format))
+            .collect(),
+        Err(e) => {
+            log::warn!("Could not detect FFmpeg capabilities, listing all formats: {}", e);
+            OUTPUT_FORMATS.iter().map(|(format, _, _)| *format).collect()
+        }
+    }
+}
+
+fn detect() -> Result<Capabilities, String> {
+    let encoders = parse_encoders(&run_listing("-encoders")?);
+    let muxers = parse_muxers(&run_listing("-muxers")?);
+    let filters = parse_filters(&run_listing("-filters")?);
+    let hwaccels = parse_hwaccels(&run_listing("-hwaccels")?);
+
+    let mut capabilities = Capabilities {
+        encoders,
+        muxers,
+        filters,
+        hwaccels,
+        formats: Vec::new(),
+    };
+    capabilities.formats = OUTPUT_FORMATS
+        .iter()
+        .filter(|(format, _, _)| capabilities.can_write(format))
+        .map(|(format, _, _)| format.to_string())
+        .collect();
+    Ok(capabilities)
+}
+
+fn run_listing(flag: &str) -> Result<String, String> {
+    let ffmpeg_path = get_ffmpeg_path()?;
+
+    let mut cmd = Command::new(&ffmpeg_path);
+    cmd.args(["-hide_banner", flag])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    // Hide CMD window on Windows
+    #[cfg(windows)]
+    cmd.creation_flags(CREATE_NO_WINDOW);
+
+    let output = cmd.output()
+        .map_err(|e| format!("Failed to execute FFmpeg: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("ffmpeg {} failed with exit code: {:?}", flag, output.status.code()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Lines after the " ------" separator look like " V....D libx264   libx264 H.264 ..."
+fn parse_encoders(output: &str) -> Vec<Encoder> {
+    let mut encoders: Vec<Encoder> = output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let flags = parts.next()?;
+            let name = parts.next()?;
+            let kind = match flags.chars().next()? {
+                'V' => EncoderKind::Video,
+                'A' => EncoderKind::Audio,
+                'S' => EncoderKind::Subtitle,
+                _ => return None,
+            };
+            Some(Encoder {
+                name: name.to_string(),
+                kind,
+                description: parts.collect::<Vec<_>>().join(" "),
+            })
+        })
+        .collect();
+    encoders.sort();
+    encoders
+}
+
+// Lines after the " --" separator look like "  E mp4   MP4 (MPEG-4 Part 14)"
+fn parse_muxers(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("--"))
+        .skip(1)
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let flags = parts.next()?;
+            let names = parts.next()?;
+            flags.contains('E').then_some(names)
+        })
+        .flat_map(|names| names.split(','))
+        .map(str::to_string)
+        .collect()
+}
+
+// Filter lines look like " TSC scale   V->V   Scale the input video size..."
+fn parse_filters(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let _flags = parts.next()?;
+            let name = parts.next()?;
+            let io = parts.next()?;
+            io.contains("->").then(|| name.to_string())
+        })
+        .collect()
+}
+
+// One method per line after the "Hardware acceleration methods:" header
+fn parse_hwaccels(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .skip_while(|line| !line.trim_end().ends_with(':'))
+        .skip(1)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_encoders() {
+        let output = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ S..... = Subtitle
+ .F.... = Frame-level multithreading
+ ..S... = Slice-level multithreading
+ ...X.. = Codec is experimental
+ ....B. = Supports draw_horiz_band
+ .....D = Supports direct rendering method 1
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ V..... h264_nvenc           NVIDIA NVENC H.264 encoder (codec h264)
+ A....D aac                  AAC (Advanced Audio Coding)
+ A..X.D opus                 Opus
+ S..... srt                  SubRip subtitle
+";
+        let encoders = parse_encoders(output);
+        let names: Vec<_> = encoders.iter().map(|encoder| (encoder.name.as_str(), encoder.kind)).collect();
+        assert_eq!(
+            names,
+            [
+                ("aac", EncoderKind::Audio),
+                ("h264_nvenc", EncoderKind::Video),
+                ("libx264", EncoderKind::Video),
+                ("opus", EncoderKind::Audio),
+                ("srt", EncoderKind::Subtitle),
+            ]
+        );
+        assert_eq!(encoders[2].description, "libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)");
+    }
+
+    #[test]
+    fn parses_muxers() {
+        // Older builds separate the legend with "--", newer ones add a device column
+        let listings = [
+            "\
+File formats:
+ D. = Demuxing supported
+ .E = Muxing supported
+ --
+ DE matroska,webm    Matroska / WebM
+  E mp4             MP4 (MPEG-4 Part 14)
+ D  mov,mp4,m4a,3gp,3g2,mj2 QuickTime / MOV
+  E webm            WebM
+",
+            "\
+ Formats:
+ D.. = Demuxing supported
+ .E. = Muxing supported
+ ..d = Is a device
+ ---
+ DE  matroska,webm   Matroska / WebM
+  E  mp4             MP4 (MPEG-4 Part 14)
+ D   mov,mp4,m4a,3gp,3g2,mj2 QuickTime / MOV
+  E  webm            WebM
+ DEd v4l2            Video4Linux2 output device
+",
+        ];
+        for (i, output) in listings.into_iter().enumerate() {
+            let muxers = parse_muxers(output);
+            assert!(["matroska", "mp4", "webm"].iter().all(|name| muxers.contains(*name)), "{}", i);
+            assert!(!muxers.contains("mov"), "{}", i);
+            assert!(!muxers.contains("="), "{}", i);
+        }
+        assert!(parse_muxers(listings[1]).contains("v4l2"));
+    }
+
+    #[test]
+    fn writes_video_formats_with_any_codec_they_hold() {
+        let capabilities = |encoders: &[&str]| Capabilities {
+            encoders: encoders
+                .iter()
+                .map(|name| Encoder { name: name.to_string(), kind: EncoderKind::Video, description: String::new() })
+                .collect(),
+            muxers: ["mp4", "matroska", "webm", "flv", "mp3"].into_iter().map(String::from).collect(),
+            ..Default::default()
+        };
+
+        // No libx264, but x265 still fills mp4 and mkv
+        let x265 = capabilities(&["libx265"]);
+        assert!(x265.can_write("mp4") && x265.can_write("mkv"));
+        assert!(!x265.can_write("webm") && !x265.can_write("flv"));
+
+        let av1 = capabilities(&["libaom-av1"]);
+        assert!(av1.can_write("webm") && av1.can_write("mp4"));
+        // Formats without the muxer or encoder stay out
+        assert!(!av1.can_write("mov") && !av1.can_write("mp3"));
+    }
+
+    #[test]
+    fn parses_filters_and_hwaccels() {
+        let filters = parse_filters(
+            "\
+Filters:
+  T.. = Timeline support
+  .S. = Slice threading
+  ..C = Command support
+  A = Audio input/output
+  V = Video input/output
+  N = Dynamic number and/or type of input/output
+  | = Source or sink filter
+ ... abuffer           |->A       Buffer audio frames, and make them accessible to the filterchain.
+ TSC scale             V->V       Scale the input video size and/or convert the image format.
+ T.. zscale            V->V       Apply resizing, colorspace and bit depth conversion.
+ ... concat            N->N       Concatenate audio and video streams.
+",
+        );
+        assert_eq!(filters.into_iter().collect::<Vec<_>>(), ["abuffer", "concat", "scale", "zscale"]);
+
+        let hwaccels = parse_hwaccels("Hardware acceleration methods:\nvdpau\ncuda\nvaapi\n\n");
+        assert_eq!(hwaccels.into_iter().collect::<Vec<_>>(), ["cuda", "vaapi", "vdpau"]);
+        assert!(parse_hwaccels("Hardware acceleration methods:\n\n").is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+
+/// FFmpeg argument list built from its parts, always rendered in the same order:
+/// global options, inputs (their options before `-i`), then outputs (stream
+/// maps, filter chains and options before the output path).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FfmpegCommand {
+    global: Vec<String>,
+    inputs: Vec<Input>,
+    outputs: Vec<Output>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Input {
+    options: Vec<String>,
+    path: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Output {
+    maps: Vec<String>,
+    // Stream specifier the video filters apply to, all video streams when unset
+    video_filter_stream: Option<String>,
+    video_filters: Vec<String>,
+    audio_filters: Vec<String>,
+    options: Vec<String>,
+    path: String,
+}
+
+impl FfmpegCommand {
+    /// New command that overwrites existing outputs.
+    pub fn new() -> Self {
+        let mut command = Self::default();
+        command.global(["-y", "-hide_banner"]);
+        command
+    }
+
+    pub fn global<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.global.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Writes `key=value` progress blocks to stdout.
+    pub fn with_progress(&mut self) -> &mut Self {
+        self.global(["-progress", "pipe:1", "-nostats"])
+    }
+
+    pub fn input(&mut self, path: impl Into<String>) -> &mut Input {
+        self.inputs.push(Input {
+            options: Vec::new(),
+            path: path.into(),
+        });
+        self.inputs.last_mut().unwrap()
+    }
+
+    pub fn output(&mut self, path: impl Into<String>) -> &mut Output {
+        self.outputs.push(Output {
+            path: path.into(),
+            ..Output::default()
+        });
+        self.outputs.last_mut().unwrap()
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    /// The output added last, to add options to an already built command.
+    pub fn last_output(&mut self) -> Option<&mut Output> {
+        self.outputs.last_mut()
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = self.global.clone();
+
+        for input in &self.inputs {
+            args.extend(input.options.iter().cloned());
+            args.extend(["-i".to_string(), input.path.clone()]);
+        }
+
+        for output in &self.outputs {
+            for map in &output.maps {
+                args.extend(["-map".to_string(), map.clone()]);
+            }
+            if !output.video_filters.is_empty() {
+                let stream = output.video_filter_stream.as_deref().unwrap_or("v");
+                args.extend([format!("-filter:{}", stream), output.video_filters.join(",")]);
+            }
+            if !output.audio_filters.is_empty() {
+                args.extend(["-filter:a".to_string(), output.audio_filters.join(",")]);
+            }
+            args.extend(output.options.iter().cloned());
+            args.push(output.path.clone());
+        }
+
+        args
+    }
+
+    /// Shell-like rendering of the full command line, for logs and dry runs.
+    pub fn to_command_line(&self, program: &Path) -> String {
+        std::iter::once(program.to_string_lossy().to_string())
+            .chain(self.to_args())
+            .map(|arg| quote_arg(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Input {
+    // Options placed before `-i`, e.g. `-ss` for input seeking
+    pub fn option<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.extend(args.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Output {
+    pub fn map(&mut self, spec: impl Into<String>) -> &mut Self {
+        self.maps.push(spec.into());
+        self
+    }
+
+    pub fn video_filter(&mut self, filter: impl Into<String>) -> &mut Self {
+        self.video_filters.push(filter.into());
+        self
+    }
+
+    /// Limits the video filters to one stream, e.g. "v:0", so other video
+    /// streams of the output can still be copied.
+    pub fn video_filter_stream(&mut self, spec: impl Into<String>) -> &mut Self {
+        self.video_filter_stream = Some(spec.into());
+        self
+    }
+
+    pub fn audio_filter(&mut self, filter: impl Into<String>) -> &mut Self {
+        self.audio_filters.push(filter.into());
+        self
+    }
+
+    pub fn option<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn has_audio_filters(&self) -> bool {
+        !self.audio_filters.is_empty()
+    }
+
+    /// Encoders selected by any `-c:<stream>` option, including "copy".
+    pub fn codecs(&self) -> impl Iterator<Item = &str> {
+        self.options
+            .windows(2)
+            .filter(|pair| pair[0].starts_with("-c:"))
+            .map(|pair| pair[1].as_str())
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.options.iter().any(|arg| arg == flag)
+    }
+}
+
+// Single quotes keep everything literal in POSIX shells, including `$`,
+// backticks and globs; a quote inside is closed, escaped and reopened
+#[cfg(not(windows))]
+fn quote_arg(arg: &str) -> String {
+    let needs_quotes = arg.is_empty()
+        || arg.chars().any(|c| {
+            c.is_whitespace()
+                || matches!(
+                    c,
+                    '"' | '\'' | '\\' | ';' | '&' | '|' | '(' | ')' | '[' | ']' | '{' | '}' | '<' | '>'
+                        | '$' | '`' | '*' | '?' | '~' | '!' | '#'
+                )
+        });
+    if needs_quotes {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    } else {
+        arg.to_string()
+    }
+}
+
+// cmd.exe only understands double quotes
+#[cfg(windows)]
+fn quote_arg(arg: &str) -> String {
+    let needs_quotes = arg.is_empty()
+        || arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | ';' | '&' | '|' | '(' | ')' | '[' | ']' | '$'));
+    if needs_quotes {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_parts_in_a_fixed_order() {
+        let mut command = FfmpegCommand::new();
+        command.with_progress();
+        command.input("in.mkv");
+        command
+            .output("out.mp4")
+            .option(["-c:v", "libx264"])
+            .video_filter("scale=1280:720")
+            .map("0:v:0")
+            .audio_filter("atempo=2")
+            .video_filter("setpts=PTS/2");
+
+        assert_eq!(
+            command.to_args(),
+            [
+                "-y", "-hide_banner", "-progress", "pipe:1", "-nostats",
+                "-i", "in.mkv",
+                "-map", "0:v:0",
+                "-filter:v", "scale=1280:720,setpts=PTS/2",
+                "-filter:a", "atempo=2",
+                "-c:v", "libx264",
+                "out.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn quotes_arguments_for_the_command_line() {
+        let mut command = FfmpegCommand::new();
+        command.input("my video.mov");
+        command.output("out.mp4").video_filter("crop=10:10:0:0");
+
+        assert_eq!(
+            command.to_command_line(Path::new("ffmpeg")),
+            "ffmpeg -y -hide_banner -i 'my video.mov' -filter:v crop=10:10:0:0 out.mp4"
+        );
+
+        let cases = [
+            ("a$HOME.mp4", "'a$HOME.mp4'"),
+            ("$(rm -rf x).mp4", "'$(rm -rf x).mp4'"),
+            ("`id`.mp4", "'`id`.mp4'"),
+            ("clip*.mp4", "'clip*.mp4'"),
+            ("take?.mp4", "'take?.mp4'"),
+            ("~/out.mp4", "'~/out.mp4'"),
+            ("it's.mp4", "'it'\\''s.mp4'"),
+            ("say \"hi\".mp4", "'say \"hi\".mp4'"),
+            ("", "''"),
+            ("scale=1280:-2", "scale=1280:-2"),
+        ];
+        for (arg, expected) in cases {
+            assert_eq!(quote_arg(arg), expected, "{}", arg);
+        }
+    }
+
+    #[test]
+    fn finds_selected_codecs() {
+        let mut command = FfmpegCommand::new();
+        command.output("out.webm").option(["-c:v", "libvpx-vp9", "-an", "-c:2", "copy"]);
+        let output = &command.outputs()[0];
+
+        assert_eq!(output.codecs().collect::<Vec<_>>(), ["libvpx-vp9", "copy"]);
+        assert!(output.has_flag("-an"));
+    }
+}
@@ -1,11 +1,17 @@
-use crate::ffmpeg::{convert_media, ensure_ffmpeg, get_media_info, get_supported_formats, cancel_conversion as cancel_ffmpeg_conversion, MediaInfo};
-use tauri::{AppHandle, State};
+use crate::binaries::{ffprobe_path, set_override, Binary, FfmpegInfo};
+use crate::capabilities::{self, invalidate as invalidate_capabilities, supported_formats, Capabilities};
+use crate::concat::ConcatRequest;
+use crate::error::ConversionError;
+use crate::crop_detect::CropDetection;
+use crate::ffmpeg::{detect_black_borders, ensure_ffmpeg, preview_command, remux_plan, webp_output_path};
+use crate::probe::{get_media_info, MediaInfo};
+use crate::queue::{ConversionJob, ConversionQueue, JobId};
+use crate::remux::StreamPlan;
+use crate::request::{ConversionOptions, ConversionRequest, FieldError};
+use crate::split::SplitRequest;
+use tauri::{AppHandle, Emitter, State};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-
-// Windows-specific imports for hiding CMD windows
-#[cfg(windows)]
-use std::os::windows::process::CommandExt;
 
 // Windows constant for hiding console window
 #[cfg(windows)]
@@ -13,94 +19,210 @@ const CREATE_NO_WINDOW: u32 = 0x08000000;
 
 #[derive(Default)]
 pub struct AppState {
-    pub is_converting: Arc<Mutex<bool>>,
+    pub queue: Arc<ConversionQueue>,
+}
+
+#[tauri::command]
+pub async fn initialize_ffmpeg() -> Result<FfmpegInfo, String> {
+    ensure_ffmpeg().await
 }
 
+// Overrides the FFmpeg/ffprobe binaries from settings; `None` restores auto-detection
 #[tauri::command]
-pub async fn initialize_ffmpeg() -> Result<(), String> {
+pub async fn set_ffmpeg_paths(
+    ffmpeg_path: Option<String>,
+    ffprobe_path: Option<String>,
+) -> Result<FfmpegInfo, String> {
+    set_override(Binary::Ffmpeg, ffmpeg_path.filter(|p| !p.is_empty()).map(PathBuf::from));
+    set_override(Binary::Ffprobe, ffprobe_path.filter(|p| !p.is_empty()).map(PathBuf::from));
+    invalidate_capabilities();
     ensure_ffmpeg().await
 }
 
+#[tauri::command]
+pub async fn get_ffmpeg_capabilities() -> Result<Capabilities, String> {
+    capabilities::load().await.map(|capabilities| (*capabilities).clone())
+}
+
 #[tauri::command]
 pub async fn get_file_info(file_path: String) -> Result<MediaInfo, String> {
     get_media_info(&file_path).await
 }
 
+// Queues a conversion and waits until it has finished
 #[tauri::command]
 pub async fn start_conversion(
-    input_path: String,
-    output_path: String,
-    format: String,
-    quality: Option<String>,
-    width: Option<u32>,
-    height: Option<u32>,
-    duration: Option<f64>,
-    duration_mode: Option<String>, // New parameter
-    fps: Option<f64>,
-    disable_audio: Option<bool>, // Add disable audio parameter
+    request: ConversionRequest,
     app_handle: AppHandle,
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    let is_converting = state.is_converting.clone();
-    let mut converting = is_converting.lock().await;
-    
-    if *converting {
-        return Err("A conversion is already in progress".to_string());
-    }
-    
-    *converting = true;
-    drop(converting);
-
-    let quality_ref = quality.as_deref();
-    let duration_mode_ref = duration_mode.as_deref();
-    let result = convert_media(
-        &input_path, 
-        &output_path, 
-        &format, 
-        quality_ref, 
-        width, 
-        height, 
-        duration, 
-        duration_mode_ref, // Pass the new parameter
-        fps, 
-        disable_audio, // Pass the disable audio parameter
-        app_handle
-    ).await;
-    
-    let mut converting = is_converting.lock().await;
-    *converting = false;
-    
-    result
+) -> Result<(), ConversionError> {
+    let (_, done) = state.queue.enqueue(request, &app_handle)?;
+    done.await
+        .map_err(|_| ConversionError::from("Conversion job was dropped before finishing"))?
+}
+
+// Queues a conversion and returns its job ID right away
+#[tauri::command]
+pub async fn enqueue_conversion(
+    request: ConversionRequest,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<JobId, ConversionError> {
+    let (job_id, _) = state.queue.enqueue(request, &app_handle)?;
+    Ok(job_id)
+}
+
+// Queues joining several files into one and waits until it has finished
+#[tauri::command]
+pub async fn start_concat(
+    request: ConcatRequest,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), ConversionError> {
+    let (_, done) = state.queue.enqueue(request, &app_handle)?;
+    done.await
+        .map_err(|_| ConversionError::from("Concat job was dropped before finishing"))?
+}
+
+// Queues joining several files into one and returns its job ID right away
+#[tauri::command]
+pub async fn enqueue_concat(
+    request: ConcatRequest,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<JobId, ConversionError> {
+    let (job_id, _) = state.queue.enqueue(request, &app_handle)?;
+    Ok(job_id)
+}
+
+// Queues splitting a file into parts and waits until it has finished
+#[tauri::command]
+pub async fn start_split(
+    request: SplitRequest,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), ConversionError> {
+    let (_, done) = state.queue.enqueue(request, &app_handle)?;
+    done.await
+        .map_err(|_| ConversionError::from("Split job was dropped before finishing"))?
+}
+
+// Queues splitting a file into parts and returns its job ID right away
+#[tauri::command]
+pub async fn enqueue_split(
+    request: SplitRequest,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<JobId, ConversionError> {
+    let (job_id, _) = state.queue.enqueue(request, &app_handle)?;
+    Ok(job_id)
+}
+
+// Checks a request without queuing it, so the UI can flag invalid fields early
+#[tauri::command]
+pub fn validate_conversion(request: ConversionRequest) -> Vec<FieldError> {
+    request.validate().err().unwrap_or_default()
+}
+
+// Dry run: the FFmpeg command line a request would execute
+#[tauri::command]
+pub async fn preview_conversion_command(request: ConversionRequest) -> Result<String, ConversionError> {
+    preview_command(&request).await
+}
+
+// Black borders found in samples of the input, for previewing a crop
+#[tauri::command]
+pub async fn detect_crop(input_path: String) -> Result<CropDetection, ConversionError> {
+    detect_black_borders(&input_path).await
+}
+
+// Which streams a remux would copy and which it would re-encode
+#[tauri::command]
+pub async fn get_remux_plan(request: ConversionRequest) -> Result<Vec<StreamPlan>, ConversionError> {
+    remux_plan(&request).await
 }
 
 #[tauri::command]
 pub async fn get_conversion_status(state: State<'_, AppState>) -> Result<bool, ()> {
-    let is_converting = state.is_converting.lock().await;
-    Ok(*is_converting)
+    Ok(state.queue.is_busy())
 }
 
 #[tauri::command]
-pub fn get_supported_output_formats() -> Vec<&'static str> {
-    get_supported_formats()
+pub fn get_conversion_jobs(state: State<'_, AppState>) -> Vec<ConversionJob> {
+    state.queue.jobs()
 }
 
 #[tauri::command]
-pub async fn cancel_conversion(state: State<'_, AppState>) -> Result<(), String> {
-    let mut converting = state.is_converting.lock().await;
-    *converting = false;
-    
-    // Cancel the actual FFmpeg conversion
-    cancel_ffmpeg_conversion()?;
-    
-    Ok(())
+pub fn get_conversion_job(job_id: JobId, state: State<'_, AppState>) -> Result<ConversionJob, String> {
+    state.queue.job(job_id)
+        .ok_or_else(|| format!("Conversion job {} not found", job_id))
+}
+
+#[tauri::command]
+pub fn move_conversion_job(job_id: JobId, position: usize, state: State<'_, AppState>) -> Result<(), String> {
+    state.queue.move_job(job_id, position)
+}
+
+#[tauri::command]
+pub fn clear_finished_jobs(state: State<'_, AppState>) {
+    state.queue.clear_finished();
 }
 
+#[tauri::command]
+pub fn get_max_parallel_jobs(state: State<'_, AppState>) -> usize {
+    state.queue.max_parallel()
+}
+
+#[tauri::command]
+pub fn set_max_parallel_jobs(count: usize, app_handle: AppHandle, state: State<'_, AppState>) {
+    state.queue.set_max_parallel(count, &app_handle);
+}
+
+#[tauri::command]
+pub async fn get_supported_output_formats() -> Vec<&'static str> {
+    // Capability detection runs FFmpeg, keep it off the async workers
+    tauri::async_runtime::spawn_blocking(supported_formats)
+        .await
+        .unwrap_or_default()
+}
+
+// Cancels a single job, or every unfinished job when no ID is given
+#[tauri::command]
+pub async fn cancel_conversion(
+    job_id: Option<JobId>,
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    match job_id {
+        Some(id) => state.queue.cancel(id, &app_handle),
+        None => {
+            state.queue.cancel_all(&app_handle);
+            Ok(())
+        }
+    }
+}
+
+// Converts an image to WebP in the Downloads folder through the job queue
 #[tauri::command]
 pub async fn convert_image_to_webp(
     input_path: String,
     app_handle: AppHandle,
-) -> Result<String, String> {
-    crate::ffmpeg::convert_image_to_webp(&input_path, app_handle).await
+    state: State<'_, AppState>,
+) -> Result<String, ConversionError> {
+    let output_path = webp_output_path(&input_path)?;
+    let request = ConversionRequest {
+        input_path,
+        output_path: output_path.clone(),
+        format: "webp".to_string(),
+        options: ConversionOptions::default(),
+    };
+
+    let (_, done) = state.queue.enqueue(request, &app_handle)?;
+    done.await
+        .map_err(|_| ConversionError::from("Conversion job was dropped before finishing"))??;
+
+    let _ = app_handle.emit("image-conversion-complete", &output_path);
+    Ok(output_path)
 }
 
 #[tauri::command]
@@ -113,7 +235,7 @@ pub async fn get_file_stats(file_path: String) -> Result<serde_json::Value, Stri
     let file_size = metadata.len();
     
     // Try to get image dimensions using the existing get_media_info function
-    if let Ok(media_info) = crate::ffmpeg::get_media_info(&file_path).await {
+    if let Ok(media_info) = get_media_info(&file_path).await {
         Ok(serde_json::json!({
             "size": file_size,
             "width": media_info.width,
@@ -136,15 +258,10 @@ pub async fn get_image_dimensions(path: String) -> Result<(u32, u32), String> {
 
 async fn get_image_dimensions_ffprobe(path: &str) -> Result<(u32, u32), String> {
     // Get the path to ffprobe executable
-    let ffprobe_path = std::env::current_exe()
-        .map_err(|e| format!("Failed to get executable path: {}", e))?
-        .parent()
-        .ok_or("Failed to get parent directory")?
-        .join("bin")
-        .join("ffprobe-x86_64-pc-windows-msvc");
+    let ffprobe_path = ffprobe_path().await?;
 
     // Run ffprobe command to get video/image info as JSON
-    let mut cmd = std::process::Command::new(&ffprobe_path);
+    let mut cmd = tokio::process::Command::new(&ffprobe_path);
     cmd.args([
         "-v", "quiet",
         "-print_format", "json",
@@ -152,13 +269,14 @@ async fn get_image_dimensions_ffprobe(path: &str) -> Result<(u32, u32), String>
         path
     ])
     .stdout(std::process::Stdio::piped())
-    .stderr(std::process::Stdio::piped());
+    .stderr(std::process::Stdio::piped())
+    .kill_on_drop(true);
 
     // Hide CMD window on Windows
     #[cfg(windows)]
     cmd.creation_flags(CREATE_NO_WINDOW);
 
-    let output = cmd.output()
+    let output = cmd.output().await
         .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
 
     if !output.status.success() {
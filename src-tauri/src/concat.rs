@@ -0,0 +1,418 @@
+use crate::audio::{self, is_audio_format};
+use crate::command::FfmpegCommand;
+use crate::probe::{MediaInfo, StreamInfo, StreamKind};
+use crate::remux::audio_copyable;
+use crate::request::{is_video_format, ConversionOptions, ConversionRequest, FieldError, Validator};
+use crate::reverse::Playback;
+use crate::scale::FitMode;
+use crate::trim::seconds_arg;
+use crate::video::{container_accepts, default_audio_encoder, probed_codec_name, rate_control_args, tuning_args, video_codec};
+use serde::{Deserialize, Serialize};
+
+// Used when the inputs don't tell
+const DEFAULT_FPS: f64 = 30.0;
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// Files joined in order into one output. Of the options, quality, video and
+/// audio apply; trimming does not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcatRequest {
+    pub input_paths: Vec<String>,
+    pub output_path: String,
+    pub format: String,
+    #[serde(default)]
+    pub options: ConversionOptions,
+}
+
+impl ConcatRequest {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut v = Validator::default();
+
+        v.check(self.input_paths.len() >= 2, "input_paths", "at least two files are needed");
+        for (i, path) in self.input_paths.iter().enumerate() {
+            v.check(std::path::Path::new(path).is_file(), &format!("input_paths[{}]", i), "file does not exist");
+        }
+        v.check(
+            !self.input_paths.contains(&self.output_path),
+            "output_path",
+            "must differ from the input files",
+        );
+        v.check(
+            is_video_format(&self.format) || is_audio_format(&self.format),
+            "format",
+            "must be a video or audio format",
+        );
+        let trim = &self.options.trim;
+        v.check(
+            trim.ranges().is_empty() && trim.duration.is_none(),
+            "options.trim",
+            "isn't supported when joining files",
+        );
+        let video = &self.options.video;
+        v.check(
+            !video.transform.changes_picture(),
+            "options.video",
+            "cropping, rotating and flipping aren't supported when joining files",
+        );
+        // Every input is letterboxed to one size
+        v.check(
+            matches!(video.scale.fit, FitMode::Stretch | FitMode::Pad),
+            "options.video.fit",
+            "joined files are always padded to one size, only the pad fit applies",
+        );
+        v.check(
+            self.options.speed.factor.is_none(),
+            "options.speed.factor",
+            "changing the speed isn't supported when joining files",
+        );
+        v.check(
+            self.options.playback == Playback::Forward,
+            "options.playback",
+            "reversing isn't supported when joining files",
+        );
+
+        // The options are checked like those of a single conversion of the first input
+        let mut errors = v.into_errors();
+        if let Some(first) = self.input_paths.first() {
+            let single = ConversionRequest {
+                input_path: first.clone(),
+                output_path: self.output_path.clone(),
+                format: self.format.clone(),
+                options: self.options.clone(),
+            };
+            if let Err(single_errors) = single.validate() {
+                errors.extend(single_errors.into_iter().filter(|e| e.field != "input_path"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Checks the probed inputs before anything runs: video outputs need video in
+/// at least one input, and every duration has to be known for the progress and
+/// for filling missing streams.
+pub fn check_inputs(request: &ConcatRequest, inputs: &[MediaInfo]) -> Result<(), String> {
+    let format = request.format.to_lowercase();
+    if is_video_format(&format) && !inputs.iter().any(|info| first_stream(info, StreamKind::Video).is_some()) {
+        return Err(format!("None of the inputs has video to join into a .{} file", format));
+    }
+    for (path, info) in request.input_paths.iter().zip(inputs) {
+        if info.duration.is_none() {
+            return Err(format!("The duration of {} is unknown", path));
+        }
+    }
+    Ok(())
+}
+
+/// Contents of an ffconcat list joining `paths` in order.
+pub fn list_file(paths: &[String]) -> String {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for path in paths {
+        // Single quotes can't be escaped inside a quoted string, only between two
+        list.push_str(&format!("file '{}'\n", path.replace('\'', "'\\''")));
+    }
+    list
+}
+
+/// Joins the files of a list written by `list_file` without re-encoding.
+pub fn join_command(list_path: &str, output_path: &str, keep_audio: bool) -> FfmpegCommand {
+    let mut command = FfmpegCommand::new();
+    command.with_progress();
+    // Absolute paths in the list are "unsafe" to the demuxer
+    command.input(list_path).option(["-f", "concat", "-safe", "0"]);
+    let output = command.output(output_path);
+    output.option(["-map", "0", "-c", "copy"]);
+    if !keep_audio {
+        output.option(["-an"]);
+    }
+    command
+}
+
+/// Whether the concat demuxer can join the inputs as they are: every input has
+/// the same streams with the same codec parameters, the output container can
+/// hold them and no option asks for a change.
+pub fn can_copy(request: &ConcatRequest, inputs: &[MediaInfo]) -> bool {
+    let format = request.format.to_lowercase();
+    let options = &request.options;
+    let video = &options.video;
+    let changes = video.scale.resizes(video.width, video.height)
+        || video.fps.is_some()
+        || video.codec.is_some()
+        || video.rate_control.is_some()
+        || options.audio.requires_encoding();
+    if changes || is_audio_format(&format) && inputs.iter().any(|info| first_stream(info, StreamKind::Video).is_some()) {
+        return false;
+    }
+
+    let fits = |stream: &StreamInfo| {
+        let codec = stream.codec.as_deref().unwrap_or("");
+        match stream.kind {
+            StreamKind::Video => probed_codec_name(codec).is_some_and(|name| container_accepts(&format, name)),
+            StreamKind::Audio if is_audio_format(&format) => audio::can_copy_into(&format, codec),
+            StreamKind::Audio => audio_copyable(&format, codec),
+            _ => format == "mkv",
+        }
+    };
+    let Some((first, rest)) = inputs.split_first() else {
+        return false;
+    };
+    first.streams.iter().all(fits) && rest.iter().all(|info| signature(info) == signature(first))
+}
+
+// Codec parameters that have to match for the concat demuxer
+#[derive(PartialEq)]
+struct StreamSignature<'a> {
+    kind: StreamKind,
+    codec: Option<&'a str>,
+    size: Option<(u32, u32)>,
+    // The joined stream gets the rotation of the first input only
+    rotation: Option<i32>,
+    pixel_format: Option<&'a str>,
+    // Frame rate in hundredths, so 29.97 matches 30000/1001
+    fps: Option<i64>,
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
+}
+
+fn signature(info: &MediaInfo) -> Vec<StreamSignature<'_>> {
+    info.streams
+        .iter()
+        .map(|stream| StreamSignature {
+            kind: stream.kind,
+            codec: stream.codec.as_deref(),
+            size: stream.width.zip(stream.height),
+            rotation: stream.rotation,
+            pixel_format: stream.pixel_format.as_deref(),
+            fps: stream.fps.map(|fps| (fps * 100.0).round() as i64),
+            sample_rate: stream.sample_rate,
+            channels: stream.channels,
+        })
+        .collect()
+}
+
+fn first_stream(info: &MediaInfo, kind: StreamKind) -> Option<&StreamInfo> {
+    info.streams.iter().find(|stream| stream.kind == kind)
+}
+
+/// Joins inputs that differ through the concat filter. Each input is padded to
+/// the size and brought to the frame rate of the first video (or the requested
+/// size, at most `max_dimension`) and to the sample rate and channel layout of
+/// the first audio track; inputs missing a stream get black frames or silence.
+pub fn filter_command(request: &ConcatRequest, inputs: &[MediaInfo]) -> Result<FfmpegCommand, String> {
+    let format = request.format.to_lowercase();
+    let options = &request.options;
+    let quality = options.quality.as_deref();
+
+    let first_video = inputs.iter().find_map(|info| first_stream(info, StreamKind::Video));
+    let first_audio = inputs.iter().find_map(|info| first_stream(info, StreamKind::Audio));
+    let with_video = is_video_format(&format) && first_video.is_some();
+    let with_audio = !options.audio.disabled && first_audio.is_some();
+    if !with_video && !with_audio {
+        return Err("The inputs have no streams to join".to_string());
+    }
+
+    // Even sizes, most encoders need them for 4:2:0 video
+    let even = |value: u32| value.max(2) & !1;
+    // Inputs are turned upright before the filters, so their displayed size counts
+    let first_size = first_video.and_then(StreamInfo::display_size);
+    let mut width = options.video.width.or(first_size.map(|size| size.0)).unwrap_or(1280);
+    let mut height = options.video.height.or(first_size.map(|size| size.1)).unwrap_or(720);
+    if let Some(max) = options.video.scale.max_dimension {
+        let factor = (max as f64 / width.max(height) as f64).min(1.0);
+        width = (width as f64 * factor).round() as u32;
+        height = (height as f64 * factor).round() as u32;
+    }
+    let (width, height) = (even(width), even(height));
+    let pad_color = options.video.scale.pad_color.as_deref().unwrap_or("black");
+    let fps = options.video.fps.or(first_video.and_then(|s| s.fps)).unwrap_or(DEFAULT_FPS);
+    let sample_rate = options
+        .audio
+        .sample_rate
+        .or(first_audio.and_then(|s| s.sample_rate))
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
+    let layout = match options.audio.channels.or(first_audio.and_then(|s| s.channels)) {
+        Some(1) => "mono".to_string(),
+        Some(2) | None => "stereo".to_string(),
+        Some(channels) => first_audio
+            .and_then(|s| s.channel_layout.clone())
+            .filter(|_| options.audio.channels.is_none())
+            .unwrap_or_else(|| format!("{}c", channels)),
+    };
+    let fps = seconds_arg(fps);
+
+    let mut command = FfmpegCommand::new();
+    command.with_progress();
+    let mut chains = Vec::new();
+    let mut labels = String::new();
+    for (i, (path, info)) in request.input_paths.iter().zip(inputs).enumerate() {
+        command.input(path);
+        let duration = seconds_arg(info.duration.ok_or_else(|| format!("The duration of {} is unknown", path))?);
+        if with_video {
+            chains.push(match first_stream(info, StreamKind::Video) {
+                Some(_) => format!(
+                    "[{i}:v:0]scale={width}:{height}:force_original_aspect_ratio=decrease,\
+                     pad={width}:{height}:(ow-iw)/2:(oh-ih)/2:color={pad_color},setsar=1,fps={fps}[v{i}]"
+                ),
+                None => format!("color=c=black:s={width}x{height}:r={fps}:d={duration}[v{i}]"),
+            });
+            labels.push_str(&format!("[v{}]", i));
+        }
+        if with_audio {
+            chains.push(match first_stream(info, StreamKind::Audio) {
+                Some(_) => format!("[{i}:a:0]aresample={sample_rate},aformat=channel_layouts={layout}[a{i}]"),
+                None => format!("anullsrc=r={sample_rate}:cl={layout},atrim=duration={duration}[a{i}]"),
+            });
+            labels.push_str(&format!("[a{}]", i));
+        }
+    }
+    chains.push(format!(
+        "{}concat=n={}:v={}:a={}{}{}",
+        labels,
+        inputs.len(),
+        u8::from(with_video),
+        u8::from(with_audio),
+        if with_video { "[v]" } else { "" },
+        if with_audio { "[a]" } else { "" },
+    ));
+    command.global(["-filter_complex".to_string(), chains.join(";")]);
+
+    let output = command.output(&request.output_path);
+    if with_video {
+        let codec = video_codec(&format, &options.video)?;
+        output.map("[v]");
+        output.option(["-c:v", codec.encoder()]);
+        output.option(rate_control_args(codec, options.video.rate_control.as_ref(), quality));
+        output.option(tuning_args(codec, &format, &options.video, quality));
+        if with_audio {
+            output.map("[a]");
+            output.option(["-c:a", default_audio_encoder(&format, codec)]);
+            if let Some(kbps) = options.audio.bitrate {
+                output.option(["-b:a".to_string(), format!("{}k", kbps)]);
+            }
+        }
+    } else {
+        output.map("[a]");
+        output.option(audio::encoder_args(&format, quality, &options.audio)?);
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // 10 second clip, `width` x 720
+    fn clip(width: u32, with_audio: bool) -> MediaInfo {
+        let mut streams = vec![json!({
+            "index": 0, "kind": "video", "codec": "h264", "width": width, "height": 720,
+            "pixel_format": "yuv420p", "fps": 29.97, "is_default": true,
+        })];
+        if with_audio {
+            streams.push(json!({
+                "index": 1, "kind": "audio", "codec": "aac", "sample_rate": 48000, "channels": 2, "is_default": true,
+            }));
+        }
+        MediaInfo::for_test(Some(10.0), &streams)
+    }
+
+    fn request(format: &str, options: serde_json::Value) -> ConcatRequest {
+        serde_json::from_value(json!({
+            "input_paths": ["a.mp4", "b.mp4"],
+            "output_path": format!("out.{}", format),
+            "format": format,
+            "options": options,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn escapes_quotes_in_list_files() {
+        let paths = ["/tmp/a.mp4".to_string(), "/tmp/it's.mp4".to_string()];
+        assert_eq!(
+            list_file(&paths),
+            "ffconcat version 1.0\nfile '/tmp/a.mp4'\nfile '/tmp/it'\\''s.mp4'\n"
+        );
+    }
+
+    #[test]
+    fn copies_only_matching_inputs() {
+        let cases = [
+            ("same parameters", request("mp4", json!({})), [clip(1280, true), clip(1280, true)], true),
+            ("different sizes", request("mp4", json!({})), [clip(1280, true), clip(1920, true)], false),
+            ("missing audio", request("mp4", json!({})), [clip(1280, true), clip(1280, false)], false),
+            ("h264 in webm", request("webm", json!({})), [clip(1280, true), clip(1280, true)], false),
+            (
+                "resize requested",
+                request("mp4", json!({ "video": { "width": 640, "height": 360 } })),
+                [clip(1280, true), clip(1280, true)],
+                false,
+            ),
+        ];
+
+        for (name, request, inputs, expected) in cases {
+            assert_eq!(can_copy(&request, &inputs), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn normalizes_inputs_through_the_filter() {
+        let command = filter_command(&request("mp4", json!({})), &[clip(1280, true), clip(1920, false)]).unwrap();
+        assert_eq!(
+            command.to_args(),
+            [
+                "-y", "-hide_banner", "-progress", "pipe:1", "-nostats",
+                "-filter_complex",
+                "[0:v:0]scale=1280:720:force_original_aspect_ratio=decrease,pad=1280:720:(ow-iw)/2:(oh-ih)/2:color=black,setsar=1,fps=29.97[v0];\
+                 [0:a:0]aresample=48000,aformat=channel_layouts=stereo[a0];\
+                 [1:v:0]scale=1280:720:force_original_aspect_ratio=decrease,pad=1280:720:(ow-iw)/2:(oh-ih)/2:color=black,setsar=1,fps=29.97[v1];\
+                 anullsrc=r=48000:cl=stereo,atrim=duration=10[a1];\
+                 [v0][a0][v1][a1]concat=n=2:v=1:a=1[v][a]",
+                "-i", "a.mp4", "-i", "b.mp4",
+                "-map", "[v]", "-map", "[a]",
+                "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac",
+                "out.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn honours_the_pad_color_and_max_dimension() {
+        let request = request("mp4", json!({ "video": { "fit": "pad", "pad_color": "white", "max_dimension": 640 } }));
+        let args = filter_command(&request, &[clip(1280, false), clip(1920, false)]).unwrap().to_args();
+        assert!(args[6].starts_with(
+            "[0:v:0]scale=640:360:force_original_aspect_ratio=decrease,pad=640:360:(ow-iw)/2:(oh-ih)/2:color=white,"
+        ));
+    }
+
+    #[test]
+    fn checks_inputs_before_joining() {
+        let audio_only = MediaInfo::for_test(Some(10.0), &[json!({ "index": 0, "kind": "audio", "codec": "aac" })]);
+        let mut unknown_length = clip(1280, true);
+        unknown_length.duration = None;
+
+        assert!(check_inputs(&request("mp4", json!({})), &[clip(1280, true), clip(1280, false)]).is_ok());
+        assert!(check_inputs(&request("m4a", json!({})), &[audio_only.clone(), audio_only.clone()]).is_ok());
+        assert!(check_inputs(&request("mp4", json!({})), &[audio_only.clone(), audio_only]).is_err());
+        assert!(check_inputs(&request("mp4", json!({})), &[clip(1280, true), unknown_length]).is_err());
+    }
+
+    #[test]
+    fn validates_with_field_paths() {
+        let fields = |options: serde_json::Value| {
+            let request = request("mp4", options);
+            request.validate().unwrap_err().into_iter().map(|e| e.field).collect::<Vec<_>>()
+        };
+        // The inputs don't exist in tests, so their errors come first
+        let inputs = ["input_paths[0]", "input_paths[1]"];
+
+        assert_eq!(fields(json!({})), inputs);
+        assert_eq!(fields(json!({ "video": { "width": 640, "height": 360, "fit": "cover" } }))[2..], ["options.video.fit"]);
+        assert_eq!(fields(json!({ "trim": { "start": 5 } }))[2..], ["options.trim"]);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::command::FfmpegCommand;
+use crate::transform::Crop;
+use crate::trim::{seconds_arg, Segment};
+use serde::{Deserialize, Serialize};
+
+// Samples spread over the input, each analysed for a couple of seconds
+const SAMPLE_COUNT: usize = 8;
+const SAMPLE_SECONDS: f64 = 2.0;
+// Luma up to this (of 255) counts as black
+const BLACK_LIMIT: u32 = 24;
+// Borders thinner than this on every side aren't worth a crop
+const MIN_BORDER: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<CropRect> for Crop {
+    fn from(rect: CropRect) -> Self {
+        Crop::Rect {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CropDetection {
+    // None when there are no borders worth removing
+    pub crop: Option<CropRect>,
+    // Upright size of the input, which the rectangle is relative to
+    pub width: u32,
+    pub height: u32,
+    // Samples that showed any picture, all-black ones don't count
+    pub samples: usize,
+}
+
+/// Parts of the input to analyse: evenly spread samples, or all of it when
+/// it is too short to sample.
+pub fn sample_ranges(duration: Option<f64>) -> Vec<Segment> {
+    match duration {
+        Some(duration) if duration > SAMPLE_SECONDS * SAMPLE_COUNT as f64 => (0..SAMPLE_COUNT)
+            .map(|i| {
+                let start = duration * (i as f64 + 0.5) / SAMPLE_COUNT as f64 - SAMPLE_SECONDS / 2.0;
+                Segment { start, end: Some(start + SAMPLE_SECONDS) }
+            })
+            .collect(),
+        _ => vec![Segment { start: 0.0, end: None }],
+    }
+}
+
+/// Runs `cropdetect` over one sample without writing anything. With `reset=0`
+/// the last reported rectangle covers every frame of the sample.
+pub fn sample_command(input_path: &str, range: &Segment) -> FfmpegCommand {
+    let mut command = FfmpegCommand::new();
+    command.global(["-nostats"]);
+    let input = command.input(input_path);
+    if range.start > 0.0 {
+        input.option(["-ss".to_string(), seconds_arg(range.start)]);
+    }
+
+    let output = command.output("-");
+    output.map("0:v:0");
+    if let Some(end) = range.end {
+        output.option(["-t".to_string(), seconds_arg(end - range.start)]);
+    }
+    output.video_filter(format!("cropdetect=limit={}:round=2:reset=0", BLACK_LIMIT));
+    output.option(["-an", "-sn", "-f", "null"]);
+    command
+}
+
+/// The last rectangle `cropdetect` logged. Black frames give empty or
+/// negative sizes and are skipped.
+pub fn last_crop(stderr: &str) -> Option<CropRect> {
+    stderr
+        .lines()
+        .rev()
+        .filter(|line| line.contains("cropdetect"))
+        .find_map(|line| {
+            let (_, crop) = line.rsplit_once("crop=")?;
+            let values: Vec<i64> = crop.trim().split(':').map(|value| value.parse().ok()).collect::<Option<_>>()?;
+            let [width, height, x, y] = values[..] else {
+                return None;
+            };
+            (width > 0 && height > 0 && x >= 0 && y >= 0).then_some(CropRect {
+                x: x as u32,
+                y: y as u32,
+                width: width as u32,
+                height: height as u32,
+            })
+        })
+}
+
+/// Smallest rectangle holding the picture of every sample, so a dark scene
+/// can't cut into a brighter one.
+pub fn aggregate(rects: &[CropRect], width: u32, height: u32) -> CropDetection {
+    let left = rects.iter().map(|r| r.x).min();
+    let top = rects.iter().map(|r| r.y).min();
+    let right = rects.iter().map(|r| r.x + r.width).max();
+    let bottom = rects.iter().map(|r| r.y + r.height).max();
+
+    let crop = match (left, top, right, bottom) {
+        (Some(left), Some(top), Some(right), Some(bottom)) => {
+            let (right, bottom) = (right.min(width), bottom.min(height));
+            let borders = [left, top, width - right, height - bottom];
+            borders.iter().any(|border| *border >= MIN_BORDER).then_some(CropRect {
+                x: left,
+                y: top,
+                // Even sizes for 4:2:0 video
+                width: (right - left) & !1,
+                height: (bottom - top) & !1,
+            })
+        }
+        _ => None,
+    };
+    CropDetection {
+        crop,
+        width,
+        height,
+        samples: rects.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cropdetect_output() {
+        let stderr = "\
+[Parsed_cropdetect_0 @ 0x600] x1:0 x2:1919 y1:140 y2:939 w:1920 h:800 x:0 y:140 pts:1 t:0.04 limit:0.094118 crop=1920:800:0:140
+[Parsed_cropdetect_0 @ 0x600] x1:0 x2:1919 y1:138 y2:941 w:1920 h:804 x:0 y:138 pts:2 t:0.08 limit:0.094118 crop=1920:804:0:138
+[out#0/null @ 0x700] video:1kB audio:0kB";
+        assert_eq!(last_crop(stderr), Some(CropRect { x: 0, y: 138, width: 1920, height: 804 }));
+
+        let black = "[Parsed_cropdetect_0 @ 0x600] x1:1919 x2:0 y1:1079 y2:0 w:-1904 h:-1064 x:1912 y:1072 crop=-1904:-1064:1912:1072";
+        assert_eq!(last_crop(black), None);
+    }
+
+    #[test]
+    fn combines_samples_into_one_rectangle() {
+        let rect = |x, y, width, height| CropRect { x, y, width, height };
+
+        // A dark scene detects a smaller picture, the union keeps the bright one
+        let detection = aggregate(&[rect(0, 140, 1920, 800), rect(240, 300, 1440, 480)], 1920, 1080);
+        assert_eq!(detection.crop, Some(rect(0, 140, 1920, 800)));
+        assert_eq!(detection.samples, 2);
+
+        // Pillarboxed 4:3 with an odd width
+        let detection = aggregate(&[rect(239, 0, 1441, 1080)], 1920, 1080);
+        assert_eq!(detection.crop, Some(rect(239, 0, 1440, 1080)));
+
+        assert_eq!(aggregate(&[rect(2, 0, 1916, 1080)], 1920, 1080).crop, None);
+        assert_eq!(aggregate(&[], 1920, 1080).crop, None);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::request::{format_field_errors, FieldError};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// How many trailing FFmpeg stderr lines are kept for error reports
+pub const STDERR_TAIL_LINES: usize = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // FFmpeg or ffprobe could not be found or started
+    BinaryMissing,
+    // The input doesn't exist, is truncated or isn't media FFmpeg understands
+    InputUnreadable,
+    // A stream can't be decoded, or can't be stored in the output container
+    UnsupportedCodec,
+    // The FFmpeg build lacks the encoder the conversion needs
+    EncoderMissing,
+    DiskFull,
+    PermissionDenied,
+    // The request, or an option FFmpeg rejected
+    InvalidOptions,
+    Cancelled,
+    // FFmpeg exited successfully but left no usable output
+    OutputMissing,
+    // A size-targeted encode stayed above the target after all retries
+    SizeTargetMissed,
+    Unknown,
+}
+
+/// Error returned by conversions. `ffmpeg_log` holds the last lines FFmpeg
+/// wrote to stderr so the UI can show what actually went wrong.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversionError {
+    pub kind: ErrorKind,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    #[serde(default)]
+    pub ffmpeg_log: Vec<String>,
+    pub exit_code: Option<i32>,
+}
+
+impl ConversionError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            fields: Vec::new(),
+            ffmpeg_log: Vec::new(),
+            exit_code: None,
+        }
+    }
+
+    pub fn cancelled() -> Self {
+        Self::new(ErrorKind::Cancelled, "Conversion cancelled by user")
+    }
+
+    pub fn invalid_request(fields: Vec<FieldError>) -> Self {
+        Self {
+            message: format_field_errors(&fields),
+            fields,
+            ..Self::new(ErrorKind::InvalidOptions, "")
+        }
+    }
+
+    /// Classifies a failed FFmpeg run from the tail of its stderr.
+    pub fn from_ffmpeg(exit_code: Option<i32>, stderr_tail: Vec<String>) -> Self {
+        let (kind, message) = classify_stderr(&stderr_tail).unwrap_or_else(|| {
+            (
+                ErrorKind::Unknown,
+                format!("FFmpeg conversion failed with exit code: {:?}", exit_code),
+            )
+        });
+        Self {
+            ffmpeg_log: stderr_tail,
+            exit_code,
+            ..Self::new(kind, message)
+        }
+    }
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// Most helpers still report plain strings
+impl From<String> for ConversionError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorKind::Unknown, message)
+    }
+}
+
+impl From<&str> for ConversionError {
+    fn from(message: &str) -> Self {
+        Self::new(ErrorKind::Unknown, message)
+    }
+}
+
+/// Ring buffer of the most recent stderr lines.
+#[derive(Debug, Default)]
+pub struct StderrTail {
+    lines: VecDeque<String>,
+}
+
+impl StderrTail {
+    pub fn push(&mut self, line: String) {
+        if self.lines.len() == STDERR_TAIL_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn into_lines(self) -> Vec<String> {
+        self.lines.into()
+    }
+}
+
+// Checked in order, the first match wins. Resource problems come first since
+// FFmpeg usually follows them with generic "Invalid argument" lines.
+const STDERR_PATTERNS: &[(&str, ErrorKind, &str)] = &[
+    ("No space left on device", ErrorKind::DiskFull, "The disk is full"),
+    ("Permission denied", ErrorKind::PermissionDenied, "Permission denied while reading the input or writing the output"),
+    ("Read-only file system", ErrorKind::PermissionDenied, "The output location is read-only"),
+    ("Unknown encoder", ErrorKind::EncoderMissing, "The installed FFmpeg lacks a required encoder"),
+    ("Encoder not found", ErrorKind::EncoderMissing, "The installed FFmpeg lacks a required encoder"),
+    ("not currently supported in container", ErrorKind::UnsupportedCodec, "The output container can't hold this codec"),
+    ("Could not find tag for codec", ErrorKind::UnsupportedCodec, "The output container can't hold this codec"),
+    ("Decoder (codec", ErrorKind::UnsupportedCodec, "The input uses a codec FFmpeg can't decode"),
+    ("No such file or directory", ErrorKind::InputUnreadable, "A file or folder doesn't exist"),
+    ("Invalid data found when processing input", ErrorKind::InputUnreadable, "The input is damaged or not a media file"),
+    ("moov atom not found", ErrorKind::InputUnreadable, "The input is incomplete (missing moov atom)"),
+    ("does not contain any stream", ErrorKind::InputUnreadable, "The input has no usable streams"),
+    ("Unrecognized option", ErrorKind::InvalidOptions, "FFmpeg rejected an option"),
+    ("Option not found", ErrorKind::InvalidOptions, "FFmpeg rejected an option"),
+    ("Error parsing", ErrorKind::InvalidOptions, "FFmpeg rejected an option"),
+    ("Error initializing filter", ErrorKind::InvalidOptions, "A filter could not be set up"),
+    ("Error reinitializing filters", ErrorKind::InvalidOptions, "A filter could not be set up"),
+];
+
+fn classify_stderr(lines: &[String]) -> Option<(ErrorKind, String)> {
+    STDERR_PATTERNS.iter().find_map(|(pattern, kind, summary)| {
+        lines
+            .iter()
+            .find(|line| line.contains(pattern))
+            .map(|line| (*kind, format!("{}: {}", summary, line.trim())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn classifies_ffmpeg_stderr() {
+        let cases = [
+            ("av_interleaved_write_frame(): No space left on device\nError writing trailer: Invalid argument", ErrorKind::DiskFull),
+            ("out.mp4: Permission denied", ErrorKind::PermissionDenied),
+            ("Unknown encoder 'libx265'", ErrorKind::EncoderMissing),
+            ("[mp4 @ 0x1] Could not find tag for codec pcm_s16le in stream #1, codec not currently supported in container", ErrorKind::UnsupportedCodec),
+            ("in.mp4: Invalid data found when processing input", ErrorKind::InputUnreadable),
+            ("[mov,mp4,m4a @ 0x1] moov atom not found", ErrorKind::InputUnreadable),
+            ("Unrecognized option 'foo'.\nError splitting the argument list: Option not found", ErrorKind::InvalidOptions),
+            ("Conversion failed!", ErrorKind::Unknown),
+        ];
+
+        for (stderr, kind) in cases {
+            let error = ConversionError::from_ffmpeg(Some(1), lines(stderr));
+            assert_eq!(error.kind, kind, "{}", stderr);
+            assert_eq!(error.ffmpeg_log, lines(stderr));
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_tail() {
+        let mut tail = StderrTail::default();
+        for i in 0..STDERR_TAIL_LINES + 5 {
+            tail.push(i.to_string());
+        }
+        let lines = tail.into_lines();
+        assert_eq!(lines.len(), STDERR_TAIL_LINES);
+        assert_eq!(lines[0], "5");
+    }
+}
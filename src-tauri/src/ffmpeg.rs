@@ -1,651 +1,1274 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::Mutex;
+use crate::binaries::{resolve_async, Binary, FfmpegInfo};
+use crate::audio::{apply_audio_output, is_audio_format, select_audio_stream};
+use crate::capabilities::{self, require_encoder};
+use crate::command::FfmpegCommand;
+use crate::concat::{self, ConcatRequest};
+use crate::crop_detect::{self, CropDetection};
+use crate::error::{ConversionError, ErrorKind, StderrTail};
+use crate::probe::{get_keyframes, get_media_info, MediaInfo, StreamInfo, StreamKind};
+use crate::progress::ProgressTracker;
+use crate::queue::JobHandle;
+use crate::remux::{self, RemuxReport, StreamPlan};
+use crate::request::{is_video_format, ConversionOptions, ConversionRequest};
+use crate::reverse::{self, Playback};
+use crate::scale::scale_filters;
+use crate::transform::{self, transform_filters, Crop};
+use crate::smart_cut;
+use crate::speed;
+use crate::split::{self, SplitBy, SplitManifest, SplitPart, SplitRequest};
+use crate::target_size::{self, SizeTarget, MAX_SIZE_RETRIES};
+use crate::trim::{select_expression, seconds_arg, CutMode, Segment, TrimOptions};
+use crate::video::{default_audio_encoder, rate_control_args, tuning_args, video_codec};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
 use tauri::{AppHandle, Emitter};
-use std::io::{BufRead, BufReader};
-use std::thread;
-
-// Windows-specific imports for hiding CMD windows
-#[cfg(windows)]
-use std::os::windows::process::CommandExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tauri::async_runtime::JoinHandle;
+use tokio::process::{Child, Command};
 
 // Windows constant for hiding console window
 #[cfg(windows)]
 const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ConversionProgress {
-    pub frame: Option<u64>,
-    pub fps: Option<f64>,
-    pub bitrate: Option<String>,
-    pub total_size: Option<u64>,
-    pub out_time_us: Option<u64>,
-    pub speed: Option<f64>,
-    pub progress: Option<String>,
-    pub percentage: Option<f64>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MediaInfo {
-    pub duration: Option<f64>,
-    pub width: Option<u32>,
-    pub height: Option<u32>,
-    pub video_codec: Option<String>,
-    pub audio_codec: Option<String>,
-    pub bitrate: Option<String>,
-    pub fps: Option<f64>,
-    pub file_size: Option<u64>,
-}
-
-// Global cancellation flag
-pub static CONVERSION_CANCELLED: Mutex<bool> = Mutex::new(false);
-
-pub fn get_ffmpeg_path() -> Result<PathBuf, String> {
-    // Get the path to the bundled FFmpeg executable
-    let exe_dir = std::env::current_exe()
-        .map_err(|e| format!("Failed to get current exe path: {}", e))?
-        .parent()
-        .ok_or("Failed to get exe parent directory")?
-        .to_path_buf();
-
-    let ffmpeg_path = exe_dir.join("ffmpeg-x86_64-pc-windows-msvc");
-    
-    if ffmpeg_path.exists() {
-        Ok(ffmpeg_path)
-    } else {
-        // Fallback to system FFmpeg
-        Ok(PathBuf::from("ffmpeg"))
-    }
+// How long FFmpeg gets to finish after being asked to quit before it is killed
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// `-fs` stops writing once the limit is passed, so size split parts aim a little lower
+const SPLIT_SIZE_MARGIN: f64 = 0.02;
+// Less than this left of the input isn't worth another part
+const MIN_SPLIT_PART: f64 = 0.05;
+// Assumed for speed changes when the input doesn't tell
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+pub async fn ensure_ffmpeg() -> Result<FfmpegInfo, String> {
+    // Resolve both binaries up front so a missing one is reported at startup
+    let ffmpeg = resolve_async(Binary::Ffmpeg).await?;
+    let ffprobe = resolve_async(Binary::Ffprobe).await?;
+    log::info!("FFmpeg found and working: {}", ffmpeg.version);
+    Ok(FfmpegInfo { ffmpeg, ffprobe })
 }
 
-pub async fn ensure_ffmpeg() -> Result<(), String> {
-    let ffmpeg_path = get_ffmpeg_path()?;
-    
-    // Test if FFmpeg is working
-    let mut cmd = Command::new(&ffmpeg_path);
-    cmd.arg("-version").stdout(Stdio::piped()).stderr(Stdio::piped());
-    
-    // Hide CMD window on Windows
-    #[cfg(windows)]
-    cmd.creation_flags(CREATE_NO_WINDOW);
-    
-    let output = cmd.output()
-        .map_err(|e| format!("Failed to execute FFmpeg: {}. Make sure FFmpeg is installed or bundled.", e))?;
+fn is_image_format(fmt: &str) -> bool {
+    matches!(fmt.to_lowercase().as_str(), "jpg" | "jpeg" | "png" | "webp" | "bmp" | "gif" | "tiff" | "ico")
+}
 
-    if output.status.success() {
-        let version_info = String::from_utf8_lossy(&output.stdout);
-        log::info!("FFmpeg found and working: {}", version_info.lines().next().unwrap_or("Unknown version"));
-        Ok(())
-    } else {
-        Err("FFmpeg executable found but not working properly".to_string())
-    }
+fn extension(path: &str) -> String {
+    std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase()
 }
 
-pub async fn get_media_info(input_path: &str) -> Result<MediaInfo, String> {
-    let ffmpeg_path = get_ffmpeg_path()?;
-    
-    // Use FFmpeg to get media information
-    let mut cmd = Command::new(&ffmpeg_path);
-    cmd.args([
-        "-i", input_path,
-        "-f", "null", 
-        "-"
-    ])
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped());
-    
-    // Hide CMD window on Windows
-    #[cfg(windows)]
-    cmd.creation_flags(CREATE_NO_WINDOW);
-    
-    let output = cmd.output()
-        .map_err(|e| format!("Failed to execute FFmpeg: {}", e))?;
+// Image to image conversions skip progress reporting and the video encoders
+fn is_image_conversion(request: &ConversionRequest) -> bool {
+    is_image_format(&extension(&request.input_path)) && is_image_format(&extension(&request.output_path))
+}
 
-    // FFmpeg outputs media info to stderr
-    let error_output = String::from_utf8_lossy(&output.stderr);
-    
-    let mut media_info = MediaInfo {
-        duration: None,
-        width: None,
-        height: None,
-        video_codec: None,
-        audio_codec: None,
-        bitrate: None,
-        fps: None,
-        file_size: None,
+// Where the first pass of a two-pass encode writes its (discarded) output
+const NULL_OUTPUT: &str = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+/// One run of a two-pass encode aiming at a file size.
+pub struct Pass<'a> {
+    pub number: u32,
+    pub target: &'a SizeTarget,
+    // Prefix of the statistics files shared by both passes
+    pub log_prefix: &'a str,
+}
+
+/// Builds the FFmpeg invocation for a request. `media_info` is the probe of the
+/// input; audio outputs and duration compression can't be built without it.
+pub fn build_command(request: &ConversionRequest, media_info: Option<&MediaInfo>) -> Result<FfmpegCommand, String> {
+    build(request, media_info, None)
+}
+
+/// Builds one pass of a size-targeted encode.
+pub fn build_pass_command(
+    request: &ConversionRequest,
+    media_info: Option<&MediaInfo>,
+    pass: &Pass,
+) -> Result<FfmpegCommand, String> {
+    build(request, media_info, Some(pass))
+}
+
+fn build(request: &ConversionRequest, media_info: Option<&MediaInfo>, pass: Option<&Pass>) -> Result<FfmpegCommand, String> {
+    let auto_crop = Some(Crop::Auto);
+    if request.options.video.transform.crop == auto_crop || request.options.image.transform.crop == auto_crop {
+        return Err("Automatic crops have to be detected before the command is built".to_string());
+    }
+    if request.options.playback == Playback::Boomerang {
+        return Err("Boomerangs are written in pieces, they can't be built as a single command".to_string());
+    }
+    if is_image_conversion(request) {
+        return Ok(build_image_command(request));
+    }
+
+    let format = request.format.to_lowercase();
+    let options = &request.options;
+    let quality = options.quality.as_deref();
+    let is_audio = is_audio_format(&format);
+
+    let output_path = match pass {
+        Some(pass) if pass.number == 1 => NULL_OUTPUT,
+        _ => request.output_path.as_str(),
     };
 
-    // Parse the FFmpeg output
-    for line in error_output.lines() {
-        // Parse duration
-        if line.contains("Duration:") {
-            if let Some(duration_str) = extract_duration_from_line(line) {
-                media_info.duration = parse_duration(&duration_str);
+    let ranges = options.trim.ranges();
+    let fast_cut = options.trim.cut == CutMode::Fast && !ranges.is_empty();
+    if fast_cut && ranges.len() > 1 {
+        return Err("Several segments can't be cut without re-encoding in a single command".to_string());
+    }
+
+    // `None` when the input wasn't probed, so its rotation is unknown
+    let source_rotation = media_info.map(|info| {
+        info.streams
+            .iter()
+            .find(|stream| stream.kind == StreamKind::Video)
+            .and_then(|stream| stream.rotation)
+            .unwrap_or(0)
+    });
+
+    let mut command = FfmpegCommand::new();
+    command.with_progress();
+    let input = command.input(&request.input_path);
+    if !is_audio {
+        input.option(transform::input_args(&options.video.transform, source_rotation));
+    }
+    // Seeking the input skips decoding everything before the first kept frame
+    let offset = ranges.first().map_or(0.0, |range| range.start);
+    if offset > 0.0 {
+        input.option(["-ss".to_string(), seconds_arg(offset)]);
+    }
+    let last_end = ranges.last().and_then(|range| range.end);
+    if let (Some(end), true) = (last_end, ranges.len() > 1) {
+        // Stops reading after the last segment, the output is shorter than this
+        input.option(["-t".to_string(), seconds_arg(end - offset)]);
+    }
+
+    let output = command.output(output_path);
+    match ranges.as_slice() {
+        [] => {}
+        [_] => {
+            if let Some(end) = last_end {
+                output.option(["-t".to_string(), seconds_arg(end - offset)]);
             }
         }
-        
-        // Parse video stream info
-        if line.contains("Stream") && line.contains("Video:") {
-            if let Some((codec, width, height, fps)) = extract_video_info_from_line(line) {
-                media_info.video_codec = Some(codec);
-                media_info.width = width;
-                media_info.height = height;
-                media_info.fps = fps;
+        // Keep the segments and close the gaps between them
+        _ => {
+            let expression = select_expression(&ranges, offset);
+            if !is_audio {
+                output.video_filter(format!("select='{}',setpts=N/FRAME_RATE/TB", expression));
+            }
+            if !options.audio.disabled {
+                output.audio_filter(format!("aselect='{}',asetpts=N/SR/TB", expression));
             }
         }
-        
-        // Parse audio stream info
-        if line.contains("Stream") && line.contains("Audio:") {
-            if let Some(codec) = extract_audio_codec_from_line(line) {
-                media_info.audio_codec = Some(codec);
+    }
+
+    if !is_audio {
+        let video = &options.video;
+        let filters = transform::source_rotation_filters(&video.transform, source_rotation.unwrap_or(0))
+            .into_iter()
+            .chain(transform_filters(&video.transform, true))
+            .chain(scale_filters(video.width, video.height, &video.scale, true));
+        for filter in filters {
+            output.video_filter(filter);
+        }
+        output.option(transform::output_args(&video.transform, source_rotation));
+    }
+
+    // Speed changes retime the video and stretch the audio to match, chaining
+    // atempo so any factor keeps the sound
+    if let Some(factor) = speed::speed_factor(options, media_info.and_then(|info| info.duration))? {
+        if !is_audio {
+            let fps = options.video.fps.or(media_info.and_then(|info| info.fps));
+            for filter in speed::video_filters(factor, options.speed.frames, fps) {
+                output.video_filter(filter);
             }
         }
-        
-        // Parse bitrate
-        if line.contains("bitrate:") {
-            if let Some(bitrate) = extract_bitrate_from_line(line) {
-                media_info.bitrate = Some(bitrate);
+        if !options.audio.disabled {
+            let sample_rate = media_info
+                .and_then(|info| select_audio_stream(info, options.audio.stream_index).ok())
+                .and_then(|stream| stream.sample_rate)
+                .unwrap_or(DEFAULT_SAMPLE_RATE);
+            for filter in speed::audio_filters(factor, options.speed.preserve_pitch, sample_rate) {
+                output.audio_filter(filter);
             }
         }
     }
 
-    Ok(media_info)
-}
-
-fn extract_duration_from_line(line: &str) -> Option<String> {
-    if let Some(start) = line.find("Duration: ") {
-        let start = start + 10; // "Duration: ".len()
-        if let Some(end) = line[start..].find(',') {
-            return Some(line[start..start + end].to_string());
+    // Last, so `reverse` buffers the frames at their output size
+    if options.playback == Playback::Reverse {
+        if !is_audio {
+            output.video_filter("reverse");
+        }
+        if !options.audio.disabled {
+            output.audio_filter("areverse");
         }
     }
-    None
-}
 
-fn parse_duration(duration_str: &str) -> Option<f64> {
-    let parts: Vec<&str> = duration_str.split(':').collect();
-    if parts.len() == 3 {
-        if let (Ok(hours), Ok(minutes), Ok(seconds)) = (
-            parts[0].parse::<f64>(),
-            parts[1].parse::<f64>(),
-            parts[2].parse::<f64>(),
-        ) {
-            return Some(hours * 3600.0 + minutes * 60.0 + seconds);
-        }
+    if is_audio {
+        let media_info = media_info.ok_or("Failed to read input audio streams")?;
+        let source = select_audio_stream(media_info, options.audio.stream_index)?;
+        apply_audio_output(output, &format, quality, &options.audio, source)?;
+        return Ok(command);
     }
-    None
-}
 
-fn extract_video_info_from_line(line: &str) -> Option<(String, Option<u32>, Option<u32>, Option<f64>)> {
-    let mut codec = None;
-    let mut width = None;
-    let mut height = None;
-    let mut fps = None;
-    
-    // Extract codec
-    if let Some(video_pos) = line.find("Video: ") {
-        let after_video = &line[video_pos + 7..];
-        if let Some(codec_end) = after_video.find(' ') {
-            codec = Some(after_video[..codec_end].to_string());
+    if let Some(target_fps) = options.video.fps {
+        output.option(["-r".to_string(), target_fps.to_string()]);
+    }
+
+    // The first pass only analyses the video
+    let drop_audio = options.audio.disabled || pass.is_some_and(|pass| pass.number == 1);
+    if drop_audio && !output.has_flag("-an") {
+        output.option(["-an"]);
+    }
+
+    if fast_cut {
+        // Copied streams start at the keyframe before the cut, shift them back to zero
+        output.option(["-avoid_negative_ts", "make_zero"]);
+    }
+
+    let codec = video_codec(&format, &options.video)?;
+
+    if options.remux {
+        let media_info = media_info.ok_or("The input streams are unknown, so they can't be copied")?;
+        let streams = remux::plan(request, media_info)?;
+        output.option(remux::stream_args(&streams, &format));
+        // The main video comes first, attached pictures after it are copied and
+        // FFmpeg can't filter a copied stream
+        output.video_filter_stream("v:0");
+        // Encoder settings only for the streams that are actually encoded
+        if remux::transcodes(&streams, StreamKind::Video) {
+            output.option(rate_control_args(codec, options.video.rate_control.as_ref(), quality));
+            output.option(tuning_args(codec, &format, &options.video, quality));
+        }
+        if let (true, Some(kbps)) = (remux::transcodes(&streams, StreamKind::Audio), options.audio.bitrate) {
+            output.option(["-b:a".to_string(), format!("{}k", kbps)]);
         }
+        return Ok(command);
     }
-    
-    // Extract resolution
-    let parts: Vec<&str> = line.split(&[',', ' ']).collect();
-    for part in parts {
-        if part.contains('x') && !part.contains('@') && !part.contains('.') {
-            if let Some(x_pos) = part.find('x') {
-                let width_str = &part[..x_pos];
-                let height_str = &part[x_pos + 1..];
-                
-                if width_str.chars().all(|c| c.is_ascii_digit()) && 
-                   height_str.chars().all(|c| c.is_ascii_digit()) {
-                    width = width_str.parse().ok();
-                    height = height_str.parse().ok();
-                    break;
-                }
-            }
+
+    if fast_cut {
+        output.option(["-c:v", "copy"]);
+        if !output.has_flag("-an") {
+            output.option(["-c:a", "copy"]);
         }
+        return Ok(command);
     }
-    
-    // Extract FPS
-    if let Some(fps_pos) = line.find(" fps") {
-        let before_fps = &line[..fps_pos];
-        if let Some(fps_start) = before_fps.rfind(' ') {
-            if let Ok(fps_val) = before_fps[fps_start + 1..].parse::<f64>() {
-                fps = Some(fps_val);
-            }
+
+    output.option(["-c:v", codec.encoder()]);
+    match pass {
+        Some(pass) => {
+            output.option([
+                "-b:v".to_string(),
+                format!("{}k", pass.target.video_kbps),
+                "-pass".to_string(),
+                pass.number.to_string(),
+                "-passlogfile".to_string(),
+                pass.log_prefix.to_string(),
+            ]);
+        }
+        None => {
+            output.option(rate_control_args(codec, options.video.rate_control.as_ref(), quality));
         }
     }
-    
-    if let Some(codec) = codec {
-        Some((codec, width, height, fps))
-    } else {
-        None
+    output.option(tuning_args(codec, &format, &options.video, quality));
+
+    if pass.is_some_and(|pass| pass.number == 1) {
+        output.option(["-f", "null"]);
+    } else if !output.has_flag("-an") {
+        output.option(["-c:a", default_audio_encoder(&format, codec)]);
+        // Size targets pin the audio bitrate their budget was computed with
+        let audio_kbps = pass.map(|pass| pass.target.audio_kbps).or(options.audio.bitrate);
+        if let Some(kbps) = audio_kbps.filter(|kbps| *kbps > 0) {
+            output.option(["-b:a".to_string(), format!("{}k", kbps)]);
+        }
     }
+
+    Ok(command)
 }
 
-fn extract_audio_codec_from_line(line: &str) -> Option<String> {
-    if let Some(audio_pos) = line.find("Audio: ") {
-        let after_audio = &line[audio_pos + 7..];
-        if let Some(codec_end) = after_audio.find(' ') {
-            return Some(after_audio[..codec_end].to_string());
+fn build_image_command(request: &ConversionRequest) -> FfmpegCommand {
+    let quality = request.options.quality.as_deref();
+    let image = &request.options.image;
+    let scale = scale_filters(image.width, image.height, &image.scale, false);
+
+    let mut command = FfmpegCommand::new();
+    command.input(&request.input_path).option(transform::input_args(&image.transform, None));
+    let output = command.output(&request.output_path);
+    for filter in transform_filters(&image.transform, false) {
+        output.video_filter(filter);
+    }
+
+    let output_ext = extension(&request.output_path);
+    if output_ext == "webp" {
+        let quality_val = match quality {
+            Some("low") => "60",
+            Some("medium") => "80",
+            Some("high") => "95",
+            Some(q) if q.parse::<u8>().is_ok() => q,
+            _ => "80",
+        };
+        output.option(["-c:v", "libwebp", "-quality", quality_val]);
+    } else if output_ext == "ico" {
+        // ICO files are limited to 256px, default to a common icon size
+        if scale.is_empty() {
+            output.video_filter("scale=32:32");
         }
+        // PNG compression is good for ICO files
+        output.option(["-c:v", "png"]);
     }
-    None
+
+    for filter in scale {
+        output.video_filter(filter);
+    }
+
+    command
 }
 
-fn extract_bitrate_from_line(line: &str) -> Option<String> {
-    if let Some(bitrate_pos) = line.find("bitrate: ") {
-        let after_bitrate = &line[bitrate_pos + 9..];
-        if let Some(bitrate_end) = after_bitrate.find(' ') {
-            return Some(after_bitrate[..bitrate_end].to_string());
+// Checks every encoder the command selects against the detected FFmpeg build
+async fn ensure_encoders(command: &FfmpegCommand, format: &str) -> Result<(), ConversionError> {
+    for output in command.outputs() {
+        for encoder in output.codecs().filter(|e| *e != "copy") {
+            require_encoder(encoder, &format!("{} output", format))
+                .await
+                .map_err(|message| ConversionError::new(ErrorKind::EncoderMissing, message))?;
         }
     }
-    None
+    Ok(())
 }
 
-pub async fn convert_media(
-    input_path: &str,
-    output_path: &str,
-    format: &str,
-    quality: Option<&str>,
-    width: Option<u32>,
-    height: Option<u32>,
-    duration: Option<f64>,
-    duration_mode: Option<&str>,
-    fps: Option<f64>,
-    disable_audio: Option<bool>,
-    app_handle: AppHandle,
-) -> Result<(), String> {
-    // Reset cancellation flag
-    {
-        let mut cancelled = CONVERSION_CANCELLED.lock().unwrap();
-        *cancelled = false;
+// Resolves FFmpeg and detects its capabilities off the async workers, so the
+// command builders that run next only read caches
+async fn ffmpeg_path() -> Result<std::path::PathBuf, ConversionError> {
+    let info = resolve_async(Binary::Ffmpeg)
+        .await
+        .map_err(|message| ConversionError::new(ErrorKind::BinaryMissing, message))?;
+    if let Err(e) = capabilities::load().await {
+        log::warn!("Could not detect FFmpeg capabilities: {}", e);
     }
+    Ok(info.path)
+}
 
-    let ffmpeg_path = get_ffmpeg_path()?;
+fn invalid_options(message: String) -> ConversionError {
+    ConversionError::new(ErrorKind::InvalidOptions, message)
+}
+
+// Temporary file prefix unique to an output, e.g. for the two-pass statistics
+// that FFmpeg appends "-0.log" and friends to
+fn temp_prefix(purpose: &str, output_path: &str) -> String {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    output_path.hash(&mut hasher);
+    std::env::temp_dir()
+        .join(format!("{}-{:016x}", purpose, hasher.finish()))
+        .to_string_lossy()
+        .to_string()
+}
 
-    // Helper: image extensions
-    fn is_image_format(fmt: &str) -> bool {
-        matches!(fmt.to_lowercase().as_str(), "jpg" | "jpeg" | "png" | "webp" | "bmp" | "gif" | "tiff" | "ico")
+fn remove_pass_logs(log_prefix: &str) {
+    for suffix in ["-0.log", "-0.log.temp", "-0.log.mbtree", "-0.log.mbtree.temp"] {
+        let _ = std::fs::remove_file(format!("{}{}", log_prefix, suffix));
     }
+}
 
-    let input_ext = std::path::Path::new(input_path)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_lowercase();
-    let output_ext = std::path::Path::new(output_path)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_lowercase();
+// What a request runs: a single FFmpeg command, the passes of a size-targeted
+// encode, or pieces written to temporary files and then joined
+enum Plan {
+    Single(FfmpegCommand),
+    TwoPass { target: SizeTarget, log_prefix: String },
+    Pieces(Pieces),
+}
+
+struct Piece {
+    command: FfmpegCommand,
+    path: String,
+    duration: Option<f64>,
+}
 
-    let is_image = is_image_format(&input_ext) && is_image_format(&output_ext);
+struct Pieces {
+    pieces: Vec<Piece>,
+    list_path: String,
+    join: FfmpegCommand,
+}
 
-    if is_image {
-        return convert_image_simple(input_path, output_path, quality, width, height).await;
+impl Pieces {
+    fn remove_files(&self) {
+        for piece in &self.pieces {
+            let _ = std::fs::remove_file(&piece.path);
+        }
+        let _ = std::fs::remove_file(&self.list_path);
     }
+}
 
-    let mut args = vec!["-i".to_string(), input_path.to_string()];
+// Fast cuts of several segments: each one is copied into its own file, which
+// are then joined, still without re-encoding
+fn cut_pieces(request: &ConversionRequest, media_info: Option<&MediaInfo>, ranges: &[Segment]) -> Result<Pieces, String> {
+    let prefix = temp_prefix("ffmpeg-cut", &request.output_path);
+    let input_duration = media_info.and_then(|info| info.duration);
 
-    // Add overwrite flag early
-    args.push("-y".to_string());
+    let pieces = ranges
+        .iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let mut piece_request = request.clone();
+            piece_request.output_path = format!("{}-{}.{}", prefix, i, request.format.to_lowercase());
+            piece_request.options.trim = TrimOptions {
+                start: Some(range.start),
+                end: range.end,
+                cut: CutMode::Fast,
+                ..TrimOptions::default()
+            };
+            Ok(Piece {
+                command: build_command(&piece_request, media_info)?,
+                duration: range.end.or(input_duration).map(|end| end - range.start),
+                path: piece_request.output_path,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
 
-    // Handle duration parameter based on mode
-    if let Some(duration_secs) = duration {
-        match duration_mode.unwrap_or("trim") {
-            "trim" => {
-                args.extend(["-t".to_string(), duration_secs.to_string()]);
-            }
-            "compress" => {
-                if let Ok(media_info) = get_media_info(input_path).await {
-                    if let Some(original_duration) = media_info.duration {
-                        let speed_factor = original_duration / duration_secs;
-                        let video_filter = if let (Some(w), Some(h)) = (width, height) {
-                            format!(r"scale={}:{},setpts=PTS/{}", w, h, speed_factor)
-                        } else {
-                            format!("setpts=PTS/{}", speed_factor)
-                        };
-                        args.extend(["-filter:v".to_string(), video_filter]);
-                        if speed_factor >= 0.5 && speed_factor <= 4.0 {
-                            args.extend(["-filter:a".to_string(), format!("atempo={}", speed_factor)]);
-                        } else {
-                            args.extend(["-an".to_string()]);
-                        }
-                    }
-                }
-            }
-            _ => {
-                args.extend(["-t".to_string(), duration_secs.to_string()]);
+    Ok(join_pieces(request, pieces, &prefix))
+}
+
+// Smart cuts: re-encoded pieces around each cut, copied pieces between them
+fn smart_pieces(
+    request: &ConversionRequest,
+    media_info: Option<&MediaInfo>,
+    ranges: &[Segment],
+    keyframes: &[f64],
+) -> Result<Pieces, String> {
+    let media_info = media_info.ok_or("The input streams are unknown, so it can't be smart cut")?;
+    let source = media_info
+        .streams
+        .iter()
+        .find(|stream| stream.kind == StreamKind::Video)
+        .ok_or("The input has no video to cut")?;
+    let encode_args = smart_cut::encode_args(source, &request.format.to_lowercase())?;
+    let keep_audio = !request.options.audio.disabled;
+
+    let prefix = temp_prefix("ffmpeg-cut", &request.output_path);
+    let pieces = smart_cut::plan_pieces(ranges, keyframes)
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            let path = format!("{}-{}.{}", prefix, i, request.format.to_lowercase());
+            Piece {
+                command: smart_cut::piece_command(&request.input_path, piece, &path, &encode_args, keep_audio),
+                duration: piece.end.or(media_info.duration).map(|end| end - piece.start),
+                path,
             }
-        }
-    } else if let (Some(w), Some(h)) = (width, height) {
-        args.extend(["-vf".to_string(), format!("scale={}:{}", w, h)]);
+        })
+        .collect();
+    Ok(join_pieces(request, pieces, &prefix))
+}
+
+// Reversed outputs: the kept ranges cut into chunks short enough for `reverse`
+// to buffer, each reversed into its own file and joined last chunk first. A
+// boomerang plays the ranges forward in a first piece.
+fn reverse_plan(request: &ConversionRequest, media_info: Option<&MediaInfo>) -> Result<Plan, String> {
+    let options = &request.options;
+    let input_duration = media_info.and_then(|info| info.duration);
+    let video = media_info
+        .and_then(|info| info.streams.iter().find(|stream| stream.kind == StreamKind::Video))
+        .filter(|_| !is_audio_format(&request.format));
+    let seconds = reverse::chunk_seconds(video.and_then(StreamInfo::display_size), video.and_then(|stream| stream.fps));
+    let chunks = reverse::reversed_chunks(&options.trim.ranges(), input_duration, seconds);
+    if options.playback == Playback::Reverse && chunks.len() == 1 {
+        return Ok(Plan::Single(build_command(request, media_info)?));
     }
 
-    if let Some(target_fps) = fps {
-        args.extend(["-r".to_string(), target_fps.to_string()]);
+    // Compressing picks the speed from the whole input, the chunks keep it
+    let factor = speed::speed_factor(options, input_duration)?;
+    let prefix = temp_prefix("ffmpeg-reverse", &request.output_path);
+    let mut pieces = Vec::new();
+    let mut add_piece = |mut piece_request: ConversionRequest| -> Result<(), String> {
+        piece_request.output_path = format!("{}-{}.{}", prefix, pieces.len(), request.format.to_lowercase());
+        pieces.push(Piece {
+            command: build_command(&piece_request, media_info)?,
+            duration: speed::output_duration(&piece_request.options, input_duration),
+            path: piece_request.output_path,
+        });
+        Ok(())
+    };
+
+    if options.playback == Playback::Boomerang {
+        let mut forward = request.clone();
+        forward.options.playback = Playback::Forward;
+        add_piece(forward)?;
+    }
+    for chunk in chunks {
+        let mut backward = request.clone();
+        backward.options.playback = Playback::Reverse;
+        backward.options.trim = TrimOptions {
+            start: Some(chunk.start).filter(|start| *start > 0.0),
+            end: chunk.end,
+            ..TrimOptions::default()
+        };
+        backward.options.speed.factor = factor;
+        add_piece(backward)?;
     }
+    Ok(Plan::Pieces(join_pieces(request, pieces, &prefix)))
+}
 
-    if disable_audio.unwrap_or(false) {
-        args.extend(["-an".to_string()]);
+fn join_pieces(request: &ConversionRequest, pieces: Vec<Piece>, prefix: &str) -> Pieces {
+    let list_path = format!("{}.txt", prefix);
+    Pieces {
+        join: concat::join_command(&list_path, &request.output_path, true),
+        pieces,
+        list_path,
     }
+}
 
-    let crf_value = match quality {
-        Some("low") => Some("28"),
-        Some("medium") => Some("23"),
-        Some("high") => Some("18"),
-        Some(q) if q.parse::<u8>().is_ok() => Some(q),
-        _ => Some("23"),
-    };
+// Probes the input and plans its commands; options the builder can't honour
+// are reported as invalid options
+async fn prepare(request: &ConversionRequest) -> Result<(Plan, Option<MediaInfo>), ConversionError> {
+    request.validate().map_err(ConversionError::invalid_request)?;
 
-    match format {
-        "mp4" => {
-            args.extend(["-c:v".to_string(), "libx264".to_string()]);
-            if let Some(crf) = crf_value {
-                args.extend(["-crf".to_string(), crf.to_string()]);
+    let media_info = if is_image_conversion(request) {
+        None
+    } else {
+        match get_media_info(&request.input_path).await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                log::warn!("Could not probe {}: {}", request.input_path, e);
+                None
             }
-            args.extend(["-preset".to_string(), "medium".to_string()]);
         }
-        "webm" => {
-            args.extend(["-c:v".to_string(), "libvpx-vp9".to_string()]);
-            if let Some(crf) = crf_value {
-                args.extend(["-crf".to_string(), crf.to_string()]);
-            }
-            args.extend(["-b:v".to_string(), "0".to_string()]); // For constant quality mode
+    };
+
+    let ranges = request.options.trim.ranges();
+    let plan = match target_size::plan(request, media_info.as_ref()).map_err(invalid_options)? {
+        Some(target) => Plan::TwoPass {
+            target,
+            log_prefix: temp_prefix("ffmpeg2pass", &request.output_path),
+        },
+        None if request.options.trim.cut == CutMode::Fast && ranges.len() > 1 => {
+            Plan::Pieces(cut_pieces(request, media_info.as_ref(), &ranges).map_err(invalid_options)?)
         }
-        "avi" => {
-            args.extend(["-c:v".to_string(), "libx264".to_string()]);
-            if let Some(crf) = crf_value {
-                args.extend(["-crf".to_string(), crf.to_string()]);
-            }
+        None if request.options.trim.cut == CutMode::Smart && !ranges.is_empty() => {
+            let keyframes = get_keyframes(&request.input_path)
+                .await
+                .map_err(|message| ConversionError::new(ErrorKind::InputUnreadable, message))?;
+            let pieces = smart_pieces(request, media_info.as_ref(), &ranges, &keyframes).map_err(invalid_options)?;
+            Plan::Pieces(pieces)
         }
-        "mov" | "mkv" => {
-            args.extend(["-c:v".to_string(), "libx264".to_string()]);
-            if let Some(crf) = crf_value {
-                args.extend(["-crf".to_string(), crf.to_string()]);
-            }
+        None if request.options.playback != Playback::Forward => {
+            reverse_plan(request, media_info.as_ref()).map_err(invalid_options)?
         }
-        _ => {
-            args.extend(["-c:v".to_string(), "libx264".to_string()]);
-            if let Some(crf) = crf_value {
-                args.extend(["-crf".to_string(), crf.to_string()]);
-            }
+        None => Plan::Single(build_command(request, media_info.as_ref()).map_err(invalid_options)?),
+    };
+    Ok((plan, media_info))
+}
+
+// The request with any automatic crop replaced by the borders found in the input
+async fn resolve_request(request: &ConversionRequest) -> Result<ConversionRequest, ConversionError> {
+    request.validate().map_err(ConversionError::invalid_request)?;
+    let mut request = request.clone();
+    resolve_auto_crop(&request.input_path, &mut request.options).await?;
+    Ok(request)
+}
+
+async fn resolve_auto_crop(input_path: &str, options: &mut ConversionOptions) -> Result<(), ConversionError> {
+    for transform in [&mut options.video.transform, &mut options.image.transform] {
+        if transform.crop == Some(Crop::Auto) {
+            transform.crop = detect_black_borders(input_path).await?.crop.map(Crop::from);
         }
     }
+    Ok(())
+}
 
-    // Add progress reporting
-    args.extend(["-progress".to_string(), "pipe:1".to_string()]);
-    
-    // Output file
-    args.push(output_path.to_string());
+/// Looks for black borders in samples spread over the input.
+pub async fn detect_black_borders(input_path: &str) -> Result<CropDetection, ConversionError> {
+    let ffmpeg_path = ffmpeg_path().await?;
+    let unreadable = |message: String| ConversionError::new(ErrorKind::InputUnreadable, message);
+    let media_info = get_media_info(input_path).await.map_err(unreadable)?;
+    let (width, height) = media_info
+        .streams
+        .iter()
+        .find(|stream| stream.kind == StreamKind::Video)
+        .and_then(StreamInfo::display_size)
+        .ok_or_else(|| unreadable("The input has no video to look for borders in".to_string()))?;
 
-    log::info!("FFmpeg command: {} {}", ffmpeg_path.display(), args.join(" "));
+    let mut rects = Vec::new();
+    for range in crop_detect::sample_ranges(media_info.duration) {
+        let command = crop_detect::sample_command(input_path, &range);
+        let mut process = Command::new(&ffmpeg_path);
+        process
+            .args(command.to_args())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
 
-    // Create the command
-    let mut command = Command::new(&ffmpeg_path);
-    command
-        .args(&args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdin(Stdio::null()); // Ensure stdin is null
+        #[cfg(windows)]
+        process.creation_flags(CREATE_NO_WINDOW);
 
-    // Hide CMD window on Windows
-    #[cfg(windows)]
-    command.creation_flags(CREATE_NO_WINDOW);
+        let output = process.output().await.map_err(spawn_error)?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            let mut tail = StderrTail::default();
+            stderr.lines().for_each(|line| tail.push(line.to_string()));
+            return Err(ConversionError::from_ffmpeg(output.status.code(), tail.into_lines()));
+        }
+        rects.extend(crop_detect::last_crop(&stderr));
+    }
+
+    let detection = crop_detect::aggregate(&rects, width, height);
+    log::info!(
+        "Detected crop for {}: {:?} of {}x{} from {} samples",
+        input_path, detection.crop, width, height, detection.samples
+    );
+    Ok(detection)
+}
 
-    let mut child = command.spawn().map_err(|e| format!("Failed to start FFmpeg: {}", e))?;
+/// Which streams a remux of the request copies and which it re-encodes.
+pub async fn remux_plan(request: &ConversionRequest) -> Result<Vec<StreamPlan>, ConversionError> {
+    request.validate().map_err(ConversionError::invalid_request)?;
+    let media_info = get_media_info(&request.input_path)
+        .await
+        .map_err(|message| ConversionError::new(ErrorKind::InputUnreadable, message))?;
+    remux::plan(request, &media_info).map_err(invalid_options)
+}
 
-    // Get the total duration for progress calculation
-    let total_duration = if duration_mode == Some("trim") && duration.is_some() {
-        duration
-    } else {
-        match get_media_info(input_path).await {
-            Ok(info) => info.duration,
-            Err(_) => None,
+/// Returns the exact command line a request would run, without running it.
+pub async fn preview_command(request: &ConversionRequest) -> Result<String, ConversionError> {
+    let ffmpeg_path = ffmpeg_path().await?;
+    let request = &resolve_request(request).await?;
+    let (plan, media_info) = prepare(request).await?;
+    match plan {
+        Plan::Single(command) => Ok(command.to_command_line(&ffmpeg_path)),
+        Plan::TwoPass { target, log_prefix } => {
+            let lines = [1, 2]
+                .into_iter()
+                .map(|number| {
+                    let pass = Pass { number, target: &target, log_prefix: &log_prefix };
+                    build_pass_command(request, media_info.as_ref(), &pass)
+                        .map(|command| command.to_command_line(&ffmpeg_path))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid_options)?;
+            Ok(lines.join(" && "))
         }
-    };
+        Plan::Pieces(pieces) => {
+            let lines: Vec<String> = pieces
+                .pieces
+                .iter()
+                .map(|piece| &piece.command)
+                .chain([&pieces.join])
+                .map(|command| command.to_command_line(&ffmpeg_path))
+                .collect();
+            Ok(lines.join(" && "))
+        }
+    }
+}
 
-    // Handle both stdout (progress) and stderr (logs) in separate threads
-    let stdout_handle = if let Some(stdout) = child.stdout.take() {
-        let app_handle_clone = app_handle.clone();
-        Some(thread::spawn(move || {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
-                // Check for cancellation
-                {
-                    let cancelled = CONVERSION_CANCELLED.lock().unwrap();
-                    if *cancelled {
-                        break;
-                    }
-                }
+pub async fn convert_media(
+    request: &ConversionRequest,
+    job: JobHandle,
+    app_handle: AppHandle,
+) -> Result<(), ConversionError> {
+    let ffmpeg_path = ffmpeg_path().await?;
+    let request = &resolve_request(request).await?;
+    let output_path = request.output_path.as_str();
 
-                if let Ok(line_content) = line {
-                    log::debug!("FFmpeg stdout: {}", line_content);
-                    
-                    if let Some(progress) = parse_progress_line(&line_content, total_duration) {
-                        let _ = app_handle_clone.emit("conversion-progress", progress);
-                    }
-
-                    if line_content.contains("progress=end") {
-                        log::info!("Conversion completed successfully");
-                        break;
-                    }
-                }
+    let (plan, media_info) = prepare(request).await?;
+
+    let total_duration = speed::output_duration(&request.options, media_info.as_ref().and_then(|info| info.duration))
+        .map(|duration| duration * request.options.playback.repeats() as f64);
+
+    match plan {
+        Plan::Single(command) => {
+            // Fail early with a clear message instead of an FFmpeg "Unknown encoder"
+            ensure_encoders(&command, &request.format).await?;
+            log::info!("FFmpeg command: {}", command.to_command_line(&ffmpeg_path));
+
+            if is_image_conversion(request) {
+                return run_image_command(&ffmpeg_path, &command, output_path, &job).await;
             }
-        }))
-    } else {
-        None
-    };
 
-    let stderr_handle = if let Some(stderr) = child.stderr.take() {
-        Some(thread::spawn(move || {
-            let reader = BufReader::new(stderr);
-            for line in reader.lines() {
-                if let Ok(line_content) = line {
-                    log::debug!("FFmpeg stderr: {}", line_content);
-                }
+            if let (true, Some(info)) = (request.options.remux, media_info.as_ref()) {
+                report_remux_plan(request, info, &job, &app_handle);
             }
-        }))
+
+            let tracker = ProgressTracker::new(job.id, total_duration);
+            run_with_progress(&ffmpeg_path, &command, tracker, output_path, &job, &app_handle).await?;
+            verify_output(output_path)?;
+        }
+        Plan::TwoPass { target, log_prefix } => {
+            let passes = TwoPassRun {
+                request,
+                media_info: media_info.as_ref(),
+                ffmpeg_path: &ffmpeg_path,
+                log_prefix: &log_prefix,
+                total_duration,
+                job: &job,
+                app_handle: &app_handle,
+            };
+            let result = passes.run(target).await;
+            remove_pass_logs(&log_prefix);
+            result?;
+        }
+        Plan::Pieces(pieces) => {
+            let result = run_pieces(&ffmpeg_path, &pieces, request, total_duration, &job, &app_handle).await;
+            pieces.remove_files();
+            result?;
+        }
+    }
+
+    log::info!("Media conversion completed successfully");
+    Ok(())
+}
+
+/// Joins the inputs of a concat request, by stream copy when they match.
+pub async fn concat_media(
+    request: &ConcatRequest,
+    job: JobHandle,
+    app_handle: AppHandle,
+) -> Result<(), ConversionError> {
+    let ffmpeg_path = ffmpeg_path().await?;
+    let output_path = request.output_path.as_str();
+
+    let mut inputs = Vec::with_capacity(request.input_paths.len());
+    for path in &request.input_paths {
+        let info = get_media_info(path)
+            .await
+            .map_err(|message| ConversionError::new(ErrorKind::InputUnreadable, format!("{}: {}", path, message)))?;
+        inputs.push(info);
+    }
+    concat::check_inputs(request, &inputs).map_err(invalid_options)?;
+    let total_duration = inputs.iter().map(|info| info.duration).sum::<Option<f64>>();
+
+    let list_path = format!("{}.txt", temp_prefix("ffmpeg-concat", output_path));
+    let command = if concat::can_copy(request, &inputs) {
+        log::info!("Inputs share their codec parameters, joining without re-encoding");
+        std::fs::write(&list_path, concat::list_file(&request.input_paths))
+            .map_err(|e| format!("Failed to write the list of inputs: {}", e))?;
+        concat::join_command(&list_path, output_path, !request.options.audio.disabled)
     } else {
-        None
+        concat::filter_command(request, &inputs).map_err(invalid_options)?
     };
 
-    // Wait for the process to complete
-    let exit_status = child.wait().map_err(|e| format!("Failed to wait for FFmpeg: {}", e))?;
+    let result = async {
+        ensure_encoders(&command, &request.format).await?;
+        log::info!("FFmpeg command: {}", command.to_command_line(&ffmpeg_path));
+        let tracker = ProgressTracker::new(job.id, total_duration);
+        run_with_progress(&ffmpeg_path, &command, tracker, output_path, &job, &app_handle).await?;
+        verify_output(output_path)
+    }
+    .await;
+    let _ = std::fs::remove_file(&list_path);
+    result?;
 
-    // Wait for the threads to complete
-    if let Some(handle) = stdout_handle {
-        let _ = handle.join();
+    log::info!("Concatenation completed successfully");
+    Ok(())
+}
+
+/// Splits the input into parts and writes a manifest of them next to the parts.
+pub async fn split_media(
+    request: &SplitRequest,
+    job: JobHandle,
+    app_handle: AppHandle,
+) -> Result<(), ConversionError> {
+    request.validate().map_err(ConversionError::invalid_request)?;
+    let ffmpeg_path = ffmpeg_path().await?;
+    let mut request = request.clone();
+    resolve_auto_crop(&request.input_path, &mut request.options).await?;
+    let request = &request;
+    let media_info = get_media_info(&request.input_path)
+        .await
+        .map_err(|message| ConversionError::new(ErrorKind::InputUnreadable, message))?;
+
+    let run = SplitRun {
+        request,
+        media_info: &media_info,
+        ffmpeg_path: &ffmpeg_path,
+        job: &job,
+        app_handle: &app_handle,
+    };
+    let mut parts = Vec::new();
+    let result = match request.by {
+        SplitBy::Duration { seconds } => run.by_duration(seconds, &mut parts).await,
+        SplitBy::Size { max_mb } => run.by_size(max_mb, &mut parts).await,
+        SplitBy::Chapters => run.by_chapters(&mut parts).await,
+    };
+    if let Err(e) = result {
+        // Don't leave an incomplete set of parts behind
+        for part in &parts {
+            let _ = std::fs::remove_file(&part.path);
+        }
+        return Err(e);
     }
-    if let Some(handle) = stderr_handle {
-        let _ = handle.join();
+
+    let manifest = SplitManifest {
+        job_id: job.id,
+        input_path: request.input_path.clone(),
+        parts,
+    };
+    let json = serde_json::to_string_pretty(&manifest).map_err(|e| format!("Failed to write the split manifest: {}", e))?;
+    std::fs::write(request.manifest_path(), json).map_err(|e| format!("Failed to write the split manifest: {}", e))?;
+    let _ = app_handle.emit("split-manifest", &manifest);
+
+    log::info!("Split into {} parts", manifest.parts.len());
+    Ok(())
+}
+
+// Everything the parts of a split share
+struct SplitRun<'a> {
+    request: &'a SplitRequest,
+    media_info: &'a MediaInfo,
+    ffmpeg_path: &'a std::path::Path,
+    job: &'a JobHandle,
+    app_handle: &'a AppHandle,
+}
+
+impl SplitRun<'_> {
+    // A single segment muxer run. Copied parts start at the first keyframe after
+    // each boundary, re-encoded ones get keyframes forced onto the boundaries.
+    async fn by_duration(&self, seconds: f64, parts: &mut Vec<SplitPart>) -> Result<(), ConversionError> {
+        let request = self.request;
+        let duration = self.media_info.duration;
+        let count = duration.map_or(1, |duration| (duration / seconds).ceil() as usize);
+        let pattern = request.part_pattern(count);
+        let list_path = format!("{}.csv", temp_prefix("ffmpeg-split", &pattern));
+
+        let mut command = self.part_command(&pattern, 0.0, None)?;
+        let output = command
+            .last_output()
+            .ok_or_else(|| ConversionError::from("The split command has no output"))?;
+        if is_video_format(&request.format) && request.options.trim.cut != CutMode::Fast {
+            output.option([
+                "-force_key_frames".to_string(),
+                format!("expr:gte(t,n_forced*{})", seconds_arg(seconds)),
+            ]);
+        }
+        output.option(split::segment_args(seconds, &list_path));
+
+        let tracker = ProgressTracker::new(self.job.id, duration);
+        let result = self.run(&command, tracker, &pattern).await;
+        // The list also names the parts finished before a failure
+        if let Ok(list) = std::fs::read_to_string(&list_path) {
+            parts.extend(split::parse_segment_list(&list, &request.output_dir));
+        }
+        let _ = std::fs::remove_file(&list_path);
+        result?;
+
+        if parts.is_empty() {
+            return Err(ConversionError::new(ErrorKind::OutputMissing, "No parts were written"));
+        }
+        for part in parts.iter() {
+            verify_output(&part.path)?;
+        }
+        Ok(())
     }
 
-    // Check for cancellation
-    {
-        let cancelled = CONVERSION_CANCELLED.lock().unwrap();
-        if *cancelled {
-            // Clean up output file if it exists
-            let _ = std::fs::remove_file(output_path);
-            return Err("Conversion cancelled by user".to_string());
+    // Each part starts where the previous one ended and FFmpeg stops writing it
+    // at the size limit, so how much fits is only known once it's written
+    async fn by_size(&self, max_mb: f64, parts: &mut Vec<SplitPart>) -> Result<(), ConversionError> {
+        let request = self.request;
+        let duration = self
+            .media_info
+            .duration
+            .ok_or_else(|| invalid_options("The input duration is unknown, so it can't be split by size".to_string()))?;
+        let max_bytes = (max_mb * 1_000_000.0) as u64;
+        let limit = ((max_bytes as f64 * (1.0 - SPLIT_SIZE_MARGIN)) as u64).max(1);
+        let input_size = std::fs::metadata(&request.input_path).map_or(0, |metadata| metadata.len());
+        // Only an estimate, re-encoded parts may hold more or less of the input
+        let estimate = (input_size.div_ceil(limit) as usize).max(1);
+        let width = split::index_width(estimate);
+        // Copied parts begin at the keyframe before where they're asked to
+        let keyframes = if request.options.trim.cut == CutMode::Fast && is_video_format(&request.format) {
+            get_keyframes(&request.input_path)
+                .await
+                .map_err(|message| ConversionError::new(ErrorKind::InputUnreadable, message))?
+        } else {
+            Vec::new()
+        };
+
+        let mut start = 0.0;
+        while duration - start > MIN_SPLIT_PART {
+            let index = parts.len() + 1;
+            let path = request.part_path(&format!("{:0width$}", index, width = width), None);
+            let mut command = self.part_command(&path, start, None)?;
+            if let Some(output) = command.last_output() {
+                output.option(["-fs".to_string(), limit.to_string()]);
+            }
+
+            let tracker = ProgressTracker::for_pass(
+                self.job.id,
+                Some(duration / estimate as f64),
+                index as u32,
+                estimate.max(index) as u32,
+            );
+            self.run(&command, tracker, &path).await?;
+            let size = verify_output(&path)?;
+            let written = get_media_info(&path).await.ok().and_then(|info| info.duration).unwrap_or(0.0);
+            let (part_start, part_end) = split::written_range(start, written, &keyframes);
+            parts.push(SplitPart {
+                index: index as u32,
+                path,
+                start: part_start,
+                end: Some(part_end.min(duration)),
+                title: None,
+            });
+
+            // The next part continues at this one's real end, past any pre-roll
+            if part_end - start < MIN_SPLIT_PART {
+                return Err(invalid_options(format!(
+                    "A {} MB part is too small to hold any of the input",
+                    max_mb
+                )));
+            }
+            if size > max_bytes {
+                log::warn!("Part {} is {} bytes, over the {} byte limit", index, size, max_bytes);
+            }
+            start = part_end;
         }
+        Ok(())
     }
 
-    if !exit_status.success() {
-        log::error!("FFmpeg failed with exit code: {:?}", exit_status.code());
-        return Err(format!("FFmpeg conversion failed with exit code: {:?}", exit_status.code()));
+    // One part per chapter, cut like a trimmed conversion
+    async fn by_chapters(&self, parts: &mut Vec<SplitPart>) -> Result<(), ConversionError> {
+        let request = self.request;
+        let chapters = &self.media_info.chapters;
+        if chapters.is_empty() {
+            return Err(invalid_options("The input has no chapters to split at".to_string()));
+        }
+        let width = split::index_width(chapters.len());
+
+        for (i, chapter) in chapters.iter().enumerate() {
+            let index = i + 1;
+            let path = request.part_path(&format!("{:0width$}", index, width = width), chapter.title.as_deref());
+            // The last chapter runs to the end of the input, whatever its end time says
+            let end = Some(chapter.end).filter(|end| *end > chapter.start && index < chapters.len());
+            let command = self.part_command(&path, chapter.start, end)?;
+
+            let part_duration = end.or(self.media_info.duration).map(|end| end - chapter.start);
+            let tracker = ProgressTracker::for_pass(self.job.id, part_duration, index as u32, chapters.len() as u32);
+            self.run(&command, tracker, &path).await?;
+            verify_output(&path)?;
+            parts.push(SplitPart {
+                index: index as u32,
+                path,
+                start: chapter.start,
+                end: end.or(self.media_info.duration),
+                title: chapter.title.clone(),
+            });
+        }
+        Ok(())
     }
 
-    // Verify output file exists and has content
-    if let Ok(metadata) = std::fs::metadata(output_path) {
-        if metadata.len() == 0 {
-            return Err("Output file is empty - conversion may have failed".to_string());
+    // The command writing the given range. Fast cuts of the whole input are plain
+    // stream copies, the conversion builder only copies when there's a range.
+    fn part_command(&self, output_path: &str, start: f64, end: Option<f64>) -> Result<FfmpegCommand, ConversionError> {
+        let request = self.request;
+        let whole = start <= 0.0 && end.is_none();
+        if whole && request.options.trim.cut == CutMode::Fast && is_video_format(&request.format) {
+            return Ok(split::copy_command(
+                &request.input_path,
+                output_path,
+                !request.options.audio.disabled,
+            ));
         }
-        log::info!("Conversion completed successfully. Output file size: {} bytes", metadata.len());
-    } else {
-        return Err("Output file was not created".to_string());
+        let part = request.part(output_path.to_string(), start, end);
+        build_command(&part, Some(self.media_info)).map_err(invalid_options)
     }
 
-    log::info!("Media conversion completed successfully");
+    async fn run(&self, command: &FfmpegCommand, tracker: ProgressTracker, output_path: &str) -> Result<(), ConversionError> {
+        ensure_encoders(command, &self.request.format).await?;
+        log::info!("FFmpeg command: {}", command.to_command_line(self.ffmpeg_path));
+        run_with_progress(self.ffmpeg_path, command, tracker, output_path, self.job, self.app_handle).await
+    }
+}
+
+// Writes every piece, then joins them into the output. Each command counts as
+// one pass of the progress.
+async fn run_pieces(
+    ffmpeg_path: &std::path::Path,
+    pieces: &Pieces,
+    request: &ConversionRequest,
+    total_duration: Option<f64>,
+    job: &JobHandle,
+    app_handle: &AppHandle,
+) -> Result<(), ConversionError> {
+    let steps = pieces.pieces.len() as u32 + 1;
+    for (i, piece) in pieces.pieces.iter().enumerate() {
+        ensure_encoders(&piece.command, &request.format).await?;
+        log::info!("FFmpeg command: {}", piece.command.to_command_line(ffmpeg_path));
+        let tracker = ProgressTracker::for_pass(job.id, piece.duration, i as u32 + 1, steps);
+        run_with_progress(ffmpeg_path, &piece.command, tracker, &piece.path, job, app_handle).await?;
+        verify_output(&piece.path)?;
+    }
+
+    let paths: Vec<String> = pieces.pieces.iter().map(|piece| piece.path.clone()).collect();
+    std::fs::write(&pieces.list_path, concat::list_file(&paths))
+        .map_err(|e| format!("Failed to write the list of pieces: {}", e))?;
+
+    log::info!("FFmpeg command: {}", pieces.join.to_command_line(ffmpeg_path));
+    let tracker = ProgressTracker::for_pass(job.id, total_duration, steps, steps);
+    run_with_progress(ffmpeg_path, &pieces.join, tracker, &request.output_path, job, app_handle).await?;
+    verify_output(&request.output_path)?;
     Ok(())
 }
 
-async fn convert_image_simple(
-    input_path: &str,
-    output_path: &str,
-    quality: Option<&str>,
-    width: Option<u32>,
-    height: Option<u32>,
-) -> Result<(), String> {
-    let ffmpeg_path = get_ffmpeg_path()?;
-    
-    let mut args = vec!["-i".to_string(), input_path.to_string()];
-    
-    let output_ext = std::path::Path::new(output_path)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_lowercase();
+// Logs and emits which streams are copied and which re-encoded
+fn report_remux_plan(request: &ConversionRequest, media_info: &MediaInfo, job: &JobHandle, app_handle: &AppHandle) {
+    let Ok(streams) = remux::plan(request, media_info) else {
+        return;
+    };
+    for stream in &streams {
+        log::info!(
+            "Stream {} ({}): {:?}, {}",
+            stream.index,
+            stream.codec.as_deref().unwrap_or("unknown"),
+            stream.action,
+            stream.reason
+        );
+    }
+    let _ = app_handle.emit("remux-plan", RemuxReport { job_id: job.id, streams });
+}
 
-    if output_ext == "webp" {
-        args.extend(["-c:v".to_string(), "libwebp".to_string()]);
-        let quality_val = match quality {
-            Some("low") => "60",
-            Some("medium") => "80",
-            Some("high") => "95",
-            Some(q) if q.parse::<u8>().is_ok() => q,
-            _ => "80",
-        };
-        args.extend(["-quality".to_string(), quality_val.to_string()]);
-        
-        // Add scaling for WebP if dimensions are specified
-        if let (Some(w), Some(h)) = (width, height) {
-            args.extend(["-vf".to_string(), format!("scale={}:{}", w, h)]);
-        }
-    } else if output_ext == "ico" {
-        // For ICO format, we need to specify multiple sizes for best compatibility
-        // ICO files typically contain multiple resolutions
-        if let (Some(w), Some(h)) = (width, height) {
-            // Use the specified dimensions
-            args.extend(["-vf".to_string(), format!("scale={}:{}", w, h)]);
-        } else {
-            // Default to common icon sizes if no dimensions specified
-            args.extend(["-vf".to_string(), "scale=32:32".to_string()]);
-        }
-        // PNG compression is good for ICO files
-        args.extend(["-c:v".to_string(), "png".to_string()]);
-    } else {
-        // For other formats, just add scaling if dimensions are specified
-        if let (Some(w), Some(h)) = (width, height) {
-            args.extend(["-vf".to_string(), format!("scale={}:{}", w, h)]);
+// Everything the passes of a size-targeted encode share
+struct TwoPassRun<'a> {
+    request: &'a ConversionRequest,
+    media_info: Option<&'a MediaInfo>,
+    ffmpeg_path: &'a std::path::Path,
+    log_prefix: &'a str,
+    total_duration: Option<f64>,
+    job: &'a JobHandle,
+    app_handle: &'a AppHandle,
+}
+
+impl TwoPassRun<'_> {
+    // Runs both passes, then repeats the second one at a lower bitrate while the
+    // output is larger than the target. The first pass statistics stay valid.
+    async fn run(&self, mut target: SizeTarget) -> Result<(), ConversionError> {
+        log::info!(
+            "Targeting {} bytes with {} kbit/s video and {} kbit/s audio",
+            target.max_bytes, target.video_kbps, target.audio_kbps
+        );
+        self.run_pass(1, &target).await?;
+
+        let mut retries = 0;
+        loop {
+            self.run_pass(2, &target).await?;
+            let size = verify_output(&self.request.output_path)?;
+            if size <= target.max_bytes {
+                return Ok(());
+            }
+
+            let lower = target_size::retry(&target, size).filter(|_| retries < MAX_SIZE_RETRIES);
+            let Some(lower) = lower else {
+                return Err(ConversionError::new(
+                    ErrorKind::SizeTargetMissed,
+                    format!(
+                        "The output is {:.2} MB, above the {:.2} MB target",
+                        size as f64 / 1_000_000.0,
+                        target.max_bytes as f64 / 1_000_000.0
+                    ),
+                ));
+            };
+            log::info!(
+                "Output is {} bytes, over the {} byte target; retrying at {} kbit/s",
+                size, target.max_bytes, lower.video_kbps
+            );
+            target = lower;
+            retries += 1;
         }
     }
 
-    args.extend(["-y".to_string(), output_path.to_string()]);
+    async fn run_pass(&self, number: u32, target: &SizeTarget) -> Result<(), ConversionError> {
+        let pass = Pass { number, target, log_prefix: self.log_prefix };
+        let command = build_pass_command(self.request, self.media_info, &pass).map_err(invalid_options)?;
+        ensure_encoders(&command, &self.request.format).await?;
+        log::info!("FFmpeg pass {} command: {}", number, command.to_command_line(self.ffmpeg_path));
+
+        let tracker = ProgressTracker::for_pass(self.job.id, self.total_duration, number, 2);
+        run_with_progress(
+            self.ffmpeg_path,
+            &command,
+            tracker,
+            &self.request.output_path,
+            self.job,
+            self.app_handle,
+        )
+        .await
+    }
+}
+
+// Runs FFmpeg with `-progress` output and forwards the updates to the frontend
+async fn run_with_progress(
+    ffmpeg_path: &std::path::Path,
+    command: &FfmpegCommand,
+    mut tracker: ProgressTracker,
+    output_path: &str,
+    job: &JobHandle,
+    app_handle: &AppHandle,
+) -> Result<(), ConversionError> {
+    // Create the command
+    let mut process = Command::new(ffmpeg_path);
+    process
+        .args(command.to_args())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdin(Stdio::piped()) // Used to send `q` on cancellation
+        .kill_on_drop(true);
+
+    // Hide CMD window on Windows
+    #[cfg(windows)]
+    process.creation_flags(CREATE_NO_WINDOW);
+
+    let mut child = process.spawn().map_err(spawn_error)?;
+
+    // Read stdout (progress) and stderr (logs) concurrently so neither pipe fills up
+    let stdout_task = child.stdout.take().map(|stdout| {
+        let app_handle_clone = app_handle.clone();
+        let job = job.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line_content)) = lines.next_line().await {
+                // Keep draining stdout after cancellation so FFmpeg never
+                // blocks on a full pipe while it shuts down
+                if job.is_cancelled() {
+                    continue;
+                }
+
+                log::debug!("FFmpeg stdout: {}", line_content);
+
+                if let Some(progress) = tracker.push_line(&line_content) {
+                    let _ = app_handle_clone.emit("conversion-progress", progress);
+                }
+            }
+        })
+    });
+
+    let stderr_task = collect_stderr(&mut child);
+
+    // Wait for the process to complete
+    let exit_status = wait_for_ffmpeg(&mut child, job).await?;
+
+    // Both readers end once the pipes close
+    if let Some(task) = stdout_task {
+        let _ = task.await;
+    }
+    let stderr_tail = stderr_task.await.unwrap_or_default();
+
+    check_exit(exit_status, stderr_tail, output_path, job)
+}
+
+// Verifies the output file exists and has content, returns its size
+fn verify_output(output_path: &str) -> Result<u64, ConversionError> {
+    match std::fs::metadata(output_path) {
+        Ok(metadata) if metadata.len() == 0 => Err(ConversionError::new(
+            ErrorKind::OutputMissing,
+            "Output file is empty - conversion may have failed",
+        )),
+        Ok(metadata) => {
+            log::info!("Conversion completed successfully. Output file size: {} bytes", metadata.len());
+            Ok(metadata.len())
+        }
+        Err(_) => Err(ConversionError::new(ErrorKind::OutputMissing, "Output file was not created")),
+    }
+}
 
-    let mut cmd = Command::new(&ffmpeg_path);
-    cmd.args(&args).stdout(Stdio::piped()).stderr(Stdio::piped());
+async fn run_image_command(
+    ffmpeg_path: &std::path::Path,
+    command: &FfmpegCommand,
+    output_path: &str,
+    job: &JobHandle,
+) -> Result<(), ConversionError> {
+    let mut cmd = Command::new(ffmpeg_path);
+    cmd.args(command.to_args())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
     
     #[cfg(windows)]
     cmd.creation_flags(CREATE_NO_WINDOW);
     
-    let output = cmd.output().map_err(|e| format!("Failed to execute FFmpeg: {}", e))?;
+    let mut child = cmd.spawn().map_err(spawn_error)?;
+    let stderr_task = collect_stderr(&mut child);
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Image conversion failed: {}", stderr));
-    }
+    let exit_status = wait_for_ffmpeg(&mut child, job).await?;
+    let stderr_tail = stderr_task.await.unwrap_or_default();
 
-    Ok(())
+    check_exit(exit_status, stderr_tail, output_path, job)
 }
 
-fn parse_progress_line(line: &str, total_duration: Option<f64>) -> Option<ConversionProgress> {
-    let mut progress = ConversionProgress {
-        frame: None,
-        fps: None,
-        bitrate: None,
-        total_size: None,
-        out_time_us: None,
-        speed: None,
-        progress: None,
-        percentage: None,
+fn spawn_error(e: std::io::Error) -> ConversionError {
+    let kind = match e.kind() {
+        std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        _ => ErrorKind::BinaryMissing,
     };
+    ConversionError::new(kind, format!("Failed to start FFmpeg: {}", e))
+}
 
-    // Parse key=value pairs from FFmpeg progress output
-    for part in line.split_whitespace() {
-        if let Some((key, value)) = part.split_once('=') {
-            match key {
-                "frame" => {
-                    progress.frame = value.parse().ok();
-                }
-                "fps" => {
-                    progress.fps = value.parse().ok();
-                }
-                "bitrate" => {
-                    progress.bitrate = Some(value.to_string());
-                }
-                "total_size" => {
-                    progress.total_size = value.parse().ok();
-                }
-                "out_time_us" => {
-                    progress.out_time_us = value.parse().ok();
-                }
-                "speed" => {
-                    if let Ok(speed_val) = value.trim_end_matches('x').parse::<f64>() {
-                        progress.speed = Some(speed_val);
-                    }
-                }
-                "progress" => {
-                    progress.progress = Some(value.to_string());
-                    
-                    // Calculate percentage if we have duration
-                    if let (Some(out_time_us), Some(duration)) = (progress.out_time_us, total_duration) {
-                        let current_time = out_time_us as f64 / 1_000_000.0; // Convert to seconds
-                        let percentage = (current_time / duration * 100.0).min(100.0);
-                        progress.percentage = Some(percentage);
-                    }
-                }
-                _ => {}
+// Logs stderr as it arrives and keeps its last lines for the error report
+fn collect_stderr(child: &mut Child) -> JoinHandle<Vec<String>> {
+    let stderr = child.stderr.take();
+    tauri::async_runtime::spawn(async move {
+        let mut tail = StderrTail::default();
+        if let Some(stderr) = stderr {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line_content)) = lines.next_line().await {
+                log::debug!("FFmpeg stderr: {}", line_content);
+                tail.push(line_content);
             }
         }
+        tail.into_lines()
+    })
+}
+
+fn check_exit(
+    exit_status: ExitStatus,
+    stderr_tail: Vec<String>,
+    output_path: &str,
+    job: &JobHandle,
+) -> Result<(), ConversionError> {
+    if job.is_cancelled() {
+        // Clean up output file if it exists
+        let _ = std::fs::remove_file(output_path);
+        return Err(ConversionError::cancelled());
     }
 
-    // Only return progress if we have meaningful data
-    if progress.frame.is_some() || progress.progress.is_some() {
-        Some(progress)
-    } else {
-        None
+    if !exit_status.success() {
+        let error = ConversionError::from_ffmpeg(exit_status.code(), stderr_tail);
+        log::error!("FFmpeg failed with exit code {:?}: {}", exit_status.code(), error);
+        return Err(error);
     }
-}
 
-pub fn cancel_conversion() -> Result<(), String> {
-    let mut cancelled = CONVERSION_CANCELLED.lock().unwrap();
-    *cancelled = true;
-    log::info!("Conversion cancellation requested");
     Ok(())
 }
 
-pub fn get_supported_formats() -> Vec<&'static str> {
-    vec![
-        // Video formats
-        "mp4", "avi", "mov", "mkv", "webm", "flv", "wmv", "m4v",
-        // Audio formats
-        "mp3", "wav", "flac", "aac", "ogg", "wma", "m4a",
-        // Image formats
-        "jpg", "jpeg", "png", "webp", "bmp", "gif", "tiff", "ico",
-    ]
+// Waits for FFmpeg to exit. When the job gets cancelled FFmpeg is first asked to
+// quit through `q` on stdin, and killed if it hasn't stopped within the grace period.
+async fn wait_for_ffmpeg(child: &mut Child, job: &JobHandle) -> Result<ExitStatus, ConversionError> {
+    let wait_error = |e: std::io::Error| ConversionError::from(format!("Failed to wait for FFmpeg: {}", e));
+
+    while !job.is_cancelled() {
+        if let Ok(status) = tokio::time::timeout(CANCEL_POLL_INTERVAL, child.wait()).await {
+            return status.map_err(wait_error);
+        }
+    }
+
+    log::info!("Stopping FFmpeg process {:?}", child.id());
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(b"q\n").await;
+    }
+
+    if let Ok(status) = tokio::time::timeout(CANCEL_GRACE_PERIOD, child.wait()).await {
+        return status.map_err(wait_error);
+    }
+
+    log::warn!("FFmpeg process {:?} did not quit in time, killing it", child.id());
+    let _ = child.start_kill();
+    child.wait().await.map_err(wait_error)
 }
 
-pub async fn convert_image_to_webp(
-    input_path: &str,
-    app_handle: AppHandle,
-) -> Result<String, String> {
-    let ffmpeg_path = get_ffmpeg_path()?;
-    
+// Output location used by the quick "convert to WebP" action
+pub fn webp_output_path(input_path: &str) -> Result<String, String> {
     // Get the input file name without extension
     let input_file = std::path::Path::new(input_path);
     let file_stem = input_file.file_stem()
@@ -655,40 +1278,7 @@ pub async fn convert_image_to_webp(
     // Get user's downloads folder
     let downloads_dir = get_downloads_directory()?;
     let output_path = downloads_dir.join(format!("{}.webp", file_stem));
-    
-    // Build FFmpeg command for image conversion
-    let args = vec![
-        "-i".to_string(),
-        input_path.to_string(),
-        "-c:v".to_string(),
-        "libwebp".to_string(),
-        "-quality".to_string(),
-        "80".to_string(),
-        "-y".to_string(),
-        output_path.to_string_lossy().to_string(),
-    ];
-
-    // Start FFmpeg process
-    let mut cmd = Command::new(&ffmpeg_path);
-    cmd.args(&args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-    
-    // Hide CMD window on Windows
-    #[cfg(windows)]
-    cmd.creation_flags(CREATE_NO_WINDOW);
-    
-    let output = cmd.output()
-        .map_err(|e| format!("Failed to start FFmpeg process: {}", e))?;
-
-    if output.status.success() {
-        let output_path_str = output_path.to_string_lossy().to_string();
-        let _ = app_handle.emit("image-conversion-complete", &output_path_str);
-        Ok(output_path_str)
-    } else {
-        let error_output = String::from_utf8_lossy(&output.stderr);
-        Err(format!("FFmpeg image conversion failed: {}", error_output))
-    }
+    Ok(output_path.to_string_lossy().to_string())
 }
 
 fn get_downloads_directory() -> Result<std::path::PathBuf, String> {
@@ -706,4 +1296,405 @@ fn get_downloads_directory() -> Result<std::path::PathBuf, String> {
     }
     
     Ok(downloads_dir)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(output_path: &str, format: &str, options: serde_json::Value) -> ConversionRequest {
+        ConversionRequest::for_test(output_path, format, options)
+    }
+
+    // 60 second input with an H.264 video track and two AAC tracks, the second one default
+    fn media_info() -> MediaInfo {
+        MediaInfo::for_test(
+            Some(60.0),
+            &[
+                json!({ "index": 0, "kind": "video", "codec": "h264", "is_default": true }),
+                json!({ "index": 1, "kind": "audio", "codec": "aac" }),
+                json!({ "index": 2, "kind": "audio", "codec": "aac", "is_default": true }),
+            ],
+        )
+    }
+
+    // Each case lists the output arguments between the input and the output path
+    fn assert_cases(cases: Vec<(&str, ConversionRequest, Vec<&str>)>) {
+        for (name, request, output_args) in cases {
+            let expected: Vec<&str> = ["-y", "-hide_banner", "-progress", "pipe:1", "-nostats", "-i", "in.mkv"]
+                .into_iter()
+                .chain(output_args)
+                .chain([request.output_path.as_str()])
+                .collect();
+            let command = build_command(&request, Some(&media_info())).unwrap();
+            assert_eq!(command.to_args(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn builds_video_commands() {
+        let cases: Vec<(&str, ConversionRequest, Vec<&str>)> = vec![
+            (
+                "mp4 defaults",
+                request("out.mp4", "mp4", json!({})),
+                vec!["-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac"],
+            ),
+            (
+                "webm with a quality preset",
+                request("out.webm", "webm", json!({ "quality": "high" })),
+                vec!["-c:v", "libvpx-vp9", "-crf", "18", "-b:v", "0", "-c:a", "libopus"],
+            ),
+            (
+                "mkv with a numeric quality",
+                request("out.mkv", "mkv", json!({ "quality": 30 })),
+                vec!["-c:v", "libx264", "-crf", "30", "-c:a", "aac"],
+            ),
+            (
+                "scale, fps and no audio",
+                request(
+                    "out.mp4",
+                    "mp4",
+                    json!({ "video": { "width": 1280, "height": 720, "fps": 30 }, "audio": { "disabled": true } }),
+                ),
+                vec![
+                    "-filter:v", "scale=1280:720,setsar=1", "-r", "30", "-an",
+                    "-c:v", "libx264", "-crf", "23", "-preset", "medium",
+                ],
+            ),
+            (
+                "constant bitrate with preset and tune",
+                request(
+                    "out.mkv",
+                    "mkv",
+                    json!({ "video": {
+                        "rate_control": { "mode": "cbr", "bitrate": 4000, "buffer_size": 8000 },
+                        "preset": "veryfast",
+                        "tune": "zerolatency",
+                    } }),
+                ),
+                vec![
+                    "-c:v", "libx264", "-b:v", "4000k", "-minrate", "4000k", "-maxrate", "4000k",
+                    "-bufsize", "8000k", "-preset", "veryfast", "-tune", "zerolatency", "-c:a", "aac",
+                ],
+            ),
+            (
+                "hevc in mp4 with an audio bitrate",
+                request("out.mp4", "mp4", json!({ "video": { "codec": "h265" }, "audio": { "bitrate": 192 } })),
+                vec!["-c:v", "libx265", "-crf", "26", "-tag:v", "hvc1", "-c:a", "aac", "-b:a", "192k"],
+            ),
+            (
+                "prores in mov",
+                request("out.mov", "mov", json!({ "quality": "high", "video": { "codec": "prores" } })),
+                vec![
+                    "-c:v", "prores_ks", "-profile:v", "3", "-vendor", "apl0", "-pix_fmt", "yuv422p10le",
+                    "-c:a", "pcm_s16le",
+                ],
+            ),
+            (
+                "trim",
+                request("out.mp4", "mp4", json!({ "trim": { "duration": 10 } })),
+                vec!["-t", "10", "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac"],
+            ),
+            (
+                "compress fits the input into the duration",
+                request(
+                    "out.mp4",
+                    "mp4",
+                    json!({ "video": { "width": 640, "height": 360 }, "trim": { "duration": 30, "mode": "compress" } }),
+                ),
+                vec![
+                    "-filter:v", "scale=640:360,setsar=1,setpts=PTS/2",
+                    "-filter:a", "atempo=2",
+                    "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac",
+                ],
+            ),
+            (
+                "compress chains atempo beyond its range",
+                request("out.mp4", "mp4", json!({ "trim": { "duration": 10, "mode": "compress" } })),
+                vec![
+                    "-filter:v", "setpts=PTS/6",
+                    "-filter:a", "atempo=2,atempo=2,atempo=1.5",
+                    "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac",
+                ],
+            ),
+            (
+                "slow motion at a frame rate without keeping the pitch",
+                request(
+                    "out.mp4",
+                    "mp4",
+                    json!({ "video": { "fps": 60 }, "speed": { "factor": 0.5, "preserve_pitch": false, "frames": "interpolate" } }),
+                ),
+                vec![
+                    "-filter:v", "setpts=PTS/0.5,minterpolate=fps=60:mi_mode=mci",
+                    "-filter:a", "asetrate=24000,aresample=48000",
+                    "-r", "60", "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac",
+                ],
+            ),
+            (
+                "reverse",
+                request("out.mp4", "mp4", json!({ "video": { "width": 640, "height": 360 }, "playback": "reverse" })),
+                vec![
+                    "-filter:v", "scale=640:360,setsar=1,reverse",
+                    "-filter:a", "areverse",
+                    "-c:v", "libx264", "-crf", "23", "-preset", "medium", "-c:a", "aac",
+                ],
+            ),
+            (
+                "remux copies every stream",
+                request("out.mkv", "mkv", json!({ "remux": true })),
+                vec!["-map", "0:0", "-c:0", "copy", "-map", "0:1", "-c:1", "copy", "-map", "0:2", "-c:2", "copy"],
+            ),
+            (
+                "remux re-encodes only the video",
+                request("out.webm", "webm", json!({ "remux": true, "audio": { "bitrate": 96 } })),
+                vec![
+                    "-map", "0:0", "-c:0", "libvpx-vp9", "-map", "0:1", "-c:1", "libopus", "-map", "0:2",
+                    "-c:2", "libopus", "-crf", "23", "-b:v", "0", "-b:a", "96k",
+                ],
+            ),
+        ];
+
+        assert_cases(cases);
+    }
+
+    #[test]
+    fn builds_audio_commands() {
+        let cases: Vec<(&str, ConversionRequest, Vec<&str>)> = vec![
+            (
+                "mp3 preset",
+                request("out.mp3", "mp3", json!({ "quality": "high" })),
+                vec!["-map", "0:2", "-vn", "-c:a", "libmp3lame", "-b:a", "320k"],
+            ),
+            (
+                "flac with bit depth and selected track",
+                request("out.flac", "flac", json!({ "audio": { "stream_index": 1, "bit_depth": 24 } })),
+                vec![
+                    "-map", "0:1", "-vn", "-c:a", "flac",
+                    "-sample_fmt", "s32", "-bits_per_raw_sample", "24",
+                ],
+            ),
+            (
+                "wav resampled to mono",
+                request("out.wav", "wav", json!({ "audio": { "sample_rate": 44100, "channels": 1 } })),
+                vec!["-map", "0:2", "-vn", "-c:a", "pcm_s16le", "-ar", "44100", "-ac", "1"],
+            ),
+            (
+                "opus bitrate",
+                request("out.opus", "opus", json!({ "audio": { "bitrate": 96 } })),
+                vec!["-map", "0:2", "-vn", "-c:a", "libopus", "-b:a", "96k"],
+            ),
+            (
+                "aac is copied",
+                request("out.m4a", "m4a", json!({})),
+                vec!["-map", "0:2", "-vn", "-c:a", "copy"],
+            ),
+            (
+                "compress forces a transcode",
+                request("out.m4a", "m4a", json!({ "trim": { "duration": 40, "mode": "compress" } })),
+                vec!["-map", "0:2", "-filter:a", "atempo=1.5", "-vn", "-c:a", "aac", "-b:a", "160k"],
+            ),
+        ];
+
+        assert_cases(cases);
+    }
+
+    #[test]
+    fn builds_image_commands() {
+        let cases = [
+            ("out.webp", json!({}), vec!["-c:v", "libwebp", "-quality", "80"]),
+            (
+                "out.webp",
+                json!({ "quality": "low", "image": { "width": 100, "height": 50 } }),
+                vec!["-filter:v", "scale=100:50", "-c:v", "libwebp", "-quality", "60"],
+            ),
+            ("out.ico", json!({}), vec!["-filter:v", "scale=32:32", "-c:v", "png"]),
+            ("out.png", json!({ "image": { "width": 64, "height": 64 } }), vec!["-filter:v", "scale=64:64"]),
+            ("out.jpg", json!({ "image": { "width": 800 } }), vec!["-filter:v", "scale=800:-1"]),
+            (
+                "out.ico",
+                json!({ "image": { "width": 48, "height": 48, "fit": "pad", "pad_color": "0x00000000" } }),
+                vec![
+                    "-filter:v",
+                    "scale=48:48:force_original_aspect_ratio=decrease,pad=48:48:(ow-iw)/2:(oh-ih)/2:color=0x00000000",
+                    "-c:v",
+                    "png",
+                ],
+            ),
+        ];
+
+        for (output_path, options, output_args) in cases {
+            let mut request = request(output_path, output_path.rsplit('.').next().unwrap(), options);
+            request.input_path = "in.jpg".to_string();
+            let expected: Vec<&str> = ["-y", "-hide_banner", "-i", "in.jpg"]
+                .into_iter()
+                .chain(output_args)
+                .chain([output_path])
+                .collect();
+            assert_eq!(build_command(&request, None).unwrap().to_args(), expected, "{}", output_path);
+        }
+    }
+
+    #[test]
+    fn builds_both_passes_of_a_size_target() {
+        let target = SizeTarget { max_bytes: 8_000_000, video_kbps: 900, audio_kbps: 96 };
+        let request = request("out.webm", "webm", json!({ "video": { "target_size_mb": 8 } }));
+        let pass_args = |number| {
+            let pass = Pass { number, target: &target, log_prefix: "log" };
+            build_pass_command(&request, Some(&media_info()), &pass).unwrap().to_args()
+        };
+        let head = ["-y", "-hide_banner", "-progress", "pipe:1", "-nostats", "-i", "in.mkv"];
+        let video = ["-c:v", "libvpx-vp9", "-b:v", "900k", "-pass"];
+
+        let first: Vec<&str> = head
+            .into_iter()
+            .chain(["-an"])
+            .chain(video)
+            .chain(["1", "-passlogfile", "log", "-f", "null", NULL_OUTPUT])
+            .collect();
+        assert_eq!(pass_args(1), first);
+
+        let second: Vec<&str> = head
+            .into_iter()
+            .chain(video)
+            .chain(["2", "-passlogfile", "log", "-c:a", "libopus", "-b:a", "96k", "out.webm"])
+            .collect();
+        assert_eq!(pass_args(2), second);
+    }
+
+    #[test]
+    fn builds_cuts() {
+        let cases = [
+            (
+                "start and end",
+                json!({ "trim": { "start": "0:10", "end": 25.5 } }),
+                "-ss 10 -i in.mkv -t 15.5 -c:v libx264 -crf 23 -preset medium -c:a aac",
+            ),
+            (
+                "fast cut",
+                json!({ "trim": { "start": 10, "duration": 5, "cut": "fast" } }),
+                "-ss 10 -i in.mkv -t 5 -avoid_negative_ts make_zero -c:v copy -c:a copy",
+            ),
+            (
+                "accurate segments",
+                json!({ "trim": { "segments": [{ "start": 5, "end": 10 }, { "start": 20, "end": 30 }] } }),
+                "-ss 5 -t 25 -i in.mkv \
+                 -filter:v select='between(t,0,5)+between(t,15,25)',setpts=N/FRAME_RATE/TB \
+                 -filter:a aselect='between(t,0,5)+between(t,15,25)',asetpts=N/SR/TB \
+                 -c:v libx264 -crf 23 -preset medium -c:a aac",
+            ),
+        ];
+
+        for (name, options, args) in cases {
+            let command = build_command(&request("out.mp4", "mp4", options), Some(&media_info())).unwrap();
+            let expected: Vec<&str> = ["-y", "-hide_banner", "-progress", "pipe:1", "-nostats"]
+                .into_iter()
+                .chain(args.split_whitespace())
+                .chain(["out.mp4"])
+                .collect();
+            assert_eq!(command.to_args(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn transforms_before_scaling() {
+        let options = json!({ "video": {
+            "crop": { "mode": "rect", "x": 0, "y": 140, "width": 1080, "height": 1080 },
+            "rotate": 270,
+            "flip_horizontal": true,
+            "width": 720,
+            "source_rotation": "ignore",
+        } });
+        // Phone footage stored sideways: FFmpeg turns it upright, the command turns it back
+        let mut info = media_info();
+        info.streams[0].rotation = Some(90);
+        let command = build_command(&request("out.mp4", "mp4", options), Some(&info)).unwrap();
+        let expected: Vec<&str> = "-y -hide_banner -progress pipe:1 -nostats -i in.mkv \
+             -filter:v transpose=cclock,crop=1080:1080:0:140,transpose=cclock,hflip,scale=720:-2,setsar=1 \
+             -c:v libx264 -crf 23 -preset medium -c:a aac out.mp4"
+            .split_whitespace()
+            .collect();
+        assert_eq!(command.to_args(), expected);
+    }
+
+    #[test]
+    fn cuts_several_fast_segments_into_pieces() {
+        let request = request(
+            "out.mp4",
+            "mp4",
+            json!({ "trim": { "cut": "fast", "segments": [{ "start": 5, "end": 10 }, { "start": 50 }] } }),
+        );
+        let pieces = cut_pieces(&request, Some(&media_info()), &request.options.trim.ranges()).unwrap();
+
+        let durations: Vec<_> = pieces.pieces.iter().map(|piece| piece.duration).collect();
+        assert_eq!(durations, [Some(5.0), Some(10.0)]);
+        for piece in &pieces.pieces {
+            let args = piece.command.to_args();
+            assert!(args.contains(&"-ss".to_string()) && args.contains(&"copy".to_string()));
+            assert_eq!(args.last(), Some(&piece.path));
+        }
+        assert_eq!(pieces.join.to_args().last(), Some(&"out.mp4".to_string()));
+    }
+
+    #[test]
+    fn writes_boomerangs_in_reversed_chunks() {
+        let mut info = media_info();
+        info.streams[0].width = Some(1920);
+        info.streams[0].height = Some(1080);
+        info.streams[0].fps = Some(30.0);
+        let boomerang = request(
+            "out.mp4",
+            "mp4",
+            json!({ "trim": { "start": 10, "end": 20 }, "speed": { "factor": 2 }, "playback": "boomerang" }),
+        );
+        let Plan::Pieces(pieces) = reverse_plan(&boomerang, Some(&info)).unwrap() else {
+            panic!("a boomerang is written in pieces");
+        };
+
+        // Forward at double speed, then about 5.75 s chunks of 1080p, last one first
+        let durations: Vec<_> = pieces.pieces.iter().filter_map(|piece| piece.duration).collect();
+        assert_eq!(durations.len(), 3);
+        assert_eq!(durations[0], 5.0);
+        assert!(durations[1] < durations[2] && (durations[1] + durations[2] - 5.0).abs() < 1e-9);
+        let reversed: Vec<_> = pieces
+            .pieces
+            .iter()
+            .map(|piece| piece.command.to_args().iter().any(|arg| arg.ends_with("reverse")))
+            .collect();
+        assert_eq!(reversed, [false, true, true]);
+        assert_eq!(pieces.join.to_args().last(), Some(&"out.mp4".to_string()));
+
+        // Short enough to reverse in one go
+        let reverse = request("out.mp4", "mp4", json!({ "trim": { "end": 5 }, "playback": "reverse" }));
+        assert!(matches!(reverse_plan(&reverse, Some(&info)), Ok(Plan::Single(_))));
+    }
+
+    #[test]
+    fn filters_only_the_main_video_when_remuxing() {
+        let info = MediaInfo::for_test(
+            Some(60.0),
+            &[
+                json!({ "index": 0, "kind": "video", "codec": "h264", "is_default": true }),
+                json!({ "index": 1, "kind": "video", "codec": "mjpeg" }),
+            ],
+        );
+        let request = request("out.mp4", "mp4", json!({ "remux": true, "video": { "width": 640 } }));
+        let args = build_command(&request, Some(&info)).unwrap().to_args();
+
+        assert!(args.windows(2).any(|pair| pair == ["-filter:v:0", "scale=640:-2,setsar=1"]), "{:?}", args);
+        assert!(args.windows(2).any(|pair| pair == ["-c:0", "libx264"]), "{:?}", args);
+        assert!(args.windows(2).any(|pair| pair == ["-c:1", "copy"]), "{:?}", args);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_build() {
+        // Audio outputs need the probe to pick a track
+        assert!(build_command(&request("out.mp3", "mp3", json!({})), None).is_err());
+        // Compressing needs the input duration to work out the speed
+        let compress = request("out.mp4", "mp4", json!({ "trim": { "duration": 20, "mode": "compress" } }));
+        assert!(build_command(&compress, None).is_err());
+        // Automatic crops are resolved by analysing the input first
+        let auto_crop = request("out.mp4", "mp4", json!({ "video": { "crop": { "mode": "auto" } } }));
+        assert!(build_command(&auto_crop, Some(&media_info())).is_err());
+    }
+}
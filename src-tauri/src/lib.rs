@@ -1,7 +1,28 @@
+mod audio;
+mod binaries;
+mod capabilities;
+mod command;
+mod concat;
+mod crop_detect;
+mod error;
 mod ffmpeg;
+mod probe;
+mod progress;
+mod queue;
+mod remux;
+mod request;
+mod reverse;
+mod scale;
+mod smart_cut;
+mod speed;
+mod split;
+mod target_size;
+mod transform;
+mod trim;
+mod video;
 mod commands;
 
-use commands::{AppState, initialize_ffmpeg, get_file_info, start_conversion, get_conversion_status, get_supported_output_formats, cancel_conversion, convert_image_to_webp, get_file_stats, get_image_dimensions};
+use commands::{AppState, initialize_ffmpeg, set_ffmpeg_paths, get_ffmpeg_capabilities, get_file_info, start_conversion, enqueue_conversion, start_concat, enqueue_concat, start_split, enqueue_split, validate_conversion, preview_conversion_command, get_remux_plan, detect_crop, get_conversion_status, get_conversion_jobs, get_conversion_job, move_conversion_job, clear_finished_jobs, get_max_parallel_jobs, set_max_parallel_jobs, get_supported_output_formats, cancel_conversion, convert_image_to_webp, get_file_stats, get_image_dimensions};
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -15,6 +36,13 @@ pub fn run() {
             .build(),
         )?;
       }
+
+      // Detect what the FFmpeg build supports in the background, the result is cached
+      tauri::async_runtime::spawn_blocking(|| {
+        if let Err(e) = capabilities::capabilities() {
+          log::warn!("FFmpeg capability detection failed: {}", e);
+        }
+      });
       Ok(())
     })
     .plugin(tauri_plugin_os::init())
@@ -22,9 +50,26 @@ pub fn run() {
     .plugin(tauri_plugin_notification::init())
     .invoke_handler(tauri::generate_handler![
       initialize_ffmpeg,
+      set_ffmpeg_paths,
+      get_ffmpeg_capabilities,
       get_file_info,
       start_conversion,
+      enqueue_conversion,
+      start_concat,
+      enqueue_concat,
+      start_split,
+      enqueue_split,
+      validate_conversion,
+      preview_conversion_command,
+      get_remux_plan,
+      detect_crop,
       get_conversion_status,
+      get_conversion_jobs,
+      get_conversion_job,
+      move_conversion_job,
+      clear_finished_jobs,
+      get_max_parallel_jobs,
+      set_max_parallel_jobs,
       get_supported_output_formats,
       cancel_conversion,
       convert_image_to_webp,
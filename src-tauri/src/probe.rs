@@ -0,0 +1,567 @@
+use crate::binaries::ffprobe_path;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use tokio::process::Command;
+
+// Windows constant for hiding console window
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Attachment,
+    Data,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub index: u32,
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub codec_long_name: Option<String>,
+    pub profile: Option<String>,
+    // Codec level as ffprobe prints it, e.g. 41 for H.264 level 4.1
+    pub level: Option<u32>,
+    pub bitrate: Option<u64>,
+    pub duration: Option<f64>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub is_default: bool,
+    // Video
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+    pub pixel_format: Option<String>,
+    pub bit_depth: Option<u32>,
+    pub color_space: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    pub color_range: Option<String>,
+    // Clockwise rotation in degrees that players apply when displaying the stream
+    pub rotation: Option<i32>,
+    // Audio
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_format: Option<String>,
+    // Attachment
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub tags: HashMap<String, String>,
+}
+
+impl StreamInfo {
+    /// Width and height as players show the stream, swapped when it is
+    /// rotated by a quarter turn.
+    pub fn display_size(&self) -> Option<(u32, u32)> {
+        let size = self.width.zip(self.height)?;
+        match self.rotation {
+            Some(90 | 270) => Some((size.1, size.0)),
+            _ => Some(size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChapterInfo {
+    pub id: i64,
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaInfo {
+    // Summary of the first video and audio streams
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub bitrate: Option<String>,
+    pub fps: Option<f64>,
+    pub file_size: Option<u64>,
+    // Container
+    pub format_name: Option<String>,
+    pub format_long_name: Option<String>,
+    pub streams: Vec<StreamInfo>,
+    pub chapters: Vec<ChapterInfo>,
+    pub tags: HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl MediaInfo {
+    /// Probe result for tests. Each stream is JSON with at least an "index" and
+    /// a "kind"; "is_default" and "tags" may be left out.
+    pub fn for_test(duration: Option<f64>, streams: &[serde_json::Value]) -> Self {
+        let streams: Vec<_> = streams
+            .iter()
+            .map(|stream| {
+                let mut stream = stream.clone();
+                if let Some(fields) = stream.as_object_mut() {
+                    fields.entry("is_default").or_insert(false.into());
+                    fields.entry("tags").or_insert(serde_json::json!({}));
+                }
+                stream
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "duration": duration,
+            "streams": streams,
+            "chapters": [],
+            "tags": {},
+        }))
+        .unwrap()
+    }
+}
+
+// Raw `ffprobe -print_format json` output. Most numbers are printed as strings.
+#[derive(Debug, Default, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    #[serde(default)]
+    chapters: Vec<ProbeChapter>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProbeStream {
+    index: u32,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    codec_long_name: Option<String>,
+    profile: Option<String>,
+    level: Option<i64>,
+    bit_rate: Option<String>,
+    duration: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+    r_frame_rate: Option<String>,
+    pix_fmt: Option<String>,
+    bits_per_raw_sample: Option<String>,
+    color_space: Option<String>,
+    color_transfer: Option<String>,
+    color_primaries: Option<String>,
+    color_range: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    channel_layout: Option<String>,
+    sample_fmt: Option<String>,
+    #[serde(default)]
+    disposition: HashMap<String, i64>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    side_data_list: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProbeChapter {
+    id: i64,
+    start_time: Option<String>,
+    end_time: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProbeFormat {
+    format_name: Option<String>,
+    format_long_name: Option<String>,
+    duration: Option<String>,
+    size: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+pub async fn get_media_info(input_path: &str) -> Result<MediaInfo, String> {
+    let ffprobe_path = ffprobe_path().await?;
+
+    let mut cmd = Command::new(&ffprobe_path);
+    cmd.args([
+        "-v", "error",
+        "-print_format", "json",
+        "-show_format",
+        "-show_streams",
+        "-show_chapters",
+        input_path,
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+
+    // Hide CMD window on Windows
+    #[cfg(windows)]
+    cmd.creation_flags(CREATE_NO_WINDOW);
+
+    let output = cmd.output().await
+        .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe failed: {}", stderr.trim()));
+    }
+
+    let probe: ProbeOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse ffprobe output: {}", e))?;
+
+    let mut media_info = media_info_from_probe(probe);
+    if media_info.file_size.is_none() {
+        media_info.file_size = std::fs::metadata(input_path).ok().map(|m| m.len());
+    }
+    Ok(media_info)
+}
+
+/// Sorted timestamps of the keyframes of the first video stream, in seconds.
+/// Reads packet flags only, nothing is decoded.
+pub async fn get_keyframes(input_path: &str) -> Result<Vec<f64>, String> {
+    let ffprobe_path = ffprobe_path().await?;
+
+    let mut cmd = Command::new(&ffprobe_path);
+    cmd.args([
+        "-v", "error",
+        "-select_streams", "v:0",
+        "-show_entries", "packet=pts_time,flags",
+        "-of", "csv=print_section=0",
+        input_path,
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+
+    // Hide CMD window on Windows
+    #[cfg(windows)]
+    cmd.creation_flags(CREATE_NO_WINDOW);
+
+    let output = cmd.output().await
+        .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe failed: {}", stderr.trim()));
+    }
+
+    Ok(keyframes_from_packets(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Lines look like "12.345000,K__"; packets come in decode order
+fn keyframes_from_packets(csv: &str) -> Vec<f64> {
+    let mut keyframes: Vec<f64> = csv
+        .lines()
+        .filter_map(|line| line.trim().split_once(','))
+        .filter(|(_, flags)| flags.starts_with('K'))
+        .filter_map(|(pts_time, _)| pts_time.parse().ok())
+        .collect();
+    keyframes.sort_by(f64::total_cmp);
+    keyframes.dedup();
+    keyframes
+}
+
+fn media_info_from_probe(probe: ProbeOutput) -> MediaInfo {
+    let format = probe.format.unwrap_or_default();
+    let streams: Vec<StreamInfo> = probe.streams.into_iter().map(stream_info_from_probe).collect();
+    let chapters = probe
+        .chapters
+        .into_iter()
+        .map(|chapter| ChapterInfo {
+            id: chapter.id,
+            start: parse_f64(chapter.start_time.as_deref()).unwrap_or(0.0),
+            end: parse_f64(chapter.end_time.as_deref()).unwrap_or(0.0),
+            title: chapter.tags.get("title").cloned(),
+        })
+        .collect();
+
+    let video = streams.iter().find(|s| s.kind == StreamKind::Video);
+    let audio = streams.iter().find(|s| s.kind == StreamKind::Audio);
+
+    // Images and some raw streams only carry the duration on the stream
+    let duration = parse_f64(format.duration.as_deref())
+        .or_else(|| streams.iter().filter_map(|s| s.duration).reduce(f64::max));
+
+    // The summary bitrate is a bare number of kbit/s, without a unit, as before
+    let bitrate = parse_u64(format.bit_rate.as_deref())
+        .map(|bps| format!("{}", bps / 1000));
+
+    MediaInfo {
+        duration,
+        width: video.and_then(|s| s.width),
+        height: video.and_then(|s| s.height),
+        video_codec: video.and_then(|s| s.codec.clone()),
+        audio_codec: audio.and_then(|s| s.codec.clone()),
+        bitrate,
+        fps: video.and_then(|s| s.fps),
+        file_size: parse_u64(format.size.as_deref()),
+        format_name: format.format_name,
+        format_long_name: format.format_long_name,
+        chapters,
+        tags: format.tags,
+        streams,
+    }
+}
+
+fn stream_info_from_probe(stream: ProbeStream) -> StreamInfo {
+    let kind = match stream.codec_type.as_deref() {
+        Some("video") => StreamKind::Video,
+        Some("audio") => StreamKind::Audio,
+        Some("subtitle") => StreamKind::Subtitle,
+        Some("attachment") => StreamKind::Attachment,
+        Some("data") => StreamKind::Data,
+        _ => StreamKind::Unknown,
+    };
+
+    let fps = parse_rational(stream.avg_frame_rate.as_deref())
+        .or_else(|| parse_rational(stream.r_frame_rate.as_deref()));
+
+    let bit_depth = parse_u64(stream.bits_per_raw_sample.as_deref())
+        .map(|depth| depth as u32)
+        .or_else(|| stream.pix_fmt.as_deref().and_then(pixel_format_bit_depth));
+
+    StreamInfo {
+        index: stream.index,
+        kind,
+        codec: stream.codec_name,
+        codec_long_name: stream.codec_long_name,
+        profile: stream.profile,
+        // Unknown levels are printed as -99
+        level: stream.level.filter(|level| *level > 0).map(|level| level as u32),
+        bitrate: parse_u64(stream.bit_rate.as_deref()),
+        duration: parse_f64(stream.duration.as_deref()),
+        language: stream.tags.get("language").cloned(),
+        title: stream.tags.get("title").cloned(),
+        is_default: stream.disposition.get("default").copied().unwrap_or(0) != 0,
+        width: stream.width,
+        height: stream.height,
+        fps: if kind == StreamKind::Video { fps } else { None },
+        pixel_format: stream.pix_fmt,
+        bit_depth: if kind == StreamKind::Video { bit_depth } else { None },
+        color_space: stream.color_space,
+        color_transfer: stream.color_transfer,
+        color_primaries: stream.color_primaries,
+        color_range: stream.color_range,
+        rotation: stream_rotation(&stream.side_data_list, &stream.tags),
+        sample_rate: parse_u64(stream.sample_rate.as_deref()).map(|rate| rate as u32),
+        channels: stream.channels,
+        channel_layout: stream.channel_layout,
+        sample_format: stream.sample_fmt,
+        filename: stream.tags.get("filename").cloned(),
+        mime_type: stream.tags.get("mimetype").cloned(),
+        tags: stream.tags,
+    }
+}
+
+// Newer FFmpeg versions expose the display matrix as side data (counter-clockwise),
+// older ones as a `rotate` tag (clockwise). Normalized to clockwise 0..360.
+fn stream_rotation(side_data: &[serde_json::Value], tags: &HashMap<String, String>) -> Option<i32> {
+    let from_side_data = side_data
+        .iter()
+        .find_map(|entry| entry.get("rotation").and_then(|r| r.as_f64()))
+        .map(|rotation| -rotation.round() as i32);
+    let from_tags = tags.get("rotate").and_then(|r| r.parse::<i32>().ok());
+
+    from_side_data
+        .or(from_tags)
+        .map(|rotation| rotation.rem_euclid(360))
+        .filter(|rotation| *rotation != 0)
+}
+
+// The depth is the suffix of names like yuv420p10le, gbrp12be or gray16le;
+// without one the common families are 8-bit (yuv410p, nv12, rgb24)
+fn pixel_format_bit_depth(pix_fmt: &str) -> Option<u32> {
+    let name = pix_fmt
+        .strip_suffix("le")
+        .or_else(|| pix_fmt.strip_suffix("be"))
+        .unwrap_or(pix_fmt);
+    let stem = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &name[stem.len()..];
+
+    match (stem, digits) {
+        // Semi-planar p010, p216, p416: layout digit, then the depth
+        ("p", _) if digits.len() == 3 => digits[1..].parse().ok(),
+        ("nv", "20") | ("x2rgb" | "x2bgr", "10") => Some(10),
+        ("rgb" | "bgr", "48") | ("rgba" | "bgra", "64") => Some(16),
+        ("gray" | "ya", "") => Some(8),
+        ("gray" | "ya", depth) => depth.parse().ok(),
+        // Planar yuv420p10, yuva444p16, gbrap12
+        (planar, depth) if planar.ends_with('p') && !depth.is_empty() => depth.parse().ok(),
+        (family, _)
+            if ["yuv", "yuyv", "uyvy", "gbr", "nv"].iter().any(|prefix| family.starts_with(prefix))
+                || family.contains("rgb")
+                || family.contains("bgr") =>
+        {
+            Some(8)
+        }
+        _ => None,
+    }
+}
+
+fn parse_f64(value: Option<&str>) -> Option<f64> {
+    value.and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite())
+}
+
+fn parse_u64(value: Option<&str>) -> Option<u64> {
+    value.and_then(|v| v.parse::<u64>().ok())
+}
+
+// Parses frame rates like "30000/1001"; "0/0" means unknown
+fn parse_rational(value: Option<&str>) -> Option<f64> {
+    let (num, den) = value?.split_once('/')?;
+    let num: f64 = num.parse().ok()?;
+    let den: f64 = den.parse().ok()?;
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bit_depth_from_pixel_format() {
+        let cases = [
+            ("yuv420p", Some(8)),
+            ("yuv410p", Some(8)),
+            ("yuvj422p", Some(8)),
+            ("nv12", Some(8)),
+            ("rgb24", Some(8)),
+            ("bgr0", Some(8)),
+            ("yuv420p10le", Some(10)),
+            ("yuv444p12be", Some(12)),
+            ("yuva444p16le", Some(16)),
+            ("gbrp10le", Some(10)),
+            ("gray", Some(8)),
+            ("gray12le", Some(12)),
+            ("p010le", Some(10)),
+            ("p216be", Some(16)),
+            ("nv20le", Some(10)),
+            ("rgb48le", Some(16)),
+            ("pal8", None),
+        ];
+        for (pix_fmt, expected) in cases {
+            assert_eq!(pixel_format_bit_depth(pix_fmt), expected, "{}", pix_fmt);
+        }
+    }
+
+    #[test]
+    fn maps_ffprobe_output() {
+        // Trimmed `ffprobe -print_format json -show_format -show_streams -show_chapters`
+        // output of a phone recording with a cover picture
+        let json = r#"{
+            "streams": [
+                {
+                    "index": 0, "codec_name": "hevc", "codec_long_name": "H.265 / HEVC (High Efficiency Video Coding)",
+                    "profile": "Main 10", "codec_type": "video", "width": 3840, "height": 2160,
+                    "pix_fmt": "yuv420p10le", "level": 153, "color_range": "tv", "color_space": "bt2020nc",
+                    "color_transfer": "arib-std-b67", "color_primaries": "bt2020",
+                    "r_frame_rate": "30/1", "avg_frame_rate": "30000/1001", "duration": "12.345678",
+                    "bit_rate": "45000000",
+                    "disposition": { "default": 1, "attached_pic": 0 },
+                    "tags": { "language": "und", "handler_name": "Core Media Video" },
+                    "side_data_list": [
+                        { "side_data_type": "Display Matrix", "displaymatrix": "...", "rotation": -90 }
+                    ]
+                },
+                {
+                    "index": 1, "codec_name": "aac", "profile": "LC", "codec_type": "audio",
+                    "sample_fmt": "fltp", "sample_rate": "48000", "channels": 2, "channel_layout": "stereo",
+                    "bits_per_raw_sample": "0", "duration": "12.330000", "bit_rate": "192000",
+                    "disposition": { "default": 1 },
+                    "tags": { "language": "eng", "title": "Main" }
+                },
+                {
+                    "index": 2, "codec_name": "mjpeg", "codec_type": "video", "width": 600, "height": 600,
+                    "pix_fmt": "yuvj420p", "level": -99, "r_frame_rate": "90000/1", "avg_frame_rate": "0/0",
+                    "disposition": { "default": 0, "attached_pic": 1 }
+                }
+            ],
+            "chapters": [
+                { "id": 0, "start_time": "0.000000", "end_time": "6.000000", "tags": { "title": "Intro" } }
+            ],
+            "format": {
+                "format_name": "mov,mp4,m4a,3gp,3g2,mj2", "format_long_name": "QuickTime / MOV",
+                "duration": "12.345678", "size": "70123456", "bit_rate": "45441234",
+                "tags": { "major_brand": "qt  " }
+            }
+        }"#;
+        let info = media_info_from_probe(serde_json::from_str(json).unwrap());
+
+        assert_eq!(info.duration, Some(12.345678));
+        assert_eq!((info.width, info.height), (Some(3840), Some(2160)));
+        assert_eq!(info.video_codec.as_deref(), Some("hevc"));
+        assert_eq!(info.audio_codec.as_deref(), Some("aac"));
+        assert_eq!(info.bitrate.as_deref(), Some("45441"));
+        assert_eq!(info.file_size, Some(70123456));
+        assert_eq!(info.format_name.as_deref(), Some("mov,mp4,m4a,3gp,3g2,mj2"));
+        assert_eq!(info.chapters.len(), 1);
+        assert_eq!(info.chapters[0].title.as_deref(), Some("Intro"));
+        assert_eq!(info.chapters[0].end, 6.0);
+
+        let video = &info.streams[0];
+        assert_eq!(video.kind, StreamKind::Video);
+        assert!((video.fps.unwrap() - 29.97).abs() < 0.01);
+        assert_eq!(video.bit_depth, Some(10));
+        assert_eq!(video.level, Some(153));
+        assert_eq!(video.rotation, Some(90));
+        assert_eq!(video.display_size(), Some((2160, 3840)));
+        assert!(video.is_default);
+
+        let audio = &info.streams[1];
+        assert_eq!(audio.kind, StreamKind::Audio);
+        assert_eq!((audio.sample_rate, audio.channels), (Some(48000), Some(2)));
+        assert_eq!((audio.language.as_deref(), audio.title.as_deref()), (Some("eng"), Some("Main")));
+        assert_eq!((audio.fps, audio.bit_depth), (None, None));
+
+        // A 0/0 average rate falls back to the real base rate, unknown levels are dropped
+        let cover = &info.streams[2];
+        assert_eq!(cover.fps, Some(90000.0));
+        assert_eq!((cover.level, cover.bit_depth), (None, Some(8)));
+        assert!(!cover.is_default);
+    }
+
+    #[test]
+    fn reads_rotation_from_side_data_or_tags() {
+        let tags = |rotate: &str| HashMap::from([("rotate".to_string(), rotate.to_string())]);
+        let matrix = |rotation: f64| vec![serde_json::json!({ "side_data_type": "Display Matrix", "rotation": rotation })];
+
+        let cases = [
+            ("counter-clockwise matrix", matrix(-90.0), HashMap::new(), Some(90)),
+            ("clockwise matrix", matrix(90.0), HashMap::new(), Some(270)),
+            ("upside down", matrix(180.0), HashMap::new(), Some(180)),
+            ("tag", Vec::new(), tags("90"), Some(90)),
+            ("side data wins", matrix(-270.0), tags("180"), Some(270)),
+            ("no rotation", matrix(0.0), HashMap::new(), None),
+            ("nothing", Vec::new(), HashMap::new(), None),
+        ];
+        for (name, side_data, tags, expected) in cases {
+            assert_eq!(stream_rotation(&side_data, &tags), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn parses_keyframe_packets() {
+        // `-show_entries packet=pts_time,flags -of csv=print_section=0`, B-frames
+        // make packets arrive out of presentation order
+        let csv = "\
+0.000000,K__
+0.133467,___
+0.066733,___
+2.002000,K__
+N/A,K__
+4.004000,K_D
+2.002000,K__
+";
+        assert_eq!(keyframes_from_packets(csv), [0.0, 2.002, 4.004]);
+        assert!(keyframes_from_packets("").is_empty());
+    }
+}
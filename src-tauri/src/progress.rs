@@ -0,0 +1,274 @@
+use crate::queue::JobId;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversionProgress {
+    pub job_id: JobId,
+    pub frame: Option<u64>,
+    pub fps: Option<f64>,
+    pub bitrate: Option<String>,
+    // Bytes written to the output so far
+    pub total_size: Option<u64>,
+    pub out_time_us: Option<u64>,
+    // Speed as reported by FFmpeg (media seconds per wall-clock second)
+    pub speed: Option<f64>,
+    // Speed over the whole run and over the last progress block
+    pub average_speed: Option<f64>,
+    pub instant_speed: Option<f64>,
+    // Output bytes written per second since the start
+    pub throughput: Option<f64>,
+    pub elapsed_seconds: f64,
+    pub eta_seconds: Option<f64>,
+    pub progress: Option<String>,
+    // Of the whole job, across all encoder passes
+    pub percentage: Option<f64>,
+    // 1-based encoder pass and the number of passes
+    pub pass: u32,
+    pub passes: u32,
+}
+
+/// Assembles the `key=value` lines printed by `-progress pipe:1` into complete
+/// blocks. FFmpeg closes each block with a `progress=continue|end` line.
+pub struct ProgressTracker {
+    job_id: JobId,
+    total_duration: Option<f64>,
+    started: Instant,
+    block: Block,
+    last_sample: Option<(Instant, f64)>,
+    pass: u32,
+    passes: u32,
+}
+
+#[derive(Default)]
+struct Block {
+    frame: Option<u64>,
+    fps: Option<f64>,
+    bitrate: Option<String>,
+    total_size: Option<u64>,
+    out_time_us: Option<u64>,
+    speed: Option<f64>,
+}
+
+impl ProgressTracker {
+    pub fn new(job_id: JobId, total_duration: Option<f64>) -> Self {
+        Self {
+            job_id,
+            total_duration: total_duration.filter(|d| *d > 0.0),
+            started: Instant::now(),
+            block: Block::default(),
+            last_sample: None,
+            pass: 1,
+            passes: 1,
+        }
+    }
+
+    /// Tracker for one run of a multi-pass encode. Percentages and ETA then
+    /// cover all passes, assuming each takes about as long as this one.
+    pub fn for_pass(job_id: JobId, total_duration: Option<f64>, pass: u32, passes: u32) -> Self {
+        Self {
+            pass,
+            passes,
+            ..Self::new(job_id, total_duration)
+        }
+    }
+
+    /// Feeds one line of progress output. Returns an update once a block is complete.
+    pub fn push_line(&mut self, line: &str) -> Option<ConversionProgress> {
+        let (key, value) = line.trim().split_once('=')?;
+        let value = value.trim();
+
+        match key {
+            "frame" => self.block.frame = value.parse().ok(),
+            "fps" => self.block.fps = value.parse().ok(),
+            "bitrate" => self.block.bitrate = Some(value.to_string()).filter(|v| v != "N/A"),
+            "total_size" => self.block.total_size = value.parse().ok(),
+            // `out_time_ms` is in microseconds too, only use it when `out_time_us` is missing
+            "out_time_us" => self.block.out_time_us = value.parse().ok(),
+            "out_time_ms" if self.block.out_time_us.is_none() => {
+                self.block.out_time_us = value.parse().ok()
+            }
+            "speed" => self.block.speed = value.trim_end_matches('x').parse().ok(),
+            "progress" => return Some(self.finish_block(value)),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn finish_block(&mut self, state: &str) -> ConversionProgress {
+        let block = std::mem::take(&mut self.block);
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let position = block.out_time_us.map(|us| us as f64 / 1_000_000.0);
+
+        let average_speed = position
+            .filter(|_| elapsed > 0.0)
+            .map(|position| position / elapsed);
+
+        let instant_speed = match (position, self.last_sample) {
+            (Some(position), Some((last_time, last_position))) => {
+                let wall = now.duration_since(last_time).as_secs_f64();
+                (wall > 0.0).then(|| (position - last_position).max(0.0) / wall)
+            }
+            _ => None,
+        };
+        if let Some(position) = position {
+            self.last_sample = Some((now, position));
+        }
+
+        let finished = state == "end";
+        let pass_fraction = if finished {
+            Some(1.0)
+        } else {
+            match (position, self.total_duration) {
+                (Some(position), Some(total)) => Some((position / total).clamp(0.0, 1.0)),
+                _ => None,
+            }
+        };
+        let completed_passes = (self.pass - 1) as f64;
+        let percentage = pass_fraction.map(|fraction| (completed_passes + fraction) / self.passes as f64 * 100.0);
+        let remaining_passes = (self.passes - self.pass) as f64;
+
+        // Prefer the run average, it doesn't jump around like the per-block speed
+        let eta_seconds = if finished {
+            Some(0.0).filter(|_| remaining_passes == 0.0)
+        } else {
+            match (position, self.total_duration, average_speed.or(block.speed)) {
+                (Some(position), Some(total), Some(speed)) if speed > 0.0 => {
+                    Some(((total - position).max(0.0) + remaining_passes * total) / speed)
+                }
+                _ => None,
+            }
+        };
+
+        let throughput = block
+            .total_size
+            .filter(|_| elapsed > 0.0)
+            .map(|size| size as f64 / elapsed);
+
+        ConversionProgress {
+            job_id: self.job_id,
+            frame: block.frame,
+            fps: block.fps,
+            bitrate: block.bitrate,
+            total_size: block.total_size,
+            out_time_us: block.out_time_us,
+            speed: block.speed,
+            average_speed,
+            instant_speed,
+            throughput,
+            elapsed_seconds: elapsed,
+            eta_seconds,
+            // Only the last pass ends the job
+            progress: Some(if finished && remaining_passes > 0.0 { "continue" } else { state }.to_string()),
+            percentage,
+            pass: self.pass,
+            passes: self.passes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds recorded `-progress pipe:1` output, returning the completed blocks
+    fn feed(tracker: &mut ProgressTracker, output: &str) -> Vec<ConversionProgress> {
+        output.lines().filter_map(|line| tracker.push_line(line)).collect()
+    }
+
+    #[test]
+    fn assembles_progress_blocks() {
+        let mut tracker = ProgressTracker::new(7, Some(20.0));
+        let updates = feed(
+            &mut tracker,
+            "\
+frame=0
+fps=0.00
+stream_0_0_q=0.0
+bitrate=N/A
+total_size=48
+out_time_us=N/A
+out_time_ms=N/A
+out_time=N/A
+dup_frames=0
+drop_frames=0
+speed=N/A
+progress=continue
+frame=240
+fps=48.02
+stream_0_0_q=28.0
+bitrate=1843.2kbits/s
+total_size=2304000
+out_time_us=10000000
+out_time_ms=10000000
+out_time=00:00:10.000000
+dup_frames=0
+drop_frames=0
+speed=2.01x
+progress=continue
+",
+        );
+        assert_eq!(updates.len(), 2);
+
+        // Nothing is known before the first frame is written
+        let first = &updates[0];
+        assert_eq!(first.job_id, 7);
+        assert_eq!((first.frame, first.total_size), (Some(0), Some(48)));
+        assert_eq!((first.out_time_us, first.speed, first.bitrate.as_deref()), (None, None, None));
+        assert_eq!((first.percentage, first.eta_seconds), (None, None));
+        assert_eq!(first.progress.as_deref(), Some("continue"));
+
+        let second = &updates[1];
+        assert_eq!((second.frame, second.fps), (Some(240), Some(48.02)));
+        assert_eq!(second.bitrate.as_deref(), Some("1843.2kbits/s"));
+        assert_eq!((second.out_time_us, second.speed), (Some(10_000_000), Some(2.01)));
+        assert_eq!(second.percentage, Some(50.0));
+        assert!(second.eta_seconds.is_some());
+
+        let end = feed(&mut tracker, "frame=480\nout_time_us=19960000\nspeed=2.02x\nprogress=end\n");
+        assert_eq!(end.len(), 1);
+        assert_eq!(end[0].percentage, Some(100.0));
+        assert_eq!(end[0].eta_seconds, Some(0.0));
+        assert_eq!(end[0].progress.as_deref(), Some("end"));
+    }
+
+    #[test]
+    fn reads_out_time_ms_when_out_time_us_is_missing() {
+        let mut tracker = ProgressTracker::new(1, Some(10.0));
+        let updates = feed(&mut tracker, "out_time_ms=2500000\nprogress=continue\n");
+        assert_eq!(updates[0].out_time_us, Some(2_500_000));
+        assert_eq!(updates[0].percentage, Some(25.0));
+    }
+
+    #[test]
+    fn spreads_passes_over_the_job() {
+        let block = |out_time_us: u64, state: &str| format!("out_time_us={}\nspeed=4x\nprogress={}\n", out_time_us, state);
+
+        let mut first = ProgressTracker::for_pass(3, Some(40.0), 1, 2);
+        let updates = feed(&mut first, &(block(20_000_000, "continue") + &block(40_000_000, "end")));
+        assert_eq!(updates[0].percentage, Some(25.0));
+        // The first pass ending doesn't end the job
+        assert_eq!(updates[1].percentage, Some(50.0));
+        assert_eq!(updates[1].progress.as_deref(), Some("continue"));
+        assert_eq!(updates[1].eta_seconds, None);
+
+        let mut second = ProgressTracker::for_pass(3, Some(40.0), 2, 2);
+        let updates = feed(&mut second, &(block(0, "continue") + &block(10_000_000, "continue") + &block(40_000_000, "end")));
+        let percentages: Vec<_> = updates.iter().map(|update| update.percentage).collect();
+        assert_eq!(percentages, [Some(50.0), Some(62.5), Some(100.0)]);
+        assert_eq!((updates[2].pass, updates[2].passes), (2, 2));
+        assert_eq!(updates[2].progress.as_deref(), Some("end"));
+        assert_eq!(updates[2].eta_seconds, Some(0.0));
+    }
+
+    #[test]
+    fn leaves_the_percentage_open_without_a_duration() {
+        let mut tracker = ProgressTracker::new(1, Some(0.0));
+        let updates = feed(&mut tracker, "out_time_us=5000000\nprogress=continue\nprogress=end\n");
+        assert_eq!(updates[0].percentage, None);
+        assert_eq!(updates[0].eta_seconds, None);
+        assert_eq!(updates[1].percentage, Some(100.0));
+    }
+}
@@ -0,0 +1,513 @@
+use crate::concat::ConcatRequest;
+use crate::error::ConversionError;
+use crate::ffmpeg::{concat_media, convert_media, split_media};
+use crate::request::{ConversionRequest, FieldError};
+use crate::split::SplitRequest;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::oneshot;
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+// Identity and cancellation flag of a job, handed to the code running it
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    pub id: JobId,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn new(id: JobId) -> Self {
+        Self {
+            id,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// What a job runs
+#[derive(Debug, Clone)]
+pub enum JobRequest {
+    Convert(ConversionRequest),
+    Concat(ConcatRequest),
+    Split(SplitRequest),
+}
+
+impl From<ConversionRequest> for JobRequest {
+    fn from(request: ConversionRequest) -> Self {
+        JobRequest::Convert(request)
+    }
+}
+
+impl From<ConcatRequest> for JobRequest {
+    fn from(request: ConcatRequest) -> Self {
+        JobRequest::Concat(request)
+    }
+}
+
+impl From<SplitRequest> for JobRequest {
+    fn from(request: SplitRequest) -> Self {
+        JobRequest::Split(request)
+    }
+}
+
+impl JobRequest {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        match self {
+            JobRequest::Convert(request) => request.validate(),
+            JobRequest::Concat(request) => request.validate(),
+            JobRequest::Split(request) => request.validate(),
+        }
+    }
+
+    fn snapshot(&self, id: JobId) -> ConversionJob {
+        let (input_path, output_path, format) = match self {
+            JobRequest::Convert(r) => (r.input_path.clone(), &r.output_path, &r.format),
+            // Shown as its first input
+            JobRequest::Concat(r) => (r.input_paths.first().cloned().unwrap_or_default(), &r.output_path, &r.format),
+            // Parts go to a folder
+            JobRequest::Split(r) => (r.input_path.clone(), &r.output_dir, &r.format),
+        };
+        ConversionJob {
+            id,
+            input_path,
+            output_path: output_path.clone(),
+            format: format.clone(),
+            status: JobStatus::Queued,
+            error: None,
+        }
+    }
+}
+
+// Snapshot of a job as seen by the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversionJob {
+    pub id: JobId,
+    pub input_path: String,
+    pub output_path: String,
+    pub format: String,
+    pub status: JobStatus,
+    pub error: Option<ConversionError>,
+}
+
+struct JobEntry {
+    job: ConversionJob,
+    request: JobRequest,
+    handle: JobHandle,
+    done: Option<oneshot::Sender<Result<(), ConversionError>>>,
+}
+
+struct QueueInner {
+    jobs: Vec<JobEntry>,
+    next_id: JobId,
+    max_parallel: usize,
+}
+
+pub struct ConversionQueue {
+    inner: Mutex<QueueInner>,
+}
+
+impl Default for ConversionQueue {
+    fn default() -> Self {
+        Self::new(default_parallelism())
+    }
+}
+
+// Every ffmpeg encoder already uses several threads, so only a fraction of the
+// cores gets its own process.
+pub fn default_parallelism() -> usize {
+    let cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    (cpus / 4).max(1)
+}
+
+impl ConversionQueue {
+    pub fn new(max_parallel: usize) -> Self {
+        Self {
+            inner: Mutex::new(QueueInner {
+                jobs: Vec::new(),
+                next_id: 1,
+                max_parallel: max_parallel.max(1),
+            }),
+        }
+    }
+
+    /// Validates the request, adds it to the end of the queue and starts it if a
+    /// slot is free. The returned receiver resolves once the job has finished.
+    pub fn enqueue(
+        self: &Arc<Self>,
+        request: impl Into<JobRequest>,
+        app_handle: &AppHandle,
+    ) -> Result<(JobId, oneshot::Receiver<Result<(), ConversionError>>), ConversionError> {
+        let request = request.into();
+        request.validate().map_err(ConversionError::invalid_request)?;
+
+        let (job, rx) = self.inner.lock().unwrap().push(request);
+
+        log::info!("Queued conversion job {} ({})", job.id, job.input_path);
+        emit_job_update(app_handle, &job);
+        self.pump(app_handle);
+        Ok((job.id, rx))
+    }
+
+    pub fn jobs(&self) -> Vec<ConversionJob> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.iter().map(|entry| entry.job.clone()).collect()
+    }
+
+    pub fn job(&self, id: JobId) -> Option<ConversionJob> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.iter().find(|entry| entry.job.id == id).map(|entry| entry.job.clone())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.iter().any(|entry| !entry.job.status.is_finished())
+    }
+
+    pub fn max_parallel(&self) -> usize {
+        self.inner.lock().unwrap().max_parallel
+    }
+
+    pub fn set_max_parallel(self: &Arc<Self>, max_parallel: usize, app_handle: &AppHandle) {
+        self.inner.lock().unwrap().max_parallel = max_parallel.max(1);
+        // Raising the limit may free slots for waiting jobs
+        self.pump(app_handle);
+    }
+
+    /// Cancels a queued or running job. Queued jobs are finished right away,
+    /// running jobs are flagged and finish once ffmpeg has stopped.
+    pub fn cancel(&self, id: JobId, app_handle: &AppHandle) -> Result<(), String> {
+        let cancelled = self.inner.lock().unwrap().cancel(id)?;
+        if let Some(job) = cancelled {
+            emit_job_update(app_handle, &job);
+        }
+
+        log::info!("Cancellation requested for conversion job {}", id);
+        Ok(())
+    }
+
+    pub fn cancel_all(&self, app_handle: &AppHandle) {
+        let ids: Vec<JobId> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .jobs
+                .iter()
+                .filter(|entry| !entry.job.status.is_finished())
+                .map(|entry| entry.job.id)
+                .collect()
+        };
+        for id in ids {
+            let _ = self.cancel(id, app_handle);
+        }
+    }
+
+    /// Moves a queued job to `position` among the waiting jobs (0 = next to run).
+    pub fn move_job(&self, id: JobId, position: usize) -> Result<(), String> {
+        self.inner.lock().unwrap().move_job(id, position)
+    }
+
+    /// Drops completed, failed and cancelled jobs from the list.
+    pub fn clear_finished(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.jobs.retain(|entry| !entry.job.status.is_finished());
+    }
+
+    // Starts queued jobs in order until all parallel slots are taken. Updates
+    // are emitted once the lock is released.
+    fn pump(self: &Arc<Self>, app_handle: &AppHandle) {
+        let started = self.inner.lock().unwrap().start_queued();
+
+        for (job, request, handle) in started {
+            emit_job_update(app_handle, &job);
+
+            let queue = self.clone();
+            let app_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                log::info!("Starting conversion job {}", handle.id);
+                let id = handle.id;
+                // Run in its own task, so a panic fails the job instead of
+                // keeping its slot taken for good
+                let job_app_handle = app_handle.clone();
+                let task = tauri::async_runtime::spawn(async move { run_job(&request, handle, job_app_handle).await });
+                let result = task.await.unwrap_or_else(|e| Err(job_panicked(e)));
+                queue.finish(id, result, &app_handle);
+            });
+        }
+    }
+
+    fn finish(self: &Arc<Self>, id: JobId, result: Result<(), ConversionError>, app_handle: &AppHandle) {
+        let finished = self.inner.lock().unwrap().finish(id, result);
+        if let Some(job) = finished {
+            log::info!("Conversion job {} finished: {:?}", id, job.status);
+            emit_job_update(app_handle, &job);
+        }
+        self.pump(app_handle);
+    }
+}
+
+// The state changes of the queue. They return the snapshots to emit, so the
+// caller can do that after releasing the lock.
+impl QueueInner {
+    fn push(&mut self, request: JobRequest) -> (ConversionJob, oneshot::Receiver<Result<(), ConversionError>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (tx, rx) = oneshot::channel();
+        let job = request.snapshot(id);
+        self.jobs.push(JobEntry {
+            job: job.clone(),
+            request,
+            handle: JobHandle::new(id),
+            done: Some(tx),
+        });
+        (job, rx)
+    }
+
+    fn cancel(&mut self, id: JobId) -> Result<Option<ConversionJob>, String> {
+        let entry = self
+            .jobs
+            .iter_mut()
+            .find(|entry| entry.job.id == id)
+            .ok_or_else(|| format!("Conversion job {} not found", id))?;
+
+        match entry.job.status {
+            JobStatus::Queued => {
+                entry.job.status = JobStatus::Cancelled;
+                if let Some(done) = entry.done.take() {
+                    let _ = done.send(Err(ConversionError::cancelled()));
+                }
+                Ok(Some(entry.job.clone()))
+            }
+            JobStatus::Running => {
+                entry.handle.cancel();
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn move_job(&mut self, id: JobId, position: usize) -> Result<(), String> {
+        let from = self
+            .jobs
+            .iter()
+            .position(|entry| entry.job.id == id)
+            .ok_or_else(|| format!("Conversion job {} not found", id))?;
+
+        if self.jobs[from].job.status != JobStatus::Queued {
+            return Err(format!("Conversion job {} is no longer queued", id));
+        }
+
+        let entry = self.jobs.remove(from);
+        let queued: Vec<usize> = self
+            .jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.job.status == JobStatus::Queued)
+            .map(|(index, _)| index)
+            .collect();
+        let to = match queued.get(position) {
+            Some(&index) => index,
+            None => self.jobs.len(),
+        };
+        self.jobs.insert(to, entry);
+        Ok(())
+    }
+
+    // Marks queued jobs running in order until all parallel slots are taken
+    fn start_queued(&mut self) -> Vec<(ConversionJob, JobRequest, JobHandle)> {
+        let mut running = self
+            .jobs
+            .iter()
+            .filter(|entry| entry.job.status == JobStatus::Running)
+            .count();
+
+        let mut started = Vec::new();
+        for entry in self.jobs.iter_mut() {
+            if running >= self.max_parallel {
+                break;
+            }
+            if entry.job.status == JobStatus::Queued {
+                entry.job.status = JobStatus::Running;
+                started.push((entry.job.clone(), entry.request.clone(), entry.handle.clone()));
+                running += 1;
+            }
+        }
+        started
+    }
+
+    fn finish(&mut self, id: JobId, result: Result<(), ConversionError>) -> Option<ConversionJob> {
+        let entry = self.jobs.iter_mut().find(|entry| entry.job.id == id)?;
+        entry.job.status = match &result {
+            Ok(()) => JobStatus::Completed,
+            Err(_) if entry.handle.is_cancelled() => JobStatus::Cancelled,
+            Err(_) => JobStatus::Failed,
+        };
+        entry.job.error = result.as_ref().err().cloned();
+        if let Some(done) = entry.done.take() {
+            let _ = done.send(result);
+        }
+        Some(entry.job.clone())
+    }
+}
+
+async fn run_job(
+    request: &JobRequest,
+    job: JobHandle,
+    app_handle: AppHandle,
+) -> Result<(), ConversionError> {
+    match request {
+        JobRequest::Convert(request) => convert_media(request, job, app_handle).await,
+        JobRequest::Concat(request) => concat_media(request, job, app_handle).await,
+        JobRequest::Split(request) => split_media(request, job, app_handle).await,
+    }
+}
+
+fn job_panicked(error: impl std::fmt::Display) -> ConversionError {
+    log::error!("Conversion job panicked: {}", error);
+    ConversionError::from(format!("The conversion stopped unexpectedly: {}", error))
+}
+
+fn emit_job_update(app_handle: &AppHandle, job: &ConversionJob) {
+    let _ = app_handle.emit("conversion-job-updated", job);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(jobs: usize, max_parallel: usize) -> (QueueInner, Vec<oneshot::Receiver<Result<(), ConversionError>>>) {
+        let mut inner = QueueInner {
+            jobs: Vec::new(),
+            next_id: 1,
+            max_parallel,
+        };
+        let receivers = (0..jobs)
+            .map(|i| {
+                let request: ConversionRequest = serde_json::from_value(serde_json::json!({
+                    "input_path": format!("in{}.mkv", i),
+                    "output_path": format!("out{}.mp4", i),
+                    "format": "mp4",
+                }))
+                .unwrap();
+                inner.push(request.into()).1
+            })
+            .collect();
+        (inner, receivers)
+    }
+
+    fn statuses(inner: &QueueInner) -> Vec<(JobId, JobStatus)> {
+        inner.jobs.iter().map(|entry| (entry.job.id, entry.job.status)).collect()
+    }
+
+    #[test]
+    fn starts_jobs_up_to_max_parallel() {
+        use JobStatus::*;
+        let (mut inner, _receivers) = queue(4, 2);
+
+        let started: Vec<JobId> = inner.start_queued().iter().map(|(job, _, _)| job.id).collect();
+        assert_eq!(started, [1, 2]);
+        assert_eq!(statuses(&inner), [(1, Running), (2, Running), (3, Queued), (4, Queued)]);
+        // No free slot until a job finishes
+        assert!(inner.start_queued().is_empty());
+
+        let finished = inner.finish(1, Ok(())).unwrap();
+        assert_eq!(finished.status, Completed);
+        let started: Vec<JobId> = inner.start_queued().iter().map(|(job, _, _)| job.id).collect();
+        assert_eq!(started, [3]);
+
+        // Raising the limit fills the new slot
+        inner.max_parallel = 3;
+        let started: Vec<_> = inner.start_queued().into_iter().map(|(job, _, _)| (job.id, job.status)).collect();
+        assert_eq!(started, [(4, Running)]);
+    }
+
+    #[test]
+    fn cancels_queued_and_running_jobs() {
+        use JobStatus::*;
+        let (mut inner, mut receivers) = queue(2, 1);
+        inner.start_queued();
+
+        // A queued job finishes right away and its waiter hears about it
+        let cancelled = inner.cancel(2).unwrap().unwrap();
+        assert_eq!(cancelled.status, Cancelled);
+        assert!(receivers[1].try_recv().unwrap().is_err());
+
+        // A running job is only flagged, and counts as cancelled once it stops
+        assert!(inner.cancel(1).unwrap().is_none());
+        assert!(inner.jobs[0].handle.is_cancelled());
+        assert_eq!(statuses(&inner), [(1, Running), (2, Cancelled)]);
+        let finished = inner.finish(1, Err(ConversionError::cancelled())).unwrap();
+        assert_eq!(finished.status, Cancelled);
+
+        assert!(inner.cancel(9).is_err());
+        assert!(inner.start_queued().is_empty());
+    }
+
+    #[test]
+    fn frees_the_slot_of_a_failed_job() {
+        use JobStatus::*;
+        let (mut inner, mut receivers) = queue(2, 1);
+        inner.start_queued();
+
+        // A panicking job finishes as failed like any other error
+        let failed = inner.finish(1, Err(job_panicked("index out of bounds"))).unwrap();
+        assert_eq!(failed.status, Failed);
+        assert_eq!(failed.error.unwrap().message, "The conversion stopped unexpectedly: index out of bounds");
+        assert!(receivers[0].try_recv().unwrap().is_err());
+
+        let started: Vec<JobId> = inner.start_queued().iter().map(|(job, _, _)| job.id).collect();
+        assert_eq!(started, [2]);
+        assert_eq!(statuses(&inner), [(1, Failed), (2, Running)]);
+    }
+
+    #[test]
+    fn moves_queued_jobs() {
+        let (mut inner, _receivers) = queue(4, 1);
+        inner.start_queued();
+        let order = |inner: &QueueInner| inner.jobs.iter().map(|entry| entry.job.id).collect::<Vec<_>>();
+
+        // Positions count waiting jobs only, the running job 1 stays first
+        inner.move_job(4, 0).unwrap();
+        assert_eq!(order(&inner), [1, 4, 2, 3]);
+        inner.move_job(4, 10).unwrap();
+        assert_eq!(order(&inner), [1, 2, 3, 4]);
+        inner.move_job(2, 1).unwrap();
+        assert_eq!(order(&inner), [1, 3, 2, 4]);
+
+        assert!(inner.move_job(1, 0).is_err());
+        assert!(inner.move_job(9, 0).is_err());
+
+        // The moved job is the next to start
+        inner.finish(1, Ok(()));
+        let started: Vec<JobId> = inner.start_queued().iter().map(|(job, _, _)| job.id).collect();
+        assert_eq!(started, [3]);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::probe::{MediaInfo, StreamInfo, StreamKind};
+use crate::queue::JobId;
+use crate::request::ConversionRequest;
+use crate::speed;
+use crate::trim::CutMode;
+use crate::video::{container_accepts, default_audio_encoder, probed_codec_name, video_codec};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamAction {
+    Copy,
+    Transcode,
+    Drop,
+}
+
+/// What a remux does with one input stream, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamPlan {
+    pub index: u32,
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub action: StreamAction,
+    // Encoder for transcoded streams
+    pub encoder: Option<String>,
+    pub reason: String,
+}
+
+impl StreamPlan {
+    fn new(stream: &StreamInfo, action: StreamAction, encoder: Option<&str>, reason: impl Into<String>) -> Self {
+        Self {
+            index: stream.index,
+            kind: stream.kind,
+            codec: stream.codec.clone(),
+            action,
+            encoder: encoder.map(str::to_string),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Sent as "remux-plan" when a remux starts.
+#[derive(Debug, Clone, Serialize)]
+pub struct RemuxReport {
+    pub job_id: JobId,
+    pub streams: Vec<StreamPlan>,
+}
+
+/// Decides per input stream whether it can be copied into the output container,
+/// has to be transcoded, or has to be left out.
+pub fn plan(request: &ConversionRequest, media_info: &MediaInfo) -> Result<Vec<StreamPlan>, String> {
+    let format = request.format.to_lowercase();
+    let options = &request.options;
+    let codec = video_codec(&format, &options.video)?;
+    let retimed = speed::changes_speed(options);
+    let audio_dropped = options.audio.disabled.then_some("audio is disabled");
+
+    let ranges = options.trim.ranges();
+    let accurate_cut = options.trim.cut == CutMode::Accurate && !ranges.is_empty();
+
+    // Anything that changes the pictures needs the encoder
+    let video = &options.video;
+    let video_change = if video.scale.resizes(video.width, video.height) {
+        Some("scaling needs re-encoding")
+    } else if video.transform.changes_picture() {
+        Some("cropping, rotating or flipping needs re-encoding")
+    } else if options.video.fps.is_some() {
+        Some("changing the frame rate needs re-encoding")
+    } else if retimed {
+        Some("changing the speed needs re-encoding")
+    } else if options.video.rate_control.is_some() {
+        Some("a rate control mode needs re-encoding")
+    } else if accurate_cut {
+        Some("a frame-accurate cut needs re-encoding")
+    } else {
+        None
+    };
+    let audio_change = if retimed {
+        Some("changing the speed needs re-encoding")
+    } else if options.audio.bitrate.is_some() {
+        Some("a new bitrate needs re-encoding")
+    } else if ranges.len() > 1 {
+        Some("joining segments needs re-encoding")
+    } else {
+        None
+    };
+
+    let mut main_video = true;
+    let streams = media_info
+        .streams
+        .iter()
+        .map(|stream| {
+            let source = stream.codec.as_deref().unwrap_or("unknown");
+            match stream.kind {
+                StreamKind::Video if main_video => {
+                    main_video = false;
+                    let same_codec = probed_codec_name(source) == Some(codec.name);
+                    match video_change {
+                        _ if options.video.codec.is_some() && !same_codec => {
+                            StreamPlan::new(stream, StreamAction::Transcode, Some(codec.encoder()), format!("{} was requested", codec.name))
+                        }
+                        Some(reason) => StreamPlan::new(stream, StreamAction::Transcode, Some(codec.encoder()), reason),
+                        None if probed_codec_name(source).is_some_and(|name| container_accepts(&format, name)) => {
+                            StreamPlan::new(stream, StreamAction::Copy, None, format!("{} fits in .{} files", source, format))
+                        }
+                        None => StreamPlan::new(
+                            stream,
+                            StreamAction::Transcode,
+                            Some(codec.encoder()),
+                            format!("{} can't be stored in .{} files", source, format),
+                        ),
+                    }
+                }
+                // Cover art and other secondary pictures are never worth an encode
+                StreamKind::Video if container_accepts_picture(&format, source) => {
+                    StreamPlan::new(stream, StreamAction::Copy, None, "attached picture")
+                }
+                StreamKind::Video => StreamPlan::new(stream, StreamAction::Drop, None, "only the first video stream is encoded"),
+                StreamKind::Audio => {
+                    let encoder = default_audio_encoder(&format, codec);
+                    match (audio_dropped, audio_change) {
+                        (Some(reason), _) => StreamPlan::new(stream, StreamAction::Drop, None, reason),
+                        (None, Some(reason)) => StreamPlan::new(stream, StreamAction::Transcode, Some(encoder), reason),
+                        (None, None) if audio_copyable(&format, source) => {
+                            StreamPlan::new(stream, StreamAction::Copy, None, format!("{} fits in .{} files", source, format))
+                        }
+                        (None, None) => StreamPlan::new(
+                            stream,
+                            StreamAction::Transcode,
+                            Some(encoder),
+                            format!("{} can't be stored in .{} files", source, format),
+                        ),
+                    }
+                }
+                StreamKind::Subtitle => match subtitle_encoder(&format, source) {
+                    Some("copy") => {
+                        StreamPlan::new(stream, StreamAction::Copy, None, format!("{} fits in .{} files", source, format))
+                    }
+                    Some(encoder) => StreamPlan::new(
+                        stream,
+                        StreamAction::Transcode,
+                        Some(encoder),
+                        format!(".{} files store text subtitles as {}", format, encoder),
+                    ),
+                    None => StreamPlan::new(
+                        stream,
+                        StreamAction::Drop,
+                        None,
+                        format!("{} subtitles can't be stored in .{} files", source, format),
+                    ),
+                },
+                // Fonts for styled subtitles
+                StreamKind::Attachment if format == "mkv" => {
+                    StreamPlan::new(stream, StreamAction::Copy, None, "attachment")
+                }
+                _ => StreamPlan::new(
+                    stream,
+                    StreamAction::Drop,
+                    None,
+                    format!("{:?} streams aren't kept in .{} files", stream.kind, format).to_lowercase(),
+                ),
+            }
+        })
+        .collect();
+
+    Ok(streams)
+}
+
+/// Maps and codecs of a remux, with codecs set per output stream (`-c:N`).
+pub fn stream_args(plan: &[StreamPlan], format: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let kept = plan.iter().filter(|stream| stream.action != StreamAction::Drop);
+    for (output_index, stream) in kept.enumerate() {
+        let codec = stream.encoder.as_deref().unwrap_or("copy");
+        args.extend([
+            "-map".to_string(),
+            format!("0:{}", stream.index),
+            format!("-c:{}", output_index),
+            codec.to_string(),
+        ]);
+        // Copied HEVC keeps whatever tag it had, Apple players need hvc1
+        let copied_hevc = stream.action == StreamAction::Copy && stream.codec.as_deref() == Some("hevc");
+        if copied_hevc && matches!(format, "mp4" | "mov" | "m4v") {
+            args.extend([format!("-tag:{}", output_index), "hvc1".to_string()]);
+        }
+    }
+    args
+}
+
+pub fn transcodes(plan: &[StreamPlan], kind: StreamKind) -> bool {
+    plan.iter().any(|stream| stream.kind == kind && stream.action == StreamAction::Transcode)
+}
+
+fn container_accepts_picture(format: &str, codec: &str) -> bool {
+    matches!(format, "mp4" | "m4v" | "mov" | "mkv") && matches!(codec, "mjpeg" | "png")
+}
+
+pub fn audio_copyable(format: &str, codec: &str) -> bool {
+    match format {
+        "mp4" | "m4v" => matches!(codec, "aac" | "mp3" | "ac3" | "eac3" | "opus" | "alac" | "flac"),
+        "mov" => matches!(codec, "aac" | "alac" | "mp3" | "ac3" | "eac3") || codec.starts_with("pcm_"),
+        "mkv" => true,
+        "webm" => matches!(codec, "opus" | "vorbis"),
+        "avi" => matches!(codec, "mp3" | "ac3") || codec.starts_with("pcm_"),
+        "flv" => matches!(codec, "aac" | "mp3"),
+        "wmv" => matches!(codec, "wmav1" | "wmav2"),
+        _ => false,
+    }
+}
+
+// "copy", the encoder to convert text subtitles with, or `None` to drop the stream
+fn subtitle_encoder(format: &str, codec: &str) -> Option<&'static str> {
+    let text = matches!(codec, "subrip" | "ass" | "ssa" | "webvtt" | "mov_text" | "text");
+    match format {
+        "mkv" => Some("copy"),
+        "mp4" | "m4v" | "mov" if codec == "mov_text" => Some("copy"),
+        "mp4" | "m4v" | "mov" if text => Some("mov_text"),
+        "webm" if codec == "webvtt" => Some("copy"),
+        "webm" if text => Some("webvtt"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn media_info(streams: &[(&str, &str)]) -> MediaInfo {
+        let streams: Vec<_> = streams
+            .iter()
+            .enumerate()
+            .map(|(index, (kind, codec))| json!({ "index": index, "kind": kind, "codec": codec }))
+            .collect();
+        MediaInfo::for_test(None, &streams)
+    }
+
+    fn request(format: &str, options: serde_json::Value) -> ConversionRequest {
+        ConversionRequest::for_test(&format!("out.{}", format), format, options)
+    }
+
+    fn actions(format: &str, options: serde_json::Value, streams: &[(&str, &str)]) -> Vec<(StreamAction, Option<String>)> {
+        plan(&request(format, options), &media_info(streams))
+            .unwrap()
+            .into_iter()
+            .map(|stream| (stream.action, stream.encoder))
+            .collect()
+    }
+
+    const MKV: &[(&str, &str)] = &[
+        ("video", "h264"),
+        ("audio", "aac"),
+        ("audio", "dts"),
+        ("subtitle", "subrip"),
+        ("subtitle", "hdmv_pgs_subtitle"),
+        ("attachment", "ttf"),
+    ];
+
+    #[test]
+    fn copies_what_the_container_accepts() {
+        use StreamAction::*;
+        let transcode = |encoder: &str| (Transcode, Some(encoder.to_string()));
+
+        assert_eq!(
+            actions("mp4", json!({}), MKV),
+            [(Copy, None), (Copy, None), transcode("aac"), transcode("mov_text"), (Drop, None), (Drop, None)]
+        );
+        assert_eq!(
+            actions("mkv", json!({}), MKV),
+            [(Copy, None), (Copy, None), (Copy, None), (Copy, None), (Copy, None), (Copy, None)]
+        );
+        assert_eq!(
+            actions("webm", json!({}), &[("video", "h264"), ("audio", "opus")]),
+            [transcode("libvpx-vp9"), (Copy, None)]
+        );
+        assert_eq!(
+            actions("mp4", json!({ "video": { "width": 640, "height": 360 } }), &[("video", "hevc"), ("audio", "aac")]),
+            [transcode("libx264"), (Copy, None)]
+        );
+        assert_eq!(
+            actions("mkv", json!({ "trim": { "start": 10, "cut": "fast" } }), &[("video", "h264"), ("audio", "aac")]),
+            [(Copy, None), (Copy, None)]
+        );
+        assert_eq!(
+            actions("mkv", json!({ "trim": { "start": 10 } }), &[("video", "h264"), ("audio", "aac")]),
+            [transcode("libx264"), (Copy, None)]
+        );
+        assert_eq!(
+            actions("mp4", json!({ "video": { "codec": "h265" } }), &[("video", "hevc"), ("video", "mjpeg")]),
+            [(Copy, None), (Copy, None)]
+        );
+        // The cover stays copied, the builder filters the main video only
+        assert_eq!(
+            actions("mp4", json!({ "video": { "width": 640 } }), &[("video", "h264"), ("video", "mjpeg")]),
+            [transcode("libx264"), (Copy, None)]
+        );
+    }
+
+    #[test]
+    fn numbers_codecs_by_output_stream() {
+        let plan = plan(&request("mp4", json!({})), &media_info(MKV)).unwrap();
+        assert_eq!(
+            stream_args(&plan, "mp4").join(" "),
+            "-map 0:0 -c:0 copy -map 0:1 -c:1 copy -map 0:2 -c:2 aac -map 0:3 -c:3 mov_text"
+        );
+        assert!(transcodes(&plan, StreamKind::Audio));
+        assert!(!transcodes(&plan, StreamKind::Video));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::audio::{is_audio_format, AudioOptions};
+use crate::reverse::{self, Playback};
+use crate::scale::{self, ScaleOptions};
+use crate::speed::{self, SpeedOptions};
+use crate::transform::{self, TransformOptions};
+use crate::trim::{self, CutMode, TrimOptions};
+use crate::video::{validate_options, VideoOptions};
+use serde::{Deserialize, Deserializer, Serialize};
+
+const VIDEO_FORMATS: &[&str] = &["mp4", "avi", "mov", "mkv", "webm", "flv", "wmv", "m4v"];
+
+// Output formats a request may ask for; what the FFmpeg build can actually
+// write is checked later against the detected capabilities
+const KNOWN_FORMATS: &[&str] = &[
+    "mp4", "avi", "mov", "mkv", "webm", "flv", "wmv", "m4v",
+    "mp3", "wav", "flac", "aac", "ogg", "opus", "wma", "m4a",
+    "jpg", "jpeg", "png", "webp", "bmp", "gif", "tiff", "ico",
+];
+
+/// A single conversion as sent by the frontend. All option sections are optional
+/// so new fields can be added without breaking existing callers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversionRequest {
+    pub input_path: String,
+    pub output_path: String,
+    pub format: String,
+    #[serde(default)]
+    pub options: ConversionOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversionOptions {
+    // "low", "medium", "high" or an encoder specific number (CRF, image quality)
+    #[serde(deserialize_with = "string_or_number")]
+    pub quality: Option<String>,
+    pub video: VideoOptions,
+    pub audio: AudioOptions,
+    pub image: ImageOptions,
+    pub trim: TrimOptions,
+    pub speed: SpeedOptions,
+    pub playback: Playback,
+    // Copy every stream the output container can hold and only re-encode the rest
+    pub remux: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageOptions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(flatten)]
+    pub scale: ScaleOptions,
+    #[serde(flatten)]
+    pub transform: TransformOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    // Dotted path of the offending field, e.g. "options.video.width"
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+// Collects every problem instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, ok: bool, field: &str, message: impl Into<String>) {
+        if !ok {
+            self.errors.push(FieldError {
+                field: field.to_string(),
+                message: message.into(),
+            });
+        }
+    }
+
+    fn check_dimension(&mut self, value: Option<u32>, field: &str) {
+        if let Some(value) = value {
+            self.check((1..=16384).contains(&value), field, "must be between 1 and 16384");
+        }
+    }
+
+    pub fn into_errors(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+#[cfg(test)]
+impl ConversionRequest {
+    /// Conversion of "in.mkv" for tests, `options` as the frontend sends them.
+    pub fn for_test(output_path: &str, format: &str, options: serde_json::Value) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "input_path": "in.mkv",
+            "output_path": output_path,
+            "format": format,
+            "options": options,
+        }))
+        .unwrap()
+    }
+}
+
+impl ConversionRequest {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut v = Validator::default();
+        let options = &self.options;
+        let format = self.format.to_lowercase();
+
+        v.check(!self.input_path.trim().is_empty(), "input_path", "is required");
+        v.check(
+            self.input_path.trim().is_empty() || std::path::Path::new(&self.input_path).is_file(),
+            "input_path",
+            "file does not exist",
+        );
+        v.check(!self.output_path.trim().is_empty(), "output_path", "is required");
+        v.check(self.output_path != self.input_path, "output_path", "must differ from the input file");
+        v.check(
+            KNOWN_FORMATS.contains(&format.as_str()),
+            "format",
+            format!("unsupported output format \"{}\"", self.format),
+        );
+
+        if let Some(quality) = options.quality.as_deref() {
+            v.check(
+                matches!(quality, "low" | "medium" | "high") || quality.parse::<u8>().is_ok_and(|q| q <= 100),
+                "options.quality",
+                "must be low, medium, high or a number between 0 and 100",
+            );
+            // A number is a CRF or an image quality, audio has no such scale
+            v.check(
+                !is_audio_format(&format) || quality.parse::<u8>().is_err(),
+                "options.quality",
+                "audio formats take low, medium or high; set options.audio.bitrate or vbr_quality for exact control",
+            );
+        }
+
+        v.check_dimension(options.video.width, "options.video.width");
+        v.check_dimension(options.video.height, "options.video.height");
+        let video = &options.video;
+        scale::validate_options(&video.scale, video.width, video.height, "options.video", &mut v);
+        transform::validate_options(&video.transform, "options.video", &mut v);
+        if let Some(fps) = options.video.fps {
+            v.check(fps > 0.0 && fps <= 240.0, "options.video.fps", "must be between 0 and 240");
+        }
+        if let Some(size_mb) = options.video.target_size_mb {
+            v.check(size_mb.is_finite() && size_mb > 0.0, "options.video.target_size_mb", "must be greater than 0");
+            v.check(
+                is_video_format(&format),
+                "options.video.target_size_mb",
+                "is only supported for video formats",
+            );
+        }
+        if is_video_format(&format) {
+            validate_options(&format, &options.video, options.quality.as_deref(), &mut v);
+        }
+        if options.remux {
+            v.check(is_video_format(&format), "options.remux", "is only supported for video formats");
+            v.check(
+                options.video.target_size_mb.is_none(),
+                "options.remux",
+                "can't be combined with a target size",
+            );
+        }
+
+        v.check_dimension(options.image.width, "options.image.width");
+        v.check_dimension(options.image.height, "options.image.height");
+        let image = &options.image;
+        scale::validate_options(&image.scale, image.width, image.height, "options.image", &mut v);
+        transform::validate_options(&image.transform, "options.image", &mut v);
+
+        trim::validate_options(&options.trim, &mut v);
+        speed::validate_options(options, &mut v);
+        reverse::validate_options(options, &format, &mut v);
+        v.check(
+            options.trim.cut != CutMode::Smart || is_video_format(&format),
+            "options.trim.cut",
+            "smart cuts are only supported for video formats",
+        );
+        if matches!(options.trim.cut, CutMode::Fast | CutMode::Smart) {
+            let video = &options.video;
+            let re_encodes = video.scale.resizes(video.width, video.height)
+                || video.transform.changes_picture()
+                || video.fps.is_some()
+                || video.rate_control.is_some()
+                || video.target_size_mb.is_some()
+                || speed::changes_speed(options)
+                || options.playback != Playback::Forward;
+            v.check(
+                !re_encodes,
+                "options.trim.cut",
+                "fast and smart cuts copy the video, so it can't be resized, cropped, rotated, re-timed or re-encoded",
+            );
+        }
+
+        let audio = &options.audio;
+        v.check(
+            !(audio.disabled && is_audio_format(&format)),
+            "options.audio.disabled",
+            "audio can't be disabled for an audio output format",
+        );
+        if let Some(bitrate) = audio.bitrate {
+            v.check((8..=1536).contains(&bitrate), "options.audio.bitrate", "must be between 8 and 1536 kbit/s");
+        }
+        if let Some(sample_rate) = audio.sample_rate {
+            v.check(
+                (8000..=192000).contains(&sample_rate),
+                "options.audio.sample_rate",
+                "must be between 8000 and 192000 Hz",
+            );
+        }
+        if let Some(channels) = audio.channels {
+            v.check((1..=8).contains(&channels), "options.audio.channels", "must be between 1 and 8");
+        }
+        if let Some(bit_depth) = audio.bit_depth {
+            v.check(
+                matches!(bit_depth, 16 | 24 | 32),
+                "options.audio.bit_depth",
+                "must be 16, 24 or 32",
+            );
+        }
+
+        let errors = v.into_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub fn is_video_format(format: &str) -> bool {
+    VIDEO_FORMATS.contains(&format.to_lowercase().as_str())
+}
+
+pub fn format_field_errors(errors: &[FieldError]) -> String {
+    let details: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    format!("Invalid conversion request: {}", details.join("; "))
+}
+
+// The UI sends the quality either as a preset name or as a plain number
+fn string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(Option::<StringOrNumber>::deserialize(deserializer)?.map(|value| match value {
+        StringOrNumber::String(s) => s,
+        StringOrNumber::Number(n) => n.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_cross_field_rules_by_field() {
+        // The test binary is a file that exists, so the input passes
+        let input_path = std::env::current_exe().unwrap().to_string_lossy().to_string();
+        let fields = |format: &str, options: serde_json::Value| {
+            let request: ConversionRequest = serde_json::from_value(json!({
+                "input_path": input_path,
+                "output_path": format!("out.{}", format),
+                "format": format,
+                "options": options,
+            }))
+            .unwrap();
+            request.validate().err().unwrap_or_default().into_iter().map(|e| e.field).collect::<Vec<_>>()
+        };
+        let fast = json!({ "cut": "fast", "start": 5, "end": 10 });
+        let smart = json!({ "cut": "smart", "start": 5, "end": 10 });
+
+        let cases: [(&str, &str, serde_json::Value, &[&str]); 15] = [
+            ("fast cut alone", "mp4", json!({ "trim": fast }), &[]),
+            ("fast cut and resize", "mp4", json!({ "trim": fast, "video": { "width": 640 } }), &["options.trim.cut"]),
+            ("smart cut and rotation", "mkv", json!({ "trim": smart, "video": { "rotate": 90 } }), &["options.trim.cut"]),
+            ("fast cut and speed", "mp4", json!({ "trim": fast, "speed": { "factor": 2 } }), &["options.trim.cut"]),
+            ("smart cut and target size", "mp4", json!({ "trim": smart, "video": { "target_size_mb": 10 } }), &["options.trim.cut"]),
+            ("fast cut and reverse", "mp4", json!({ "trim": fast, "playback": "reverse" }), &["options.trim.cut"]),
+            ("smart cut of audio", "mp3", json!({ "trim": smart }), &["options.trim.cut"]),
+            ("remux", "mkv", json!({ "remux": true }), &[]),
+            ("remux and target size", "mkv", json!({ "remux": true, "video": { "target_size_mb": 10 } }), &["options.remux"]),
+            ("remux of audio", "mp3", json!({ "remux": true }), &["options.remux"]),
+            ("no audio in a video", "mp4", json!({ "audio": { "disabled": true } }), &[]),
+            ("no audio in audio", "flac", json!({ "audio": { "disabled": true } }), &["options.audio.disabled"]),
+            ("target size of audio", "mp3", json!({ "video": { "target_size_mb": 10 } }), &["options.video.target_size_mb"]),
+            ("preset quality of audio", "mp3", json!({ "quality": "high" }), &[]),
+            ("numeric quality of audio", "ogg", json!({ "quality": 95 }), &["options.quality"]),
+        ];
+        for (name, format, options, expected) in cases {
+            assert_eq!(fields(format, options), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::audio::is_audio_format;
+use crate::request::{is_video_format, ConversionOptions, Validator};
+use crate::trim::Segment;
+use serde::{Deserialize, Serialize};
+
+// Decoded frames `reverse` may hold at once; longer inputs are reversed in chunks
+const FRAME_MEMORY_BUDGET: f64 = 512.0 * 1024.0 * 1024.0;
+const MIN_CHUNK_SECONDS: f64 = 1.0;
+// Audio is small enough to reverse in long chunks, so is small video
+const MAX_CHUNK_SECONDS: f64 = 60.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Playback {
+    #[default]
+    Forward,
+    // Video and audio play backwards
+    Reverse,
+    // Forward, then backwards to where it started
+    Boomerang,
+}
+
+impl Playback {
+    /// How many times the output plays the kept range.
+    pub fn repeats(self) -> u32 {
+        match self {
+            Playback::Forward | Playback::Reverse => 1,
+            Playback::Boomerang => 2,
+        }
+    }
+}
+
+/// Length of the chunks that are reversed one at a time, so the frames of one
+/// chunk fit the memory budget. Frames are counted as 8-bit 4:2:0 at the
+/// input size; audio-only inputs pass no size.
+pub fn chunk_seconds(size: Option<(u32, u32)>, fps: Option<f64>) -> f64 {
+    let Some((width, height)) = size else {
+        return MAX_CHUNK_SECONDS;
+    };
+    let frame_bytes = width as f64 * height as f64 * 1.5;
+    let seconds = FRAME_MEMORY_BUDGET / frame_bytes / fps.unwrap_or(60.0);
+    seconds.clamp(MIN_CHUNK_SECONDS, MAX_CHUNK_SECONDS)
+}
+
+/// Cuts the kept ranges into chunks of at most `seconds`, last chunk first:
+/// reversing each and joining them in this order plays the ranges backwards.
+/// An open end is cut at the input duration, or kept whole when that is unknown.
+pub fn reversed_chunks(ranges: &[Segment], input_duration: Option<f64>, seconds: f64) -> Vec<Segment> {
+    let whole = [Segment { start: 0.0, end: None }];
+    let ranges = if ranges.is_empty() { &whole[..] } else { ranges };
+
+    let mut chunks = Vec::new();
+    for range in ranges {
+        let Some(end) = range.end.or(input_duration) else {
+            chunks.push(*range);
+            continue;
+        };
+        let mut start = range.start;
+        while start < end {
+            let chunk_end = (start + seconds).min(end);
+            chunks.push(Segment { start, end: Some(chunk_end) });
+            start = chunk_end;
+        }
+    }
+    chunks.reverse();
+    chunks
+}
+
+pub fn validate_options(options: &ConversionOptions, format: &str, v: &mut Validator) {
+    if options.playback == Playback::Forward {
+        return;
+    }
+    v.check(
+        is_video_format(format) || is_audio_format(format),
+        "options.playback",
+        "only applies to video and audio formats",
+    );
+    v.check(
+        options.video.target_size_mb.is_none(),
+        "options.playback",
+        "can't be combined with a target size",
+    );
+    v.check(!options.remux, "options.playback", "can't be combined with remuxing, it needs re-encoding");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverses_in_chunks() {
+        let range = |start, end| Segment { start, end };
+
+        let chunks = reversed_chunks(&[range(5.0, Some(30.0))], Some(60.0), 10.0);
+        assert_eq!(chunks, [range(25.0, Some(30.0)), range(15.0, Some(25.0)), range(5.0, Some(15.0))]);
+
+        let chunks = reversed_chunks(&[range(0.0, Some(4.0)), range(50.0, None)], Some(60.0), 8.0);
+        assert_eq!(chunks, [range(58.0, Some(60.0)), range(50.0, Some(58.0)), range(0.0, Some(4.0))]);
+
+        assert_eq!(reversed_chunks(&[], None, 8.0), [range(0.0, None)]);
+
+        // 1080p at 30 fps holds about 5.8 seconds, 8K gets the minimum
+        assert_eq!(chunk_seconds(Some((1920, 1080)), Some(30.0)).floor(), 5.0);
+        assert_eq!(chunk_seconds(Some((7680, 4320)), Some(60.0)), MIN_CHUNK_SECONDS);
+        assert_eq!(chunk_seconds(None, None), MAX_CHUNK_SECONDS);
+    }
+}
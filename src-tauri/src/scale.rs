@@ -0,0 +1,239 @@
+use crate::request::Validator;
+use serde::{Deserialize, Serialize};
+
+/// How `width` and `height` apply when both are given. A single dimension
+/// always keeps the aspect ratio.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    // Exactly width x height, whatever the source aspect ratio
+    #[default]
+    Stretch,
+    // As large as fits inside width x height, keeping the aspect ratio
+    Contain,
+    // Contain, then padded to width x height with `pad_color`
+    Pad,
+    // Fills width x height keeping the aspect ratio, the overflow is cropped
+    Cover,
+}
+
+/// Which part of the picture a cover fit or an aspect crop keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Anchor {
+    // `crop` offsets of the kept area
+    pub fn offsets(self) -> (&'static str, &'static str) {
+        const LEFT: &str = "0";
+        const CENTER_X: &str = "(iw-ow)/2";
+        const RIGHT: &str = "iw-ow";
+        const TOP: &str = "0";
+        const CENTER_Y: &str = "(ih-oh)/2";
+        const BOTTOM: &str = "ih-oh";
+
+        match self {
+            Anchor::Center => (CENTER_X, CENTER_Y),
+            Anchor::Top => (CENTER_X, TOP),
+            Anchor::Bottom => (CENTER_X, BOTTOM),
+            Anchor::Left => (LEFT, CENTER_Y),
+            Anchor::Right => (RIGHT, CENTER_Y),
+            Anchor::TopLeft => (LEFT, TOP),
+            Anchor::TopRight => (RIGHT, TOP),
+            Anchor::BottomLeft => (LEFT, BOTTOM),
+            Anchor::BottomRight => (RIGHT, BOTTOM),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScaleOptions {
+    pub fit: FitMode,
+    // FFmpeg color name or #RRGGBB[AA], black by default
+    pub pad_color: Option<String>,
+    pub anchor: Anchor,
+    // Shrinks the longer side to at most this many pixels, never enlarges
+    pub max_dimension: Option<u32>,
+}
+
+impl ScaleOptions {
+    /// Whether the scale filters change the picture at all.
+    pub fn resizes(&self, width: Option<u32>, height: Option<u32>) -> bool {
+        width.is_some() || height.is_some() || self.max_dimension.is_some()
+    }
+}
+
+/// Filters resizing to `width` and `height` as the fit mode asks. Video sizes
+/// are rounded to even numbers, which 4:2:0 chroma subsampling needs, and the
+/// pixels are made square so players show the exact frame size.
+pub fn scale_filters(width: Option<u32>, height: Option<u32>, options: &ScaleOptions, video: bool) -> Vec<String> {
+    // -2 keeps the aspect ratio and rounds to a multiple of 2
+    let auto = if video { "-2" } else { "-1" };
+    let divisible = if video { ":force_divisible_by=2" } else { "" };
+    // Odd sizes given for video are rounded down like the automatic ones
+    let even = |size: u32| if video { (size & !1).max(2) } else { size };
+    let (width, height) = (width.map(even), height.map(even));
+
+    let mut filters = Vec::new();
+    match (width, height) {
+        (Some(w), Some(h)) => match options.fit {
+            FitMode::Stretch => filters.push(format!("scale={}:{}", w, h)),
+            FitMode::Contain => filters.push(format!("scale={}:{}:force_original_aspect_ratio=decrease{}", w, h, divisible)),
+            FitMode::Pad => {
+                let color = options.pad_color.as_deref().unwrap_or("black");
+                filters.push(format!("scale={}:{}:force_original_aspect_ratio=decrease{}", w, h, divisible));
+                filters.push(format!("pad={}:{}:(ow-iw)/2:(oh-ih)/2:color={}", w, h, color));
+            }
+            FitMode::Cover => {
+                let (x, y) = options.anchor.offsets();
+                filters.push(format!("scale={}:{}:force_original_aspect_ratio=increase", w, h));
+                filters.push(format!("crop={}:{}:{}:{}", w, h, x, y));
+            }
+        },
+        (Some(w), None) => filters.push(format!("scale={}:{}", w, auto)),
+        (None, Some(h)) => filters.push(format!("scale={}:{}", auto, h)),
+        (None, None) => {}
+    }
+    if let Some(max) = options.max_dimension {
+        // The box never exceeds the input, so smaller inputs are left alone
+        filters.push(format!(
+            "scale='min({max},iw)':'min({max},ih)':force_original_aspect_ratio=decrease{divisible}"
+        ));
+    }
+    if video && !filters.is_empty() {
+        filters.push("setsar=1".to_string());
+    }
+    filters
+}
+
+/// `prefix` is the options section, e.g. "options.video".
+pub fn validate_options(options: &ScaleOptions, width: Option<u32>, height: Option<u32>, prefix: &str, v: &mut Validator) {
+    let both = width.is_some() && height.is_some();
+    v.check(
+        both || !matches!(options.fit, FitMode::Pad | FitMode::Cover),
+        &format!("{}.fit", prefix),
+        "pad and cover need both a width and a height",
+    );
+    if let Some(color) = options.pad_color.as_deref() {
+        let field = format!("{}.pad_color", prefix);
+        v.check(options.fit == FitMode::Pad, &field, "only applies to the pad fit");
+        v.check(is_color(color), &field, "must be a color name or #RRGGBB[AA]");
+    }
+    v.check(
+        options.anchor == Anchor::Center || options.fit == FitMode::Cover,
+        &format!("{}.anchor", prefix),
+        "only applies to the cover fit",
+    );
+    if let Some(max) = options.max_dimension {
+        let field = format!("{}.max_dimension", prefix);
+        v.check((1..=16384).contains(&max), &field, "must be between 1 and 16384");
+        v.check(
+            width.is_none() && height.is_none(),
+            &field,
+            "can't be combined with a width or height",
+        );
+    }
+}
+
+// Color names are letters only, so nothing can break out of the filter
+pub fn is_color(color: &str) -> bool {
+    let hex = color.strip_prefix('#').or_else(|| color.strip_prefix("0x"));
+    match hex {
+        Some(hex) => matches!(hex.len(), 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn builds_fit_filters() {
+        let options = |value: serde_json::Value| serde_json::from_value::<ScaleOptions>(value).unwrap();
+
+        let cases = [
+            ("stretch", Some(1280), Some(720), json!({}), true, "scale=1280:720,setsar=1"),
+            ("width only", Some(1280), None, json!({}), true, "scale=1280:-2,setsar=1"),
+            ("height only", None, Some(300), json!({}), false, "scale=-1:300"),
+            (
+                "contain",
+                Some(1280),
+                Some(720),
+                json!({ "fit": "contain" }),
+                true,
+                "scale=1280:720:force_original_aspect_ratio=decrease:force_divisible_by=2,setsar=1",
+            ),
+            (
+                "pad",
+                Some(1080),
+                Some(1080),
+                json!({ "fit": "pad", "pad_color": "#ffffff" }),
+                false,
+                "scale=1080:1080:force_original_aspect_ratio=decrease,pad=1080:1080:(ow-iw)/2:(oh-ih)/2:color=#ffffff",
+            ),
+            (
+                "cover",
+                Some(1080),
+                Some(1920),
+                json!({ "fit": "cover", "anchor": "top_left" }),
+                true,
+                "scale=1080:1920:force_original_aspect_ratio=increase,crop=1080:1920:0:0,setsar=1",
+            ),
+            (
+                "max dimension",
+                None,
+                None,
+                json!({ "max_dimension": 1920 }),
+                true,
+                "scale='min(1920,iw)':'min(1920,ih)':force_original_aspect_ratio=decrease:force_divisible_by=2,setsar=1",
+            ),
+            (
+                "odd video sizes",
+                Some(1081),
+                Some(721),
+                json!({ "fit": "pad" }),
+                true,
+                "scale=1080:720:force_original_aspect_ratio=decrease:force_divisible_by=2,pad=1080:720:(ow-iw)/2:(oh-ih)/2:color=black,setsar=1",
+            ),
+            ("odd stretch", Some(641), Some(1), json!({}), true, "scale=640:2,setsar=1"),
+            ("odd image sizes", Some(641), Some(481), json!({}), false, "scale=641:481"),
+            ("nothing", None, None, json!({ "fit": "contain" }), true, ""),
+        ];
+
+        for (name, width, height, value, video, expected) in cases {
+            assert_eq!(scale_filters(width, height, &options(value), video).join(","), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn validates_fit_options() {
+        let fields = |width, height, value: serde_json::Value| {
+            let mut v = Validator::default();
+            validate_options(&serde_json::from_value(value).unwrap(), width, height, "options.video", &mut v);
+            v.into_errors().into_iter().map(|e| e.field).collect::<Vec<_>>()
+        };
+
+        assert!(fields(Some(640), Some(480), json!({ "fit": "pad", "pad_color": "0x00000080" })).is_empty());
+        assert_eq!(fields(Some(640), None, json!({ "fit": "cover" })), ["options.video.fit"]);
+        assert_eq!(
+            fields(Some(640), Some(480), json!({ "fit": "pad", "pad_color": "red:x=0" })),
+            ["options.video.pad_color"]
+        );
+        assert_eq!(fields(Some(640), Some(480), json!({ "anchor": "top" })), ["options.video.anchor"]);
+        assert_eq!(fields(Some(640), None, json!({ "max_dimension": 720 })), ["options.video.max_dimension"]);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::command::FfmpegCommand;
+use crate::probe::StreamInfo;
+use crate::trim::{seconds_arg, Segment};
+use crate::video::{container_accepts, find_codec, rate_control_args, tuning_args, VideoOptions};
+
+// Cut points this close to a keyframe count as on it
+const KEYFRAME_TOLERANCE: f64 = 0.001;
+// 8-bit 4:2:0, the pixel formats of Baseline, Main and High H.264
+const MATCHED_PIXEL_FORMATS: &[&str] = &["yuv420p", "yuvj420p"];
+
+/// A part of a smart cut: either copied between two keyframes, or re-encoded
+/// from a cut point to the next keyframe (or from the last keyframe to a cut).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CutPiece {
+    pub start: f64,
+    pub end: Option<f64>,
+    pub copy: bool,
+}
+
+/// Splits each range at the first and last keyframe inside it. Only the partial
+/// GOPs before the first and after the last keyframe are re-encoded.
+pub fn plan_pieces(ranges: &[Segment], keyframes: &[f64]) -> Vec<CutPiece> {
+    let encode = |start, end| CutPiece { start, end, copy: false };
+    let copy = |start, end| CutPiece { start, end, copy: true };
+
+    let mut pieces = Vec::new();
+    for range in ranges {
+        let first = keyframes.iter().copied().find(|k| {
+            *k >= range.start - KEYFRAME_TOLERANCE && range.end.map_or(true, |end| *k < end - KEYFRAME_TOLERANCE)
+        });
+        let Some(first) = first else {
+            // No keyframe in the range, nothing can be copied
+            pieces.push(encode(range.start, range.end));
+            continue;
+        };
+
+        if first - range.start > KEYFRAME_TOLERANCE {
+            pieces.push(encode(range.start, Some(first)));
+        }
+        match range.end {
+            Some(end) => {
+                // A keyframe right at the end needs no re-encoded tail
+                let last = keyframes
+                    .iter()
+                    .copied()
+                    .rev()
+                    .find(|k| *k >= first && *k <= end + KEYFRAME_TOLERANCE)
+                    .unwrap_or(first);
+                if last - first > KEYFRAME_TOLERANCE {
+                    pieces.push(copy(first, Some(last.min(end))));
+                }
+                if end - last > KEYFRAME_TOLERANCE {
+                    pieces.push(encode(last, Some(end)));
+                }
+            }
+            None => pieces.push(copy(first, None)),
+        }
+    }
+    pieces
+}
+
+/// Encoder arguments for the re-encoded pieces. The concat demuxer joins them
+/// with copied video, so they need the source's codec, profile, level and pixel
+/// format, and carry their own parameter sets. Only 8-bit 4:2:0 H.264 can be
+/// matched like that; anything else is rejected.
+pub fn encode_args(source: &StreamInfo, format: &str) -> Result<Vec<String>, String> {
+    let source_codec = source.codec.as_deref().unwrap_or("unknown");
+    if source_codec != "h264" {
+        return Err(format!("{} video can't be smart cut, only H.264; use an accurate cut", source_codec));
+    }
+    if !container_accepts(format, "h264") {
+        return Err(format!("H.264 video can't be smart cut into .{} files, use an accurate cut", format));
+    }
+    let pixel_format = source
+        .pixel_format
+        .as_deref()
+        .filter(|pixel_format| MATCHED_PIXEL_FORMATS.contains(pixel_format))
+        .ok_or_else(|| {
+            format!(
+                "{} H.264 can't be smart cut, only 8-bit 4:2:0; use an accurate cut",
+                source.pixel_format.as_deref().unwrap_or("Unknown pixel format")
+            )
+        })?;
+    let profile = source.profile.as_deref().and_then(x264_profile).ok_or_else(|| {
+        format!(
+            "H.264 {} can't be smart cut, use an accurate cut",
+            source.profile.as_deref().unwrap_or("of an unknown profile")
+        )
+    })?;
+
+    let codec = find_codec("h264").ok_or("Unknown video codec: h264")?;
+    let mut args = vec!["-c:v".to_string(), "libx264".to_string()];
+    args.extend(rate_control_args(codec, None, Some("high")));
+    args.extend(tuning_args(codec, format, &VideoOptions::default(), Some("high")));
+    args.extend(["-profile:v", profile, "-pix_fmt", pixel_format].map(String::from));
+    // Level 9 is "1b", which libx264 can't be asked for
+    if let Some(level) = source.level.filter(|level| *level >= 10) {
+        args.extend(["-level:v".to_string(), format!("{}.{}", level / 10, level % 10)]);
+    }
+    // Parameter sets with every keyframe, so decoders don't use the copied
+    // video's for these pieces
+    args.extend(["-x264-params", "repeat-headers=1"].map(String::from));
+    Ok(args)
+}
+
+// ffprobe profile names of H.264 and their libx264 counterparts
+fn x264_profile(profile: &str) -> Option<&'static str> {
+    match profile {
+        "Constrained Baseline" | "Baseline" => Some("baseline"),
+        "Main" => Some("main"),
+        "High" => Some("high"),
+        _ => None,
+    }
+}
+
+/// Writes one piece. Audio is always copied, its packets are short enough
+/// to cut anywhere.
+pub fn piece_command(
+    input_path: &str,
+    piece: &CutPiece,
+    output_path: &str,
+    encode_args: &[String],
+    keep_audio: bool,
+) -> FfmpegCommand {
+    let mut command = FfmpegCommand::new();
+    command.with_progress();
+    let input = command.input(input_path);
+    if !piece.copy {
+        // Encode the stored pixels like the copied pieces hold them, or rotated
+        // sources would come out turned twice
+        input.option(["-noautorotate"]);
+    }
+    if piece.start > 0.0 {
+        input.option(["-ss".to_string(), seconds_arg(piece.start)]);
+    }
+
+    let output = command.output(output_path);
+    if let Some(end) = piece.end {
+        output.option(["-t".to_string(), seconds_arg(end - piece.start)]);
+    }
+    output.option(["-avoid_negative_ts", "make_zero"]);
+    if piece.copy {
+        output.option(["-c:v", "copy"]);
+    } else {
+        output.option(encode_args);
+    }
+    if keep_audio {
+        output.option(["-c:a", "copy"]);
+    } else {
+        output.option(["-an"]);
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn re_encodes_only_around_the_cuts() {
+        let keyframes = [0.0, 2.0, 4.0, 6.0, 8.0];
+        let range = |start, end| Segment { start, end };
+        let piece = |start, end, copy| CutPiece { start, end, copy };
+
+        let cases = [
+            (
+                "inside GOPs",
+                vec![range(1.0, Some(7.0))],
+                vec![piece(1.0, Some(2.0), false), piece(2.0, Some(6.0), true), piece(6.0, Some(7.0), false)],
+            ),
+            (
+                "on keyframes",
+                vec![range(2.0, Some(6.0))],
+                vec![piece(2.0, Some(6.0), true)],
+            ),
+            ("within one GOP", vec![range(4.5, Some(5.5))], vec![piece(4.5, Some(5.5), false)]),
+            (
+                "open end",
+                vec![range(7.0, None)],
+                vec![piece(7.0, Some(8.0), false), piece(8.0, None, true)],
+            ),
+            (
+                "several ranges",
+                vec![range(0.0, Some(3.0)), range(5.0, Some(6.5))],
+                vec![
+                    piece(0.0, Some(2.0), true),
+                    piece(2.0, Some(3.0), false),
+                    piece(5.0, Some(6.0), false),
+                    piece(6.0, Some(6.5), false),
+                ],
+            ),
+        ];
+
+        for (name, ranges, expected) in cases {
+            assert_eq!(plan_pieces(&ranges, &keyframes), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn matches_the_source_encoding_or_rejects_it() {
+        let source = |codec: &str, profile: &str, pixel_format: &str| -> StreamInfo {
+            serde_json::from_value(json!({
+                "index": 0, "kind": "video", "codec": codec, "profile": profile, "level": 41,
+                "pixel_format": pixel_format, "is_default": true, "tags": {},
+            }))
+            .unwrap()
+        };
+
+        let args = encode_args(&source("h264", "High", "yuv420p"), "mp4").unwrap();
+        let expected = ["-profile:v", "high", "-pix_fmt", "yuv420p", "-level:v", "4.1", "-x264-params", "repeat-headers=1"];
+        assert!(args.starts_with(&["-c:v".to_string(), "libx264".to_string()]));
+        assert!(args.ends_with(&expected.map(String::from)), "{:?}", args);
+
+        let rejected = [
+            ("hevc", source("hevc", "Main", "yuv420p"), "mp4"),
+            ("10-bit", source("h264", "High 10", "yuv420p10le"), "mp4"),
+            ("4:2:2", source("h264", "High 4:2:2", "yuv422p"), "mkv"),
+            ("container", source("h264", "Main", "yuv420p"), "webm"),
+        ];
+        for (name, source, format) in rejected {
+            assert!(encode_args(&source, format).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::request::{ConversionOptions, Validator};
+use crate::trim::DurationMode;
+use serde::{Deserialize, Serialize};
+
+// atempo sounds best between these, larger changes are chained
+const ATEMPO_MIN: f64 = 0.5;
+const ATEMPO_MAX: f64 = 2.0;
+
+/// Where slow motion gets its extra frames from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameMode {
+    // Repeats frames when slowing down, drops them when speeding up
+    #[default]
+    Duplicate,
+    // Computes in-between frames from the motion when slowing down, slow to encode
+    Interpolate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeedOptions {
+    // Playback speed, 2 plays twice as fast. The compress duration mode picks
+    // the speed that fits the input into a duration instead
+    pub factor: Option<f64>,
+    // Off, the audio changes pitch like a tape played faster or slower
+    pub preserve_pitch: bool,
+    pub frames: FrameMode,
+}
+
+impl Default for SpeedOptions {
+    fn default() -> Self {
+        Self {
+            factor: None,
+            preserve_pitch: true,
+            frames: FrameMode::default(),
+        }
+    }
+}
+
+fn fits_duration(options: &ConversionOptions) -> bool {
+    options.trim.mode == DurationMode::Compress && options.trim.duration.is_some()
+}
+
+/// Whether the output plays at another speed than the input.
+pub fn changes_speed(options: &ConversionOptions) -> bool {
+    fits_duration(options) || options.speed.factor.is_some_and(|factor| factor != 1.0)
+}
+
+/// The speed factor of the output, `None` when it plays at the input's speed.
+pub fn speed_factor(options: &ConversionOptions, input_duration: Option<f64>) -> Result<Option<f64>, String> {
+    let factor = if fits_duration(options) {
+        let original = input_duration.ok_or("The input duration is unknown, so it can't be compressed")?;
+        options.trim.duration.map(|duration| original / duration)
+    } else {
+        options.speed.factor
+    };
+    Ok(factor.filter(|factor| *factor != 1.0))
+}
+
+/// Length of the output: the trimmed length played at the output speed.
+pub fn output_duration(options: &ConversionOptions, input_duration: Option<f64>) -> Option<f64> {
+    let trimmed = options.trim.output_duration(input_duration)?;
+    match options.speed.factor {
+        // The compressed duration already is the output length
+        Some(factor) if !fits_duration(options) => Some(trimmed / factor),
+        _ => Some(trimmed),
+    }
+}
+
+/// Retimes the video, then brings it back to `fps` frames per second (the
+/// source rate when unknown) so players get a steady frame rate.
+pub fn video_filters(factor: f64, frames: FrameMode, fps: Option<f64>) -> Vec<String> {
+    let mut filters = vec![format!("setpts=PTS/{}", factor_arg(factor))];
+    if let Some(fps) = fps {
+        let fps = factor_arg(fps);
+        filters.push(match frames {
+            FrameMode::Interpolate if factor < 1.0 => format!("minterpolate=fps={}:mi_mode=mci", fps),
+            _ => format!("fps={}", fps),
+        });
+    }
+    filters
+}
+
+/// Speeds the audio up or down. `sample_rate` is the source rate, which the
+/// pitch changing variant resamples back to.
+pub fn audio_filters(factor: f64, preserve_pitch: bool, sample_rate: u32) -> Vec<String> {
+    if !preserve_pitch {
+        let rate = (sample_rate as f64 * factor).round() as u64;
+        return vec![format!("asetrate={}", rate), format!("aresample={}", sample_rate)];
+    }
+
+    let mut filters = Vec::new();
+    let mut remaining = factor;
+    while remaining > ATEMPO_MAX {
+        filters.push(format!("atempo={}", factor_arg(ATEMPO_MAX)));
+        remaining /= ATEMPO_MAX;
+    }
+    while remaining < ATEMPO_MIN {
+        filters.push(format!("atempo={}", factor_arg(ATEMPO_MIN)));
+        remaining /= ATEMPO_MIN;
+    }
+    filters.push(format!("atempo={}", factor_arg(remaining)));
+    filters
+}
+
+pub fn validate_options(options: &ConversionOptions, v: &mut Validator) {
+    if let Some(factor) = options.speed.factor {
+        v.check(
+            factor.is_finite() && (0.01..=100.0).contains(&factor),
+            "options.speed.factor",
+            "must be between 0.01 and 100",
+        );
+        v.check(
+            !fits_duration(options),
+            "options.speed.factor",
+            "can't be combined with the compress duration mode, which picks the speed itself",
+        );
+    }
+}
+
+// Rounded so chained factors don't print as 1.4999999999999998
+fn factor_arg(factor: f64) -> String {
+    let formatted = format!("{:.6}", factor);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_speed_filters() {
+        let cases = [
+            (2.0, "atempo=2"),
+            (6.0, "atempo=2,atempo=2,atempo=1.5"),
+            (0.2, "atempo=0.5,atempo=0.5,atempo=0.8"),
+            (0.75, "atempo=0.75"),
+        ];
+        for (factor, expected) in cases {
+            assert_eq!(audio_filters(factor, true, 48000).join(","), expected, "{}", factor);
+        }
+        assert_eq!(audio_filters(1.25, false, 44100).join(","), "asetrate=55125,aresample=44100");
+
+        assert_eq!(
+            video_filters(0.25, FrameMode::Interpolate, Some(29.97)).join(","),
+            "setpts=PTS/0.25,minterpolate=fps=29.97:mi_mode=mci"
+        );
+        assert_eq!(video_filters(4.0, FrameMode::Interpolate, Some(30.0)).join(","), "setpts=PTS/4,fps=30");
+        assert_eq!(video_filters(0.5, FrameMode::Duplicate, None).join(","), "setpts=PTS/0.5");
+    }
+}
@@ -0,0 +1,349 @@
+use crate::audio::is_audio_format;
+use crate::command::FfmpegCommand;
+use crate::queue::JobId;
+use crate::request::{is_video_format, ConversionOptions, ConversionRequest, FieldError, Validator};
+use crate::reverse::Playback;
+use crate::trim::{seconds_arg, CutMode, TrimOptions};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{name}_{index}";
+
+// Parts are numbered with at least this many digits so they sort by name
+const MIN_INDEX_WIDTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum SplitBy {
+    // Parts of `seconds` each, the last one gets the rest
+    Duration { seconds: f64 },
+    // Parts of at most `max_mb` megabytes
+    Size { max_mb: f64 },
+    // One part per chapter of the input
+    Chapters,
+}
+
+/// One input cut into several files in `output_dir`. Part names come from
+/// `name_template`: `{name}` is the input file name, `{index}` the part number
+/// and `{title}` the chapter title. Fast cuts copy the streams, so parts start
+/// at keyframes; accurate cuts re-encode with the other options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitRequest {
+    pub input_path: String,
+    pub output_dir: String,
+    pub format: String,
+    pub by: SplitBy,
+    #[serde(default = "default_name_template")]
+    pub name_template: String,
+    #[serde(default)]
+    pub options: ConversionOptions,
+}
+
+fn default_name_template() -> String {
+    DEFAULT_NAME_TEMPLATE.to_string()
+}
+
+/// A file written by a split and the range of the input it holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitPart {
+    pub index: u32,
+    pub path: String,
+    pub start: f64,
+    pub end: Option<f64>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitManifest {
+    pub job_id: JobId,
+    pub input_path: String,
+    pub parts: Vec<SplitPart>,
+}
+
+impl SplitRequest {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut v = Validator::default();
+        let format = self.format.to_lowercase();
+
+        v.check(Path::new(&self.output_dir).is_dir(), "output_dir", "folder does not exist");
+        match self.by {
+            SplitBy::Duration { seconds } => {
+                v.check(seconds.is_finite() && seconds > 0.0, "by.seconds", "must be greater than 0")
+            }
+            SplitBy::Size { max_mb } => v.check(max_mb.is_finite() && max_mb > 0.0, "by.max_mb", "must be greater than 0"),
+            SplitBy::Chapters => {}
+        }
+
+        let template = &self.name_template;
+        v.check(
+            template.contains("{index}"),
+            "name_template",
+            "must contain {index}, so every part gets its own name",
+        );
+        v.check(!template.contains(['/', '\\']), "name_template", "must be a file name, not a path");
+        v.check(
+            self.by == SplitBy::Chapters || !template.contains("{title}"),
+            "name_template",
+            "{title} is only known when splitting by chapter",
+        );
+
+        v.check(
+            is_video_format(&format) || is_audio_format(&format),
+            "format",
+            "must be a video or audio format",
+        );
+        let options = &self.options;
+        v.check(
+            options.trim.ranges().is_empty() && options.trim.duration.is_none(),
+            "options.trim",
+            "isn't supported when splitting, every part covers its own range",
+        );
+        v.check(
+            options.speed.factor.is_none(),
+            "options.speed.factor",
+            "changing the speed isn't supported when splitting",
+        );
+        v.check(
+            options.playback == Playback::Forward,
+            "options.playback",
+            "reversing isn't supported when splitting",
+        );
+        v.check(options.trim.cut != CutMode::Smart, "options.trim.cut", "smart cuts aren't supported when splitting");
+        v.check(
+            options.video.target_size_mb.is_none(),
+            "options.video.target_size_mb",
+            "can't be combined with splitting, split by size instead",
+        );
+
+        // The options are checked like those of a single conversion into the first part
+        let mut errors = v.into_errors();
+        if let Err(single_errors) = self.part(self.part_path("1", None), 0.0, None).validate() {
+            errors.extend(single_errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Input file name without its extension.
+    pub fn stem(&self) -> String {
+        Path::new(&self.input_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "part".to_string())
+    }
+
+    /// Path of a part, `index` already formatted.
+    pub fn part_path(&self, index: &str, title: Option<&str>) -> String {
+        let name = part_name(&self.name_template, &self.stem(), index, title);
+        Path::new(&self.output_dir)
+            .join(format!("{}.{}", name, self.format.to_lowercase()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Segment muxer pattern for the part paths, numbered by FFmpeg.
+    pub fn part_pattern(&self, count: usize) -> String {
+        let name = part_name(
+            &self.name_template,
+            &self.stem().replace('%', "%%"),
+            &format!("%0{}d", index_width(count)),
+            None,
+        );
+        Path::new(&self.output_dir)
+            .join(format!("{}.{}", name, self.format.to_lowercase()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn manifest_path(&self) -> String {
+        Path::new(&self.output_dir)
+            .join(format!("{}-manifest.json", self.stem()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// A conversion of one range of the input into `output_path`.
+    pub fn part(&self, output_path: String, start: f64, end: Option<f64>) -> ConversionRequest {
+        let mut options = self.options.clone();
+        options.trim = TrimOptions {
+            start: Some(start).filter(|start| *start > 0.0),
+            end,
+            cut: self.options.trim.cut,
+            ..TrimOptions::default()
+        };
+        ConversionRequest {
+            input_path: self.input_path.clone(),
+            output_path,
+            format: self.format.clone(),
+            options,
+        }
+    }
+}
+
+/// Fills in a name template. Values are stripped of characters that aren't
+/// allowed in file names.
+pub fn part_name(template: &str, name: &str, index: &str, title: Option<&str>) -> String {
+    let title = title.filter(|title| !title.trim().is_empty()).unwrap_or(index);
+    template
+        .replace("{name}", &file_name_safe(name))
+        .replace("{title}", &file_name_safe(title))
+        .replace("{index}", index)
+}
+
+fn file_name_safe(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+pub fn index_width(count: usize) -> usize {
+    count.to_string().len().max(MIN_INDEX_WIDTH)
+}
+
+/// Output options handing the output to the segment muxer, which starts a new
+/// file every `seconds` (at the next keyframe) and lists them in a CSV file.
+pub fn segment_args(seconds: f64, list_path: &str) -> Vec<String> {
+    [
+        "-f",
+        "segment",
+        "-segment_time",
+        &seconds_arg(seconds),
+        "-segment_start_number",
+        "1",
+        "-reset_timestamps",
+        "1",
+        "-segment_list",
+        list_path,
+        "-segment_list_type",
+        "csv",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Copies the main video and audio streams of the input, for fast splits.
+pub fn copy_command(input_path: &str, output_path: &str, keep_audio: bool) -> FfmpegCommand {
+    let mut command = FfmpegCommand::new();
+    command.with_progress();
+    command.input(input_path);
+    let output = command.output(output_path);
+    output.option(["-c:v", "copy"]);
+    if keep_audio {
+        output.option(["-c:a", "copy"]);
+    } else {
+        output.option(["-an"]);
+    }
+    // Subtitle and data codecs rarely fit the same container as-is
+    output.option(["-sn", "-dn"]);
+    command
+}
+
+/// The input range a part written from `start` holds, `written` seconds long.
+/// Stream copies begin at the last keyframe at or before `start`, so their
+/// length includes that pre-roll; re-encoded parts pass no keyframes and begin
+/// exactly at `start`.
+pub fn written_range(start: f64, written: f64, keyframes: &[f64]) -> (f64, f64) {
+    let first = keyframes.iter().rev().copied().find(|keyframe| *keyframe <= start).unwrap_or(start);
+    (first, first + written)
+}
+
+/// Reads the parts from a segment muxer CSV list (`file,start,end` lines, the
+/// file quoted when it contains a comma or quote).
+pub fn parse_segment_list(contents: &str, output_dir: &str) -> Vec<SplitPart> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().rsplitn(3, ',');
+            let end = fields.next()?.parse::<f64>().ok()?;
+            let start = fields.next()?.parse::<f64>().ok()?;
+            let file = fields.next()?;
+            let file = match file.strip_prefix('"').and_then(|file| file.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\"\"", "\""),
+                None => file.to_string(),
+            };
+            // Entries are the names given to the muxer, keep only the file name
+            let name = Path::new(&file).file_name()?.to_owned();
+            Some((Path::new(output_dir).join(name).to_string_lossy().to_string(), start, end))
+        })
+        .enumerate()
+        .map(|(i, (path, start, end))| SplitPart {
+            index: i as u32 + 1,
+            path,
+            start,
+            end: Some(end),
+            title: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn names_parts_from_the_template() {
+        let cases = [
+            ("{name}_{index}", "talk", "001", None, "talk_001"),
+            ("{index} - {title}", "talk", "002", Some("Q&A: part 1/2"), "002 - Q&A_ part 1_2"),
+            ("{index} - {title}", "talk", "003", Some("  "), "003 - 003"),
+            ("{name}-{index}", "50%", "%03d", None, "50%-%03d"),
+        ];
+
+        for (template, name, index, title, expected) in cases {
+            assert_eq!(part_name(template, name, index, title), expected, "{}", template);
+        }
+
+        let request: SplitRequest = serde_json::from_value(json!({
+            "input_path": "/videos/100% live.mkv",
+            "output_dir": "/videos/parts",
+            "format": "MP4",
+            "by": { "mode": "duration", "seconds": 600 },
+        }))
+        .unwrap();
+        assert_eq!(request.part_pattern(1200), "/videos/parts/100%% live_%04d.mp4");
+        assert_eq!(request.part_path("007", None), "/videos/parts/100% live_007.mp4");
+    }
+
+    #[test]
+    fn reads_the_segment_list() {
+        let list = "talk_001.mp4,0.000000,600.040000\n\"a,\"\"b\"\"_002.mp4\",600.040000,1200.080000\n\ntruncated";
+        let parts = parse_segment_list(list, "/out");
+        let actual: Vec<_> = parts.iter().map(|part| (part.index, part.path.as_str(), part.start, part.end)).collect();
+        assert_eq!(
+            actual,
+            [
+                (1, "/out/talk_001.mp4", 0.0, Some(600.04)),
+                (2, "/out/a,\"b\"_002.mp4", 600.04, Some(1200.08)),
+            ]
+        );
+    }
+
+    #[test]
+    fn size_parts_join_up() {
+        // Keyframes every 2 seconds of a 20 second input, each copied part
+        // holding 5 seconds from the keyframe it starts at
+        let keyframes: Vec<f64> = (0..10).map(|i| i as f64 * 2.0).collect();
+        let mut ranges = Vec::new();
+        let mut start = 0.0;
+        while start < 20.0 {
+            let first = written_range(start, 0.0, &keyframes).0;
+            let (part_start, part_end) = written_range(start, (first + 5.0).min(20.0) - first, &keyframes);
+            ranges.push((part_start, part_end));
+            start = part_end;
+        }
+        assert_eq!(ranges, [(0.0, 5.0), (4.0, 9.0), (8.0, 13.0), (12.0, 17.0), (16.0, 20.0)]);
+        // No part starts after the previous one ended, so no input is skipped
+        assert!(ranges.windows(2).all(|pair| pair[1].0 <= pair[0].1));
+
+        // Re-encoded parts start where they were asked to
+        assert_eq!(written_range(7.5, 3.0, &[]), (7.5, 10.5));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::audio::select_audio_stream;
+use crate::probe::MediaInfo;
+use crate::request::ConversionRequest;
+use crate::speed;
+
+// Share of the target left for container overhead
+const MUX_OVERHEAD: f64 = 0.03;
+// Below this the result is unwatchable, better to refuse the target
+const MIN_VIDEO_KBPS: f64 = 64.0;
+const DEFAULT_AUDIO_KBPS: u32 = 128;
+// Retries of the second pass with a lower bitrate when the output is too large
+pub const MAX_SIZE_RETRIES: u32 = 2;
+
+/// Bitrates that make an encode land just under `max_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeTarget {
+    pub max_bytes: u64,
+    pub video_kbps: u32,
+    pub audio_kbps: u32,
+}
+
+/// Works out the bitrates for `options.video.target_size_mb`, if set. Needs the
+/// probe for the input duration and the bitrate of the audio track.
+pub fn plan(request: &ConversionRequest, media_info: Option<&MediaInfo>) -> Result<Option<SizeTarget>, String> {
+    let Some(size_mb) = request.options.video.target_size_mb else {
+        return Ok(None);
+    };
+    let max_bytes = (size_mb * 1_000_000.0) as u64;
+
+    let duration = speed::output_duration(&request.options, media_info.and_then(|info| info.duration))
+        .filter(|d| *d > 0.0)
+        .ok_or("The input duration is unknown, so a target size can't be met")?;
+
+    let audio_kbps = audio_bitrate(request, media_info)?;
+    let total_kbps = max_bytes as f64 * 8.0 / 1000.0 / duration * (1.0 - MUX_OVERHEAD);
+    let video_kbps = total_kbps - audio_kbps as f64;
+
+    if video_kbps < MIN_VIDEO_KBPS {
+        let min_mb = (MIN_VIDEO_KBPS + audio_kbps as f64) * duration * 1000.0 / 8.0 / (1.0 - MUX_OVERHEAD) / 1_000_000.0;
+        return Err(format!(
+            "{} MB is too small for {:.0} seconds of video, it needs at least {:.1} MB",
+            size_mb, duration, min_mb
+        ));
+    }
+
+    Ok(Some(SizeTarget {
+        max_bytes,
+        video_kbps: video_kbps as u32,
+        audio_kbps,
+    }))
+}
+
+// Bitrate the selected audio track will be encoded at, 0 when the output has no audio
+fn audio_bitrate(request: &ConversionRequest, media_info: Option<&MediaInfo>) -> Result<u32, String> {
+    let options = &request.options;
+    if options.audio.disabled {
+        return Ok(0);
+    }
+
+    let source = match media_info.map(|info| select_audio_stream(info, options.audio.stream_index)) {
+        Some(Ok(stream)) => Some(stream),
+        // Without any audio track the video gets the whole budget
+        Some(Err(_)) if options.audio.stream_index.is_none() => return Ok(0),
+        Some(Err(e)) => return Err(e),
+        None => None,
+    };
+
+    // Re-encoding above the source bitrate only wastes the budget
+    Ok(options.audio.bitrate.unwrap_or_else(|| {
+        source
+            .and_then(|stream| stream.bitrate)
+            .map(|bps| ((bps / 1000) as u32).clamp(32, DEFAULT_AUDIO_KBPS))
+            .unwrap_or(DEFAULT_AUDIO_KBPS)
+    }))
+}
+
+/// Lowers the video bitrate after an encode came out `actual_bytes` large.
+/// Returns `None` when the bitrate would drop below a watchable minimum.
+pub fn retry(target: &SizeTarget, actual_bytes: u64) -> Option<SizeTarget> {
+    let overshoot = actual_bytes as f64 / target.max_bytes as f64;
+    // Aim a little lower than the plain ratio, encoders rarely hit a bitrate exactly
+    let video_kbps = target.video_kbps as f64 / overshoot * 0.95;
+    (video_kbps >= MIN_VIDEO_KBPS).then_some(SizeTarget {
+        video_kbps: video_kbps as u32,
+        ..*target
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(options: serde_json::Value) -> ConversionRequest {
+        ConversionRequest::for_test("out.mp4", "mp4", options)
+    }
+
+    // 100 second input, its audio tracks at the given bitrates
+    fn media_info(audio_bitrates: &[u64]) -> MediaInfo {
+        let mut streams = vec![json!({ "index": 0, "kind": "video", "is_default": true })];
+        for (i, bitrate) in audio_bitrates.iter().enumerate() {
+            streams.push(json!({ "index": i + 1, "kind": "audio", "bitrate": bitrate, "is_default": i == 0 }));
+        }
+        MediaInfo::for_test(Some(100.0), &streams)
+    }
+
+    #[test]
+    fn splits_the_budget_between_video_and_audio() {
+        // 10 MB over 100 s is 800 kbit/s, 776 after overhead
+        let cases = [
+            ("source audio", json!({ "video": { "target_size_mb": 10 } }), &[96_000][..], 680, 96),
+            ("capped audio", json!({ "video": { "target_size_mb": 10 } }), &[320_000], 648, 128),
+            ("no audio track", json!({ "video": { "target_size_mb": 10 } }), &[], 776, 0),
+            (
+                "selected track",
+                json!({ "video": { "target_size_mb": 10 }, "audio": { "stream_index": 2 } }),
+                &[96_000, 64_000],
+                712,
+                64,
+            ),
+            (
+                "audio disabled",
+                json!({ "video": { "target_size_mb": 10 }, "audio": { "disabled": true } }),
+                &[96_000],
+                776,
+                0,
+            ),
+            (
+                "trimmed",
+                json!({ "video": { "target_size_mb": 10 }, "trim": { "duration": 50 } }),
+                &[96_000],
+                1456,
+                96,
+            ),
+        ];
+
+        for (name, options, audio, video_kbps, audio_kbps) in cases {
+            let target = plan(&request(options), Some(&media_info(audio))).unwrap().unwrap();
+            assert_eq!((target.video_kbps, target.audio_kbps), (video_kbps, audio_kbps), "{}", name);
+            assert_eq!(target.max_bytes, 10_000_000, "{}", name);
+        }
+    }
+
+    #[test]
+    fn refuses_unreachable_targets() {
+        let request = request(json!({ "video": { "target_size_mb": 1 } }));
+        assert!(plan(&request, Some(&media_info(&[128_000]))).is_err());
+        assert!(plan(&request, None).is_err());
+
+        // A missing track is reported like the conversion would
+        let missing = self::request(json!({ "video": { "target_size_mb": 10 }, "audio": { "stream_index": 5 } }));
+        assert_eq!(plan(&missing, Some(&media_info(&[96_000]))), Err("Input has no audio stream with index 5".to_string()));
+    }
+
+    #[test]
+    fn lowers_the_bitrate_after_an_overshoot() {
+        let target = SizeTarget { max_bytes: 8_000_000, video_kbps: 1000, audio_kbps: 128 };
+        assert_eq!(retry(&target, 10_000_000).map(|t| t.video_kbps), Some(760));
+        assert_eq!(retry(&target, 200_000_000), None);
+    }
+}
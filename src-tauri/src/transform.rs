@@ -0,0 +1,261 @@
+use crate::request::Validator;
+use crate::scale::{is_color, Anchor};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Crop {
+    // Rectangle of the upright picture, in pixels from its top left corner
+    Rect { x: u32, y: u32, width: u32, height: u32 },
+    // Largest area with a "W:H" aspect ratio such as "9:16", placed at `anchor`
+    Aspect {
+        ratio: String,
+        #[serde(default)]
+        anchor: Anchor,
+    },
+    // The black borders found by analysing the input, if any
+    Auto,
+}
+
+/// What happens to the rotation phones store next to the pictures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceRotation {
+    // FFmpeg turns the picture upright before any other filter, the output
+    // then needs no rotation and gets none
+    #[default]
+    Auto,
+    // Filters see the stored pixels and the output is marked unrotated
+    Ignore,
+}
+
+/// Applied in order: crop, rotate, then flip, all before scaling.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransformOptions {
+    pub crop: Option<Crop>,
+    // Degrees clockwise. Multiples of 90 are lossless transposes, other angles
+    // rotate inside a canvas large enough for the whole picture
+    pub rotate: Option<f64>,
+    // Color of the corners an arbitrary angle uncovers, black by default;
+    // "none" keeps them transparent in image formats with alpha
+    pub fill_color: Option<String>,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub source_rotation: SourceRotation,
+}
+
+impl TransformOptions {
+    /// Whether any of the filters runs, which needs the video re-encoded.
+    pub fn changes_picture(&self) -> bool {
+        self.crop.is_some() || self.rotation() != 0.0 || self.flip_horizontal || self.flip_vertical
+    }
+
+    // Clockwise rotation in 0..360
+    fn rotation(&self) -> f64 {
+        self.rotate.map_or(0.0, |degrees| degrees.rem_euclid(360.0))
+    }
+}
+
+/// Parses a "W:H" aspect ratio.
+pub fn parse_ratio(ratio: &str) -> Option<(u32, u32)> {
+    let (width, height) = ratio.split_once(':')?;
+    let width: u32 = width.trim().parse().ok()?;
+    let height: u32 = height.trim().parse().ok()?;
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+/// Crop, rotate and flip filters. Video sizes are kept even like in scaling.
+pub fn transform_filters(options: &TransformOptions, video: bool) -> Vec<String> {
+    let even = |expression: String| if video { format!("trunc({}/2)*2", expression) } else { expression };
+
+    let mut filters = Vec::new();
+    match &options.crop {
+        Some(Crop::Rect { x, y, width, height }) => filters.push(format!("crop={}:{}:{}:{}", width, height, x, y)),
+        Some(Crop::Aspect { ratio, anchor }) => {
+            if let Some((w, h)) = parse_ratio(ratio) {
+                let (x, y) = anchor.offsets();
+                filters.push(format!(
+                    "crop=w='{}':h='{}':x={}:y={}",
+                    even(format!("min(iw,ih*{}/{})", w, h)),
+                    even(format!("min(ih,iw*{}/{})", h, w)),
+                    x,
+                    y
+                ));
+            }
+        }
+        // Resolved to a rectangle before the command is built
+        Some(Crop::Auto) | None => {}
+    }
+
+    match options.rotation() {
+        0.0 => {}
+        90.0 => filters.push("transpose=clock".to_string()),
+        180.0 => filters.extend(["hflip".to_string(), "vflip".to_string()]),
+        270.0 => filters.push("transpose=cclock".to_string()),
+        degrees => {
+            let angle = format!("{}*PI/180", degrees);
+            let color = options.fill_color.as_deref().unwrap_or("black");
+            filters.push(format!(
+                "rotate={}:ow={}:oh={}:fillcolor={}",
+                angle,
+                even(format!("rotw({})", angle)),
+                even(format!("roth({})", angle)),
+                color
+            ));
+        }
+    }
+
+    if options.flip_horizontal {
+        filters.push("hflip".to_string());
+    }
+    if options.flip_vertical {
+        filters.push("vflip".to_string());
+    }
+    filters
+}
+
+/// Filters that turn the picture FFmpeg set upright back into the stored
+/// pixels when the source rotation is ignored. `rotation` is the probed
+/// clockwise rotation. Undoing the automatic rotation instead of turning it off
+/// leaves no rotation in the output on any FFmpeg version: since 6.0 an input
+/// read with `-noautorotate` passes its display matrix on, and a `rotate=0`
+/// tag no longer clears it.
+pub fn source_rotation_filters(options: &TransformOptions, rotation: i32) -> Vec<String> {
+    if options.source_rotation == SourceRotation::Auto {
+        return Vec::new();
+    }
+    match rotation.rem_euclid(360) {
+        90 => vec!["transpose=cclock".to_string()],
+        180 => vec!["hflip".to_string(), "vflip".to_string()],
+        270 => vec!["transpose=clock".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+/// Input options for the source rotation. Only used when the rotation is
+/// unknown (`None`), e.g. for images that aren't probed; otherwise
+/// `source_rotation_filters` undoes it.
+pub fn input_args(options: &TransformOptions, rotation: Option<i32>) -> Vec<String> {
+    match (options.source_rotation, rotation) {
+        (SourceRotation::Ignore, None) => vec!["-noautorotate".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+/// Output options to go with `input_args`: clears the rotation tag older
+/// FFmpeg versions carry over.
+pub fn output_args(options: &TransformOptions, rotation: Option<i32>) -> Vec<String> {
+    match (options.source_rotation, rotation) {
+        (SourceRotation::Ignore, None) => vec!["-metadata:s:v:0".to_string(), "rotate=0".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+/// `prefix` is the options section, e.g. "options.video".
+pub fn validate_options(options: &TransformOptions, prefix: &str, v: &mut Validator) {
+    match &options.crop {
+        Some(Crop::Rect { width, height, .. }) => v.check(
+            (1..=16384).contains(width) && (1..=16384).contains(height),
+            &format!("{}.crop", prefix),
+            "width and height must be between 1 and 16384",
+        ),
+        Some(Crop::Aspect { ratio, .. }) => v.check(
+            parse_ratio(ratio).is_some(),
+            &format!("{}.crop.ratio", prefix),
+            "must be two positive numbers like 16:9",
+        ),
+        Some(Crop::Auto) | None => {}
+    }
+    if let Some(degrees) = options.rotate {
+        v.check(
+            degrees.is_finite() && (-360.0..=360.0).contains(&degrees),
+            &format!("{}.rotate", prefix),
+            "must be between -360 and 360 degrees",
+        );
+    }
+    if let Some(color) = options.fill_color.as_deref() {
+        let field = format!("{}.fill_color", prefix);
+        v.check(options.rotation() % 90.0 != 0.0, &field, "only applies to rotations that aren't a multiple of 90 degrees");
+        v.check(is_color(color), &field, "must be a color name or #RRGGBB[AA]");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn builds_transform_filters() {
+        let options = |value: serde_json::Value| serde_json::from_value::<TransformOptions>(value).unwrap();
+
+        let cases = [
+            ("nothing", json!({ "rotate": 360 }), true, ""),
+            (
+                "rectangle and quarter turn",
+                json!({ "crop": { "mode": "rect", "x": 10, "y": 20, "width": 640, "height": 360 }, "rotate": 90 }),
+                true,
+                "crop=640:360:10:20,transpose=clock",
+            ),
+            (
+                "aspect",
+                json!({ "crop": { "mode": "aspect", "ratio": "9:16" } }),
+                true,
+                "crop=w='trunc(min(iw,ih*9/16)/2)*2':h='trunc(min(ih,iw*16/9)/2)*2':x=(iw-ow)/2:y=(ih-oh)/2",
+            ),
+            (
+                "aspect at the top of an image",
+                json!({ "crop": { "mode": "aspect", "ratio": "1:1", "anchor": "top" } }),
+                false,
+                "crop=w='min(iw,ih*1/1)':h='min(ih,iw*1/1)':x=(iw-ow)/2:y=0",
+            ),
+            ("counter-clockwise and flips", json!({ "rotate": -90, "flip_horizontal": true, "flip_vertical": true }), true, "transpose=cclock,hflip,vflip"),
+            ("half turn", json!({ "rotate": 180 }), false, "hflip,vflip"),
+            (
+                "arbitrary angle",
+                json!({ "rotate": 12.5, "fill_color": "white" }),
+                true,
+                "rotate=12.5*PI/180:ow=trunc(rotw(12.5*PI/180)/2)*2:oh=trunc(roth(12.5*PI/180)/2)*2:fillcolor=white",
+            ),
+        ];
+
+        for (name, value, video, expected) in cases {
+            assert_eq!(transform_filters(&options(value), video).join(","), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn undoes_the_source_rotation_when_ignored() {
+        let ignore = TransformOptions {
+            source_rotation: SourceRotation::Ignore,
+            ..Default::default()
+        };
+        assert_eq!(source_rotation_filters(&ignore, 90), ["transpose=cclock"]);
+        assert_eq!(source_rotation_filters(&ignore, -90), ["transpose=clock"]);
+        assert_eq!(source_rotation_filters(&ignore, 180), ["hflip", "vflip"]);
+        assert!(source_rotation_filters(&ignore, 0).is_empty());
+        assert!(source_rotation_filters(&TransformOptions::default(), 90).is_empty());
+
+        // A probed rotation needs no options, an unknown one turns autorotation off
+        assert!(input_args(&ignore, Some(90)).is_empty());
+        assert!(output_args(&ignore, Some(0)).is_empty());
+        assert_eq!(input_args(&ignore, None), ["-noautorotate"]);
+        assert_eq!(output_args(&ignore, None), ["-metadata:s:v:0", "rotate=0"]);
+        assert!(input_args(&TransformOptions::default(), None).is_empty());
+    }
+
+    #[test]
+    fn validates_transform_options() {
+        let fields = |value: serde_json::Value| {
+            let mut v = Validator::default();
+            validate_options(&serde_json::from_value(value).unwrap(), "options.image", &mut v);
+            v.into_errors().into_iter().map(|e| e.field).collect::<Vec<_>>()
+        };
+
+        assert!(fields(json!({ "rotate": 45, "fill_color": "none" })).is_empty());
+        assert_eq!(fields(json!({ "crop": { "mode": "aspect", "ratio": "wide" } })), ["options.image.crop.ratio"]);
+        assert_eq!(fields(json!({ "rotate": 90, "fill_color": "red" })), ["options.image.fill_color"]);
+        assert_eq!(fields(json!({ "rotate": 720 })), ["options.image.rotate"]);
+    }
+}
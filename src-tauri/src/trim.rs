@@ -0,0 +1,266 @@
+use crate::request::Validator;
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationMode {
+    // Cut the output after `duration` seconds
+    #[default]
+    Trim,
+    // Speed the whole input up or down to last `duration` seconds
+    Compress,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CutMode {
+    // Re-encodes, so cuts land on the exact frame
+    #[default]
+    Accurate,
+    // Copies the streams; cuts snap to the keyframe before each start
+    Fast,
+    // Frame accurate, but only re-encodes from each cut to the nearest keyframe
+    Smart,
+}
+
+/// A range of the input to keep, in seconds. No end keeps the rest of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    #[serde(deserialize_with = "timestamp")]
+    pub start: f64,
+    #[serde(default, deserialize_with = "optional_timestamp")]
+    pub end: Option<f64>,
+}
+
+/// Timestamps accept seconds or `[HH:]MM:SS[.mmm]` strings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrimOptions {
+    pub duration: Option<f64>,
+    pub mode: DurationMode,
+    #[serde(deserialize_with = "optional_timestamp")]
+    pub start: Option<f64>,
+    #[serde(deserialize_with = "optional_timestamp")]
+    pub end: Option<f64>,
+    // Ranges cut out and joined in order; replaces start, end and duration
+    pub segments: Vec<Segment>,
+    pub cut: CutMode,
+}
+
+impl TrimOptions {
+    /// The ranges of the input that end up in the output, empty when the whole
+    /// input is kept. A compressed duration changes the speed, not the range.
+    pub fn ranges(&self) -> Vec<Segment> {
+        if !self.segments.is_empty() {
+            return self.segments.clone();
+        }
+        let start = self.start.unwrap_or(0.0);
+        let end = match self.mode {
+            DurationMode::Trim => self.end.or(self.duration.map(|duration| start + duration)),
+            DurationMode::Compress => self.end,
+        };
+        if start > 0.0 || end.is_some() {
+            vec![Segment { start, end }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Length of the output given the length of the input.
+    pub fn output_duration(&self, input_duration: Option<f64>) -> Option<f64> {
+        if let (DurationMode::Compress, Some(duration)) = (self.mode, self.duration) {
+            return Some(duration);
+        }
+        let ranges = self.ranges();
+        if ranges.is_empty() {
+            return input_duration;
+        }
+        ranges.iter().try_fold(0.0, |total, range| {
+            let end = range.end.or(input_duration)?;
+            Some(total + (end - range.start).max(0.0))
+        })
+    }
+}
+
+pub fn validate_options(options: &TrimOptions, v: &mut Validator) {
+    if let Some(duration) = options.duration {
+        v.check(duration.is_finite() && duration > 0.0, "options.trim.duration", "must be greater than 0");
+    }
+    if let Some(start) = options.start {
+        v.check(start >= 0.0, "options.trim.start", "must not be negative");
+    }
+    if let (Some(start), Some(end)) = (options.start, options.end) {
+        v.check(end > start, "options.trim.end", "must be after the start");
+    }
+    v.check(
+        options.end.is_none() || options.duration.is_none(),
+        "options.trim.end",
+        "can't be combined with a duration",
+    );
+
+    let ranged = options.start.is_some() || options.end.is_some() || !options.segments.is_empty();
+    v.check(
+        !(ranged && options.mode == DurationMode::Compress),
+        "options.trim.mode",
+        "compress applies to the whole input and can't be combined with start, end or segments",
+    );
+
+    if options.segments.is_empty() {
+        return;
+    }
+    v.check(
+        options.start.is_none() && options.end.is_none() && options.duration.is_none(),
+        "options.trim.segments",
+        "can't be combined with start, end or duration",
+    );
+    for (i, segment) in options.segments.iter().enumerate() {
+        let field = format!("options.trim.segments[{}]", i);
+        v.check(segment.start >= 0.0, &field, "start must not be negative");
+        match segment.end {
+            Some(end) => v.check(end > segment.start, &field, "end must be after the start"),
+            None => v.check(i + 1 == options.segments.len(), &field, "only the last segment can be open-ended"),
+        }
+        if let Some(next) = options.segments.get(i + 1) {
+            v.check(
+                segment.end.is_some_and(|end| end <= next.start),
+                &field,
+                "segments must be in order and must not overlap",
+            );
+        }
+    }
+}
+
+/// Formats seconds for FFmpeg, rounded to the millisecond.
+pub fn seconds_arg(seconds: f64) -> String {
+    let formatted = format!("{:.3}", seconds);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// `select` expression keeping the given ranges, relative to `offset`.
+pub fn select_expression(ranges: &[Segment], offset: f64) -> String {
+    ranges
+        .iter()
+        .map(|range| match range.end {
+            Some(end) => format!("between(t,{},{})", seconds_arg(range.start - offset), seconds_arg(end - offset)),
+            None => format!("gte(t,{})", seconds_arg(range.start - offset)),
+        })
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// Parses seconds (`90.5`) or a clock time (`1:30.5`, `00:01:30.500`).
+pub fn parse_timestamp(value: &str) -> Result<f64, String> {
+    let invalid = || format!("Invalid timestamp \"{}\", expected seconds or HH:MM:SS.mmm", value);
+    let parts: Vec<&str> = value.trim().split(':').collect();
+    if parts.len() > 3 || parts.iter().any(|part| part.is_empty()) {
+        return Err(invalid());
+    }
+
+    let (seconds, whole) = parts.split_last().ok_or_else(invalid)?;
+    let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+    if !seconds.is_finite() || seconds < 0.0 || (!whole.is_empty() && seconds >= 60.0) {
+        return Err(invalid());
+    }
+
+    // Hours are unbounded, minutes after hours are not
+    let mut total = 0.0;
+    for (i, part) in whole.iter().enumerate() {
+        let unit: u32 = part.parse().map_err(|_| invalid())?;
+        if i + 1 == whole.len() && whole.len() == 2 && unit >= 60 {
+            return Err(invalid());
+        }
+        total = total * 60.0 + unit as f64;
+    }
+    Ok(total * 60.0 + seconds)
+}
+
+fn timestamp<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SecondsOrClock {
+        Seconds(f64),
+        Clock(String),
+    }
+
+    match SecondsOrClock::deserialize(deserializer)? {
+        SecondsOrClock::Seconds(seconds) => Ok(seconds),
+        SecondsOrClock::Clock(clock) => parse_timestamp(&clock).map_err(serde::de::Error::custom),
+    }
+}
+
+fn optional_timestamp<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "timestamp")] f64);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(seconds)| seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_timestamps() {
+        let cases = [
+            ("90", Some(90.0)),
+            ("90.25", Some(90.25)),
+            ("1:30.5", Some(90.5)),
+            ("00:01:30.500", Some(90.5)),
+            ("2:00:00", Some(7200.0)),
+            ("1:60", None),
+            ("1:75:00", None),
+            ("1::30", None),
+            ("-5", None),
+            ("soon", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_timestamp(value).ok(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn works_out_ranges_and_output_duration() {
+        let trim = |value: serde_json::Value| serde_json::from_value::<TrimOptions>(value).unwrap();
+
+        let cases = [
+            (json!({}), vec![], Some(60.0)),
+            (json!({ "duration": 10 }), vec![(0.0, Some(10.0))], Some(10.0)),
+            (json!({ "start": "0:05", "duration": 10 }), vec![(5.0, Some(15.0))], Some(10.0)),
+            (json!({ "start": 50 }), vec![(50.0, None)], Some(10.0)),
+            (json!({ "duration": 30, "mode": "compress" }), vec![], Some(30.0)),
+            (
+                json!({ "segments": [{ "start": 1, "end": "0:03" }, { "start": 40 }] }),
+                vec![(1.0, Some(3.0)), (40.0, None)],
+                Some(22.0),
+            ),
+        ];
+
+        for (options, ranges, duration) in cases {
+            let options = trim(options);
+            let actual: Vec<_> = options.ranges().iter().map(|r| (r.start, r.end)).collect();
+            assert_eq!(actual, ranges, "{:?}", options);
+            assert_eq!(options.output_duration(Some(60.0)), duration, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn selects_ranges_relative_to_the_seek() {
+        let ranges = [
+            Segment { start: 10.0, end: Some(12.5) },
+            Segment { start: 20.1, end: Some(25.3) },
+            Segment { start: 30.0, end: None },
+        ];
+        assert_eq!(
+            select_expression(&ranges, 10.0),
+            "between(t,0,2.5)+between(t,10.1,15.3)+gte(t,20)"
+        );
+    }
+}
@@ -0,0 +1,607 @@
+use crate::capabilities;
+use crate::request::Validator;
+use crate::scale::ScaleOptions;
+use crate::transform::TransformOptions;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VideoOptions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // Fit mode, padding, anchor and maximum size, next to width and height
+    #[serde(flatten)]
+    pub scale: ScaleOptions,
+    // Crop, rotation and flips, applied before scaling
+    #[serde(flatten)]
+    pub transform: TransformOptions,
+    pub fps: Option<f64>,
+    // "h264", "h265", "av1", "vp9", "mpeg4", "prores" or "ffv1";
+    // defaults to the usual codec of the output container
+    pub codec: Option<String>,
+    // Two-pass encode that keeps the output file under this many megabytes (10^6 bytes)
+    pub target_size_mb: Option<f64>,
+    // Overrides the CRF derived from `quality`
+    pub rate_control: Option<RateControl>,
+    // Encoder speed/efficiency trade-off: "slow" for x264/x265, "good" for VP9,
+    // a speed from 0 to 8 for AV1 or the profile name for ProRes
+    pub preset: Option<String>,
+    // x264/x265 `-tune` or VP9 `-tune-content`
+    pub tune: Option<String>,
+}
+
+/// How the video encoder spends bits. Bitrates are in kbit/s.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum RateControl {
+    // Constant quality (CRF for x264, CQ for VP9, the quantizer for MPEG-4)
+    Crf { crf: u32 },
+    // Average bitrate, the encoder may spend more on complex scenes
+    Abr { bitrate: u32 },
+    // Constant bitrate for streaming; `buffer_size` defaults to one second
+    Cbr { bitrate: u32, buffer_size: Option<u32> },
+    // Constant quality that never exceeds `max_bitrate`; `buffer_size` defaults to two seconds
+    CappedCrf { crf: u32, max_bitrate: u32, buffer_size: Option<u32> },
+}
+
+/// What a video codec supports, as far as the options go.
+#[derive(Debug)]
+pub struct VideoCodec {
+    pub name: &'static str,
+    // Encoders in order of preference, the first one the FFmpeg build has is used
+    encoders: &'static [&'static str],
+    // Quality scale for CRF modes, `None` when the codec has no quality mode
+    crf_range: Option<(u32, u32)>,
+    // CRF for the low/medium/high quality presets
+    quality_crf: [u32; 3],
+    bitrate_modes: bool,
+    capped_crf: bool,
+    presets: &'static [&'static str],
+    tunes: &'static [&'static str],
+    two_pass: bool,
+}
+
+const X264_PRESETS: &[&str] = &[
+    "ultrafast", "superfast", "veryfast", "faster", "fast", "medium", "slow", "slower", "veryslow", "placebo",
+];
+const X264_TUNES: &[&str] = &["film", "animation", "grain", "stillimage", "fastdecode", "zerolatency", "psnr", "ssim"];
+const X265_TUNES: &[&str] = &["psnr", "ssim", "grain", "zerolatency", "fastdecode", "animation"];
+// libvpx calls its presets deadlines
+const VP9_PRESETS: &[&str] = &["realtime", "good", "best"];
+const VP9_TUNES: &[&str] = &["default", "screen", "film"];
+// `-preset` for SVT-AV1, `-cpu-used` for libaom; lower is slower and better
+const AV1_PRESETS: &[&str] = &["0", "1", "2", "3", "4", "5", "6", "7", "8"];
+// Index is the prores_ks profile number
+const PRORES_PROFILES: &[&str] = &["proxy", "lt", "standard", "hq", "4444", "4444xq"];
+
+const VIDEO_CODECS: &[VideoCodec] = &[
+    VideoCodec {
+        name: "h264",
+        encoders: &["libx264"],
+        crf_range: Some((0, 51)),
+        quality_crf: [28, 23, 18],
+        bitrate_modes: true,
+        capped_crf: true,
+        presets: X264_PRESETS,
+        tunes: X264_TUNES,
+        two_pass: true,
+    },
+    VideoCodec {
+        name: "h265",
+        encoders: &["libx265"],
+        crf_range: Some((0, 51)),
+        quality_crf: [30, 26, 22],
+        bitrate_modes: true,
+        capped_crf: true,
+        presets: X264_PRESETS,
+        tunes: X265_TUNES,
+        two_pass: false,
+    },
+    VideoCodec {
+        name: "av1",
+        encoders: &["libsvtav1", "libaom-av1"],
+        crf_range: Some((0, 63)),
+        quality_crf: [40, 34, 26],
+        bitrate_modes: true,
+        capped_crf: true,
+        presets: AV1_PRESETS,
+        tunes: &[],
+        two_pass: false,
+    },
+    VideoCodec {
+        name: "vp9",
+        encoders: &["libvpx-vp9"],
+        crf_range: Some((0, 63)),
+        quality_crf: [28, 23, 18],
+        bitrate_modes: true,
+        capped_crf: true,
+        presets: VP9_PRESETS,
+        tunes: VP9_TUNES,
+        two_pass: true,
+    },
+    VideoCodec {
+        name: "mpeg4",
+        encoders: &["mpeg4"],
+        crf_range: Some((1, 31)),
+        quality_crf: [10, 5, 2],
+        bitrate_modes: true,
+        capped_crf: false,
+        presets: &[],
+        tunes: &[],
+        two_pass: true,
+    },
+    VideoCodec {
+        name: "prores",
+        encoders: &["prores_ks"],
+        crf_range: None,
+        quality_crf: [1, 2, 3],
+        bitrate_modes: false,
+        capped_crf: false,
+        presets: PRORES_PROFILES,
+        tunes: &[],
+        two_pass: false,
+    },
+    VideoCodec {
+        name: "ffv1",
+        encoders: &["ffv1"],
+        crf_range: None,
+        quality_crf: [0, 0, 0],
+        bitrate_modes: false,
+        capped_crf: false,
+        presets: &[],
+        tunes: &[],
+        two_pass: false,
+    },
+];
+
+// Codecs each video container can hold, the first one is the default
+fn container_codecs(format: &str) -> &'static [&'static str] {
+    match format {
+        "mp4" => &["h264", "h265", "av1", "vp9", "mpeg4"],
+        "m4v" => &["h264", "h265", "mpeg4"],
+        "mov" => &["h264", "h265", "mpeg4", "prores"],
+        "mkv" => &["h264", "h265", "av1", "vp9", "mpeg4", "prores", "ffv1"],
+        "webm" => &["vp9", "av1"],
+        "avi" => &["h264", "mpeg4", "ffv1"],
+        "flv" => &["h264"],
+        "wmv" => &["h264", "mpeg4"],
+        _ => &[],
+    }
+}
+
+/// Every encoder that can fill the video of a container, whichever of its
+/// codecs is picked.
+pub fn container_encoders(format: &str) -> Vec<&'static str> {
+    container_codecs(&format.to_lowercase())
+        .iter()
+        .filter_map(|name| find_codec(name))
+        .flat_map(|codec| codec.encoders.iter().copied())
+        .collect()
+}
+
+/// Whether the container can hold video of the named codec.
+pub fn container_accepts(format: &str, codec: &str) -> bool {
+    container_codecs(&format.to_lowercase()).contains(&codec)
+}
+
+/// Name in `VIDEO_CODECS` of a codec as ffprobe reports it.
+pub fn probed_codec_name(probe_codec: &str) -> Option<&'static str> {
+    match probe_codec {
+        "h264" => Some("h264"),
+        "hevc" => Some("h265"),
+        "av1" => Some("av1"),
+        "vp9" => Some("vp9"),
+        "mpeg4" => Some("mpeg4"),
+        "prores" => Some("prores"),
+        "ffv1" => Some("ffv1"),
+        _ => None,
+    }
+}
+
+pub fn find_codec(name: &str) -> Option<&'static VideoCodec> {
+    VIDEO_CODECS.iter().find(|codec| codec.name == name)
+}
+
+/// The codec a video output is encoded with, checked against the container.
+pub fn video_codec(format: &str, options: &VideoOptions) -> Result<&'static VideoCodec, String> {
+    let format = format.to_lowercase();
+    let allowed = container_codecs(&format);
+    let name = match options.codec.as_deref() {
+        Some(codec) if allowed.contains(&codec) => codec,
+        Some(codec) if find_codec(codec).is_none() => return Err(format!("Unknown video codec: {}", codec)),
+        Some(codec) => {
+            return Err(format!(
+                "{} video can't be stored in .{} files (supported: {})",
+                codec,
+                format,
+                allowed.join(", ")
+            ))
+        }
+        None => allowed
+            .first()
+            .ok_or_else(|| format!("{} is not a video format", format))?,
+    };
+    find_codec(name).ok_or_else(|| format!("Unknown video codec: {}", name))
+}
+
+impl VideoCodec {
+    /// Encoder to run, preferring the first one the FFmpeg build has. Only reads
+    /// capabilities detected earlier, without them the first one is assumed.
+    pub fn encoder(&self) -> &'static str {
+        if self.encoders.len() > 1 {
+            if let Some(capabilities) = capabilities::cached() {
+                if let Some(available) = self.encoders.iter().find(|e| capabilities.has_encoder(e)) {
+                    return available;
+                }
+            }
+        }
+        self.encoders[0]
+    }
+}
+
+/// Checks the codec, a numeric quality, rate control, preset and tune against
+/// the container and the codec that will run.
+pub fn validate_options(format: &str, options: &VideoOptions, quality: Option<&str>, v: &mut Validator) {
+    let codec = match video_codec(format, options) {
+        Ok(codec) => codec,
+        Err(message) => {
+            v.check(false, "options.video.codec", message);
+            return;
+        }
+    };
+
+    // A number is used as the CRF as it is
+    if let Some(crf) = quality.and_then(|quality| quality.parse::<u32>().ok()) {
+        match codec.crf_range {
+            Some((min, max)) => v.check(
+                (min..=max).contains(&crf),
+                "options.quality",
+                format!("must be between {} and {} for {}", min, max, codec.name),
+            ),
+            None => v.check(
+                false,
+                "options.quality",
+                format!("{} has no quality scale, use low, medium or high", codec.name),
+            ),
+        }
+    }
+
+    if options.target_size_mb.is_some() {
+        v.check(
+            codec.two_pass,
+            "options.video.target_size_mb",
+            format!("two-pass size targeting isn't supported for {}", codec.name),
+        );
+    }
+
+    if let Some(rate_control) = &options.rate_control {
+        let (crf, bitrates, supported) = match *rate_control {
+            RateControl::Crf { crf } => (Some(crf), vec![], codec.crf_range.is_some()),
+            RateControl::Abr { bitrate } => (None, vec![bitrate], codec.bitrate_modes),
+            RateControl::Cbr { bitrate, buffer_size } => (
+                None,
+                vec![bitrate, buffer_size.unwrap_or(bitrate)],
+                // SVT-AV1 has no constant bitrate mode for file encodes
+                codec.bitrate_modes && codec.encoder() != "libsvtav1",
+            ),
+            RateControl::CappedCrf { crf, max_bitrate, buffer_size } => {
+                (Some(crf), vec![max_bitrate, buffer_size.unwrap_or(max_bitrate)], codec.capped_crf)
+            }
+        };
+        v.check(
+            supported,
+            "options.video.rate_control",
+            format!("this rate control mode isn't supported for {}", codec.name),
+        );
+        if let (Some(crf), Some((min, max))) = (crf, codec.crf_range) {
+            v.check(
+                (min..=max).contains(&crf),
+                "options.video.rate_control.crf",
+                format!("must be between {} and {} for {}", min, max, codec.name),
+            );
+        }
+        v.check(
+            bitrates.iter().all(|kbps| (16..=200_000).contains(kbps)),
+            "options.video.rate_control",
+            "bitrates must be between 16 and 200000 kbit/s",
+        );
+        v.check(
+            options.target_size_mb.is_none(),
+            "options.video.rate_control",
+            "can't be combined with a target size",
+        );
+    }
+
+    if let Some(preset) = options.preset.as_deref() {
+        v.check(
+            codec.presets.contains(&preset),
+            "options.video.preset",
+            if codec.presets.is_empty() {
+                format!("{} has no presets", codec.name)
+            } else {
+                format!("{} presets are: {}", codec.name, codec.presets.join(", "))
+            },
+        );
+    }
+    if let Some(tune) = options.tune.as_deref() {
+        v.check(
+            codec.tunes.contains(&tune),
+            "options.video.tune",
+            if codec.tunes.is_empty() {
+                format!("{} has no tunes", codec.name)
+            } else {
+                format!("{} tunes are: {}", codec.name, codec.tunes.join(", "))
+            },
+        );
+    }
+}
+
+/// Rate control arguments, falling back to the CRF of the quality preset.
+/// Codecs without a quality scale get none.
+pub fn rate_control_args(codec: &VideoCodec, rate_control: Option<&RateControl>, quality: Option<&str>) -> Vec<String> {
+    let encoder = codec.encoder();
+    let kbps = |value: u32| format!("{}k", value);
+    let rate_control = match rate_control {
+        Some(rate_control) => *rate_control,
+        None if codec.crf_range.is_some() => RateControl::Crf {
+            crf: quality_crf(codec, quality),
+        },
+        None => return Vec::new(),
+    };
+    // libvpx and libaom only stay in constant quality mode with `-b:v 0`,
+    // and treat a bitrate next to `-crf` as the cap (constrained quality)
+    let libvpx_style = matches!(encoder, "libvpx-vp9" | "libaom-av1");
+
+    let args: Vec<(&str, String)> = match rate_control {
+        RateControl::Crf { crf } if encoder == "mpeg4" => vec![("-q:v", crf.to_string())],
+        RateControl::Crf { crf } if libvpx_style => vec![("-crf", crf.to_string()), ("-b:v", "0".to_string())],
+        RateControl::Crf { crf } => vec![("-crf", crf.to_string())],
+        RateControl::Abr { bitrate } => vec![("-b:v", kbps(bitrate))],
+        RateControl::Cbr { bitrate, buffer_size } => vec![
+            ("-b:v", kbps(bitrate)),
+            ("-minrate", kbps(bitrate)),
+            ("-maxrate", kbps(bitrate)),
+            ("-bufsize", kbps(buffer_size.unwrap_or(bitrate))),
+        ],
+        RateControl::CappedCrf { crf, max_bitrate, buffer_size } => {
+            let cap = if libvpx_style { "-b:v" } else { "-maxrate" };
+            vec![
+                ("-crf", crf.to_string()),
+                (cap, kbps(max_bitrate)),
+                ("-bufsize", kbps(buffer_size.unwrap_or(max_bitrate * 2))),
+            ]
+        }
+    };
+
+    args.into_iter().flat_map(|(flag, value)| [flag.to_string(), value]).collect()
+}
+
+/// Preset, tune and codec specific arguments. x264 keeps the "medium" preset
+/// for MP4 as before.
+pub fn tuning_args(codec: &VideoCodec, format: &str, options: &VideoOptions, quality: Option<&str>) -> Vec<String> {
+    let encoder = codec.encoder();
+    let mut args = Vec::new();
+    let mut push = |flag: &str, value: &str| args.extend([flag.to_string(), value.to_string()]);
+
+    match codec.name {
+        "prores" => {
+            // The preset picks the profile, otherwise the quality does
+            let profile = options
+                .preset
+                .as_deref()
+                .and_then(|name| PRORES_PROFILES.iter().position(|p| *p == name))
+                .unwrap_or(codec.quality_crf[quality_index(quality)] as usize);
+            push("-profile:v", &profile.to_string());
+            push("-vendor", "apl0");
+            push("-pix_fmt", if profile >= 4 { "yuva444p10le" } else { "yuv422p10le" });
+        }
+        "ffv1" => {
+            // Version 3 adds multithreading and per-slice checksums
+            push("-level", "3");
+            push("-slicecrc", "1");
+        }
+        _ => {
+            let preset = options
+                .preset
+                .as_deref()
+                .or((format == "mp4" && encoder == "libx264").then_some("medium"));
+            if let Some(preset) = preset {
+                let flag = match encoder {
+                    "libvpx-vp9" => "-deadline",
+                    "libaom-av1" => "-cpu-used",
+                    _ => "-preset",
+                };
+                push(flag, preset);
+            }
+            if let Some(tune) = options.tune.as_deref() {
+                let flag = if encoder == "libvpx-vp9" { "-tune-content" } else { "-tune" };
+                push(flag, tune);
+            }
+        }
+    }
+
+    // Apple players only recognise HEVC tagged as hvc1
+    if codec.name == "h265" && matches!(format, "mp4" | "mov" | "m4v") {
+        push("-tag:v", "hvc1");
+    }
+
+    args
+}
+
+/// Audio encoder that suits the container, instead of whatever FFmpeg defaults to.
+pub fn default_audio_encoder(format: &str, codec: &VideoCodec) -> &'static str {
+    match (format, codec.name) {
+        ("webm", _) => "libopus",
+        ("wmv", _) => "wmav2",
+        ("avi", "ffv1") => "pcm_s16le",
+        ("avi", _) => "libmp3lame",
+        // Keep intermediate codecs uncompressed all the way
+        (_, "prores" | "ffv1") => "pcm_s16le",
+        _ => "aac",
+    }
+}
+
+fn quality_index(quality: Option<&str>) -> usize {
+    match quality {
+        Some("low") => 0,
+        Some("high") => 2,
+        _ => 1,
+    }
+}
+
+// CRF for the "low"/"medium"/"high" presets, or a number given as quality
+fn quality_crf(codec: &VideoCodec, quality: Option<&str>) -> u32 {
+    match quality.map(|q| q.parse::<u8>()) {
+        Some(Ok(crf)) => crf.into(),
+        _ => codec.quality_crf[quality_index(quality)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(name: &str) -> &'static VideoCodec {
+        find_codec(name).unwrap()
+    }
+
+    #[test]
+    fn maps_rate_control_per_codec() {
+        let cases = [
+            ("h264", None, Some("high"), "-crf 18"),
+            ("vp9", None, None, "-crf 23 -b:v 0"),
+            ("mpeg4", None, Some("low"), "-q:v 10"),
+            ("prores", None, None, ""),
+            ("h264", Some(RateControl::Abr { bitrate: 2500 }), None, "-b:v 2500k"),
+            (
+                "h265",
+                Some(RateControl::Cbr { bitrate: 3000, buffer_size: None }),
+                None,
+                "-b:v 3000k -minrate 3000k -maxrate 3000k -bufsize 3000k",
+            ),
+            (
+                "h264",
+                Some(RateControl::CappedCrf { crf: 21, max_bitrate: 4000, buffer_size: None }),
+                Some("low"),
+                "-crf 21 -maxrate 4000k -bufsize 8000k",
+            ),
+            (
+                "vp9",
+                Some(RateControl::CappedCrf { crf: 31, max_bitrate: 2000, buffer_size: Some(3000) }),
+                None,
+                "-crf 31 -b:v 2000k -bufsize 3000k",
+            ),
+        ];
+
+        for (name, rate_control, quality, expected) in cases {
+            assert_eq!(
+                rate_control_args(codec(name), rate_control.as_ref(), quality).join(" "),
+                expected,
+                "{} {:?}",
+                name,
+                rate_control
+            );
+        }
+    }
+
+    #[test]
+    fn maps_presets_and_tunes() {
+        let options = VideoOptions {
+            preset: Some("good".to_string()),
+            tune: Some("screen".to_string()),
+            ..VideoOptions::default()
+        };
+        let defaults = VideoOptions::default();
+        let hq = VideoOptions {
+            preset: Some("hq".to_string()),
+            ..VideoOptions::default()
+        };
+
+        let cases = [
+            ("vp9", "webm", &options, None, "-deadline good -tune-content screen"),
+            ("h264", "mp4", &defaults, None, "-preset medium"),
+            ("h264", "mkv", &defaults, None, ""),
+            ("h265", "mp4", &defaults, None, "-tag:v hvc1"),
+            ("prores", "mov", &defaults, Some("low"), "-profile:v 1 -vendor apl0 -pix_fmt yuv422p10le"),
+            ("prores", "mov", &hq, Some("low"), "-profile:v 3 -vendor apl0 -pix_fmt yuv422p10le"),
+            ("ffv1", "mkv", &defaults, None, "-level 3 -slicecrc 1"),
+        ];
+        for (name, format, options, quality, expected) in cases {
+            assert_eq!(tuning_args(codec(name), format, options, quality).join(" "), expected, "{} in {}", name, format);
+        }
+    }
+
+    #[test]
+    fn matches_codecs_to_containers() {
+        let options = |codec: Option<&str>| VideoOptions {
+            codec: codec.map(str::to_string),
+            ..VideoOptions::default()
+        };
+
+        assert_eq!(video_codec("webm", &options(None)).unwrap().name, "vp9");
+        assert_eq!(video_codec("mov", &options(None)).unwrap().name, "h264");
+        assert_eq!(video_codec("mkv", &options(Some("ffv1"))).unwrap().name, "ffv1");
+        assert!(video_codec("webm", &options(Some("h264"))).is_err());
+        assert!(video_codec("mp4", &options(Some("prores"))).is_err());
+        assert!(video_codec("mp4", &options(Some("theora"))).is_err());
+
+        assert_eq!(default_audio_encoder("webm", codec("av1")), "libopus");
+        assert_eq!(default_audio_encoder("mov", codec("prores")), "pcm_s16le");
+        assert_eq!(default_audio_encoder("mp4", codec("h265")), "aac");
+    }
+
+    #[test]
+    fn validates_against_the_codec() {
+        let fields = |format: &str, options: &VideoOptions| {
+            let mut v = Validator::default();
+            validate_options(format, options, None, &mut v);
+            v.into_errors().into_iter().map(|e| e.field).collect::<Vec<_>>()
+        };
+        let options = VideoOptions {
+            rate_control: Some(RateControl::Crf { crf: 60 }),
+            preset: Some("slow".to_string()),
+            ..VideoOptions::default()
+        };
+
+        assert_eq!(fields("webm", &options), ["options.video.preset"]);
+        assert_eq!(fields("mp4", &options), ["options.video.rate_control.crf"]);
+
+        let prores = VideoOptions {
+            codec: Some("prores".to_string()),
+            rate_control: Some(RateControl::Abr { bitrate: 5000 }),
+            ..VideoOptions::default()
+        };
+        assert_eq!(fields("mov", &prores), ["options.video.rate_control"]);
+        assert_eq!(fields("webm", &prores), ["options.video.codec"]);
+    }
+
+    #[test]
+    fn validates_numeric_quality_per_codec() {
+        let defaults = VideoOptions::default();
+        let prores = VideoOptions {
+            codec: Some("prores".to_string()),
+            ..VideoOptions::default()
+        };
+        let mpeg4 = VideoOptions {
+            codec: Some("mpeg4".to_string()),
+            ..VideoOptions::default()
+        };
+
+        let cases = [
+            ("mp4", &defaults, "23", true),
+            ("mp4", &defaults, "80", false),
+            ("webm", &defaults, "63", true),
+            ("webm", &defaults, "64", false),
+            ("mp4", &mpeg4, "0", false),
+            ("mp4", &mpeg4, "31", true),
+            ("mov", &prores, "2", false),
+            ("mov", &prores, "high", true),
+        ];
+        for (format, options, quality, valid) in cases {
+            let mut v = Validator::default();
+            validate_options(format, options, Some(quality), &mut v);
+            let codec = options.codec.as_deref().unwrap_or("default");
+            assert_eq!(v.into_errors().is_empty(), valid, "{} for {} in {}", quality, codec, format);
+        }
+    }
+}
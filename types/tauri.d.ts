@@ -1,3 +1,43 @@
+export type StreamKind = 'video' | 'audio' | 'subtitle' | 'attachment' | 'data' | 'unknown';
+
+export interface StreamInfo {
+  index: number;
+  kind: StreamKind;
+  codec?: string;
+  codec_long_name?: string;
+  profile?: string;
+  level?: number; // e.g. 41 for H.264 level 4.1
+  bitrate?: number;
+  duration?: number;
+  language?: string;
+  title?: string;
+  is_default: boolean;
+  width?: number;
+  height?: number;
+  fps?: number;
+  pixel_format?: string;
+  bit_depth?: number;
+  color_space?: string;
+  color_transfer?: string;
+  color_primaries?: string;
+  color_range?: string;
+  rotation?: number;
+  sample_rate?: number;
+  channels?: number;
+  channel_layout?: string;
+  sample_format?: string;
+  filename?: string;
+  mime_type?: string;
+  tags: Record<string, string>;
+}
+
+export interface ChapterInfo {
+  id: number;
+  start: number;
+  end: number;
+  title?: string;
+}
+
 export interface MediaInfo {
   duration?: number;
   width?: number;
@@ -7,6 +47,136 @@ export interface MediaInfo {
   bitrate?: string;
   fps?: number;
   file_size?: number;
+  format_name?: string;
+  format_long_name?: string;
+  streams: StreamInfo[];
+  chapters: ChapterInfo[];
+  tags: Record<string, string>;
+}
+
+export type BinarySource = 'override' | 'environment' | 'bundled' | 'system';
+
+export interface BinaryInfo {
+  path: string;
+  source: BinarySource;
+  version: string;
+}
+
+export interface FfmpegInfo {
+  ffmpeg: BinaryInfo;
+  ffprobe: BinaryInfo;
+}
+
+export interface Encoder {
+  name: string;
+  kind: 'video' | 'audio' | 'subtitle';
+  description: string;
+}
+
+export interface Capabilities {
+  encoders: Encoder[];
+  muxers: string[];
+  filters: string[];
+  hwaccels: string[];
+  formats: string[];
+}
+
+export type ErrorKind =
+  | 'binary_missing'
+  | 'input_unreadable'
+  | 'unsupported_codec'
+  | 'encoder_missing'
+  | 'disk_full'
+  | 'permission_denied'
+  | 'invalid_options'
+  | 'cancelled'
+  | 'output_missing'
+  | 'size_target_missed'
+  | 'unknown';
+
+// Rejection value of the conversion commands
+export interface ConversionError {
+  kind: ErrorKind;
+  message: string;
+  fields?: { field: string; message: string }[];
+  ffmpeg_log: string[]; // last lines of FFmpeg's stderr
+  exit_code?: number;
+}
+
+export type JobStatus = 'queued' | 'running' | 'completed' | 'failed' | 'cancelled';
+
+export interface ConversionJob {
+  id: number;
+  input_path: string;
+  output_path: string;
+  format: string;
+  status: JobStatus;
+  error?: ConversionError;
+}
+
+export interface ConversionProgress {
+  job_id: number;
+  frame?: number;
+  fps?: number;
+  bitrate?: string;
+  total_size?: number;
+  out_time_us?: number;
+  speed?: number;
+  average_speed?: number;
+  instant_speed?: number;
+  throughput?: number;
+  elapsed_seconds: number;
+  eta_seconds?: number;
+  progress?: string;
+  percentage?: number;
+  pass: number; // 1-based encoder pass
+  passes: number;
+}
+
+export type StreamAction = 'copy' | 'transcode' | 'drop';
+
+// Returned by `get_remux_plan` and sent with the `remux-plan` event
+export interface StreamPlan {
+  index: number;
+  kind: StreamKind;
+  codec?: string;
+  action: StreamAction;
+  encoder?: string; // set for transcoded streams
+  reason: string;
+}
+
+export interface RemuxReport {
+  job_id: number;
+  streams: StreamPlan[];
+}
+
+export interface SplitPart {
+  index: number;
+  path: string;
+  start: number;
+  end: number | null;
+  title: string | null;
+}
+
+export interface SplitManifest {
+  job_id: number;
+  input_path: string;
+  parts: SplitPart[];
+}
+
+export interface CropRect {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+// Result of `detect_crop`; `crop` is null when there are no borders to remove
+export interface CropDetection {
+  crop: CropRect | null;
+  width: number;
+  height: number;
+  samples: number;
 }
 
 declare global {
@@ -3,20 +3,172 @@ import type { FileWithPath } from '@/stores/fileStore';
 import { useOptionStore } from '@/stores/optionStore';
 import { generateOutputPath } from './fileHelpers';
 
+export interface AudioOptions {
+  codec?: 'mp3' | 'aac' | 'alac' | 'opus' | 'vorbis' | 'flac' | 'pcm' | 'wma';
+  bitrate?: number; // kbit/s
+  vbr_quality?: number;
+  sample_rate?: number;
+  channels?: number;
+  bit_depth?: number;
+  stream_index?: number; // probed index of the audio track to keep
+  stream_copy?: boolean; // undefined = copy when the container allows it
+}
+
+// Seconds, or a clock time like '1:30' or '00:01:30.500'
+export type Timestamp = number | string;
+
+export interface TrimSegment {
+  start: Timestamp;
+  end?: Timestamp; // open-ended segments keep the rest of the input
+}
+
+export type VideoCodec = 'h264' | 'h265' | 'av1' | 'vp9' | 'mpeg4' | 'prores' | 'ffv1';
+
+// Video rate control, bitrates in kbit/s
+export type RateControl =
+  | { mode: 'crf'; crf: number }
+  | { mode: 'abr'; bitrate: number }
+  | { mode: 'cbr'; bitrate: number; buffer_size?: number }
+  | { mode: 'capped_crf'; crf: number; max_bitrate: number; buffer_size?: number };
+
+// How width and height apply when both are set; a single dimension keeps the aspect ratio
+export type FitMode = 'stretch' | 'contain' | 'pad' | 'cover';
+
+// Part of the picture a cover fit keeps
+export type Anchor =
+  | 'center' | 'top' | 'bottom' | 'left' | 'right'
+  | 'top_left' | 'top_right' | 'bottom_left' | 'bottom_right';
+
+export interface ScaleOptions {
+  fit?: FitMode;
+  pad_color?: string; // color name or #RRGGBB[AA], pad fit only
+  anchor?: Anchor; // cover fit only
+  max_dimension?: number; // caps the longer side, never enlarges
+}
+
+export type Crop =
+  | { mode: 'rect'; x: number; y: number; width: number; height: number }
+  | { mode: 'aspect'; ratio: string; anchor?: Anchor } // ratio like "9:16"
+  | { mode: 'auto' }; // black borders detected in the input
+
+// Applied before scaling: crop, rotate, then flip
+export interface TransformOptions {
+  crop?: Crop;
+  rotate?: number; // degrees clockwise; other than multiples of 90 the canvas grows
+  fill_color?: string; // corners uncovered by an arbitrary angle, "none" for transparency
+  flip_horizontal?: boolean;
+  flip_vertical?: boolean;
+  source_rotation?: 'auto' | 'ignore'; // turn phone footage upright first, or use the stored pixels
+}
+
+// Playback speed; the compress duration mode picks the factor itself
+export interface SpeedOptions {
+  factor?: number; // 2 plays twice as fast, between 0.01 and 100
+  preserve_pitch?: boolean; // defaults to true
+  frames?: 'duplicate' | 'interpolate'; // how slow motion gets its extra frames
+}
+
+// Boomerang plays the clip forward, then backwards
+export type Playback = 'forward' | 'reverse' | 'boomerang';
+
+// Payload of the `start_conversion` / `enqueue_conversion` commands
+export interface ConversionRequest {
+  input_path: string;
+  output_path: string;
+  format: string;
+  options?: {
+    quality?: string | number;
+    video?: ScaleOptions & TransformOptions & {
+      width?: number;
+      height?: number;
+      fps?: number;
+      codec?: VideoCodec; // defaults to the usual codec of the container
+      target_size_mb?: number;
+      rate_control?: RateControl;
+      preset?: string;
+      tune?: string;
+    };
+    audio?: AudioOptions & { disabled?: boolean };
+    image?: ScaleOptions & TransformOptions & { width?: number; height?: number };
+    trim?: {
+      duration?: number;
+      mode?: 'trim' | 'compress';
+      start?: Timestamp;
+      end?: Timestamp;
+      segments?: TrimSegment[]; // cut out and joined in order
+      cut?: 'accurate' | 'fast' | 'smart'; // fast copies and snaps to keyframes, smart re-encodes only around cuts
+    };
+    speed?: SpeedOptions;
+    playback?: Playback;
+    remux?: boolean; // copy the streams the container can hold, re-encode the rest
+  };
+}
+
+// Payload of the `start_concat` / `enqueue_concat` commands; trim options don't apply
+export interface ConcatRequest {
+  input_paths: string[];
+  output_path: string;
+  format: string;
+  options?: Omit<NonNullable<ConversionRequest['options']>, 'trim'>;
+}
+
+export type SplitBy =
+  | { mode: 'duration'; seconds: number }
+  | { mode: 'size'; max_mb: number }
+  | { mode: 'chapters' };
+
+export interface SplitRequest {
+  input_path: string;
+  output_dir: string;
+  format: string;
+  by: SplitBy;
+  name_template?: string; // {name}, {index} and, for chapters, {title}; defaults to "{name}_{index}"
+  options?: Omit<NonNullable<ConversionRequest['options']>, 'trim'> & {
+    trim?: { cut?: 'accurate' | 'fast' };
+  };
+}
+
 export interface ConversionOptions {
   format: string;
   quality?: string | number;
   width?: number;
   height?: number;
-  bitrate?: string;
+  bitrate?: string; // kbit/s, switches from quality (CRF) to average bitrate
+  videoCodec?: VideoCodec;
+  rateControl?: RateControl;
+  preset?: string;
+  tune?: string;
+  remux?: boolean;
   destinationFolder?: string;
   maintainAspectRatio?: boolean;
+  fit?: FitMode;
+  padColor?: string;
+  anchor?: Anchor;
+  maxDimension?: number;
+  transform?: TransformOptions;
   // Video specific options
   duration?: number; // in seconds
   durationMode?: 'trim' | 'compress'; // Add duration mode
+  startTime?: Timestamp;
+  endTime?: Timestamp;
+  segments?: TrimSegment[];
+  cutMode?: 'accurate' | 'fast' | 'smart';
+  speed?: SpeedOptions;
+  playback?: Playback;
   fps?: number;
   keepFpsRatio?: boolean;
   disableAudio?: boolean; // Add disable audio option
+  // Audio output options
+  audio?: AudioOptions;
+}
+
+/**
+ * Readable message for a rejected conversion command
+ */
+export function conversionErrorMessage(error: unknown): string {
+  if (typeof error === 'string') return error;
+  if (error && typeof error === 'object' && 'message' in error) return String(error.message);
+  return 'Unknown error';
 }
 
 /**
@@ -37,7 +189,13 @@ export async function convertImage(file: FileWithPath, customOptions?: Partial<C
     destinationFolder: file.options?.destinationFolder || customOptions?.destinationFolder || imageDefaults.destinationFolder,
     width: file.options?.width || customOptions?.width || imageDefaults.resize.width,
     height: file.options?.height || customOptions?.height || imageDefaults.resize.height,
-    maintainAspectRatio: file.options?.maintainAspectRatio ?? customOptions?.maintainAspectRatio ?? imageDefaults.resize.maintainAspectRatio
+    maintainAspectRatio: file.options?.maintainAspectRatio ?? customOptions?.maintainAspectRatio ?? imageDefaults.resize.maintainAspectRatio,
+    fit: customOptions?.fit,
+    padColor: customOptions?.padColor,
+    anchor: customOptions?.anchor,
+    maxDimension: customOptions?.maxDimension,
+    transform: customOptions?.transform,
+    transform: customOptions?.transform
   };
 
   // Generate output path
@@ -45,15 +203,26 @@ export async function convertImage(file: FileWithPath, customOptions?: Partial<C
 
   console.log('Converting image:', file.name, 'with options:', conversionOptions);
 
-  // Call the backend conversion command
-  return invoke('start_conversion', {
-    inputPath: file.path,
-    outputPath: outputPath,
+  const request: ConversionRequest = {
+    input_path: file.path,
+    output_path: outputPath,
     format: conversionOptions.format,
-    quality: conversionOptions.quality?.toString(),
-    width: conversionOptions.width,
-    height: conversionOptions.height
-  });
+    options: {
+      quality: conversionOptions.quality?.toString(),
+      image: {
+        width: conversionOptions.width,
+        height: conversionOptions.height,
+        fit: conversionOptions.fit,
+        pad_color: conversionOptions.padColor,
+        anchor: conversionOptions.anchor,
+        max_dimension: conversionOptions.maxDimension,
+        ...conversionOptions.transform
+      }
+    }
+  };
+
+  // Call the backend conversion command
+  return invoke('start_conversion', { request });
 }
 
 /**
@@ -71,15 +240,32 @@ export async function convertVideo(file: FileWithPath, customOptions?: Partial<C
   const conversionOptions: ConversionOptions = {
     format: file.options?.format || customOptions?.format || videoDefaults.format,
     quality: customOptions?.quality || videoDefaults.quality,
-    bitrate: customOptions?.bitrate || videoDefaults.bitrate.toString(),
+    // Only an explicit bitrate overrides the quality based CRF
+    bitrate: customOptions?.bitrate,
+    videoCodec: customOptions?.videoCodec,
+    rateControl: customOptions?.rateControl,
+    preset: customOptions?.preset,
+    tune: customOptions?.tune,
+    remux: customOptions?.remux,
     destinationFolder: file.options?.destinationFolder || customOptions?.destinationFolder || videoDefaults.destinationFolder,
     width: file.options?.width || customOptions?.width || videoDefaults.resolution.width,
     height: file.options?.height || customOptions?.height || videoDefaults.resolution.height,
+    fit: customOptions?.fit,
+    padColor: customOptions?.padColor,
+    anchor: customOptions?.anchor,
+    maxDimension: customOptions?.maxDimension,
     duration: file.options?.duration || customOptions?.duration || videoDefaults.duration,
     durationMode: file.options?.durationMode || customOptions?.durationMode || 'trim', // Add duration mode
+    startTime: customOptions?.startTime,
+    endTime: customOptions?.endTime,
+    segments: customOptions?.segments,
+    cutMode: customOptions?.cutMode,
+    speed: customOptions?.speed,
+    playback: customOptions?.playback,
     fps: file.options?.fps || customOptions?.fps || videoDefaults.fps,
     keepFpsRatio: file.options?.keepFpsRatio ?? customOptions?.keepFpsRatio ?? videoDefaults.keepFpsRatio,
-    disableAudio: file.options?.disableAudio ?? customOptions?.disableAudio ?? false // Add disable audio option
+    disableAudio: file.options?.disableAudio ?? customOptions?.disableAudio ?? false, // Add disable audio option
+    audio: customOptions?.audio
   };
 
   // Generate output path
@@ -87,19 +273,116 @@ export async function convertVideo(file: FileWithPath, customOptions?: Partial<C
 
   console.log('Converting video:', file.name, 'with options:', conversionOptions);
 
-  // Call the backend conversion command
-  await invoke('start_conversion', {
-    inputPath: file.path,
-    outputPath,
+  const request: ConversionRequest = {
+    input_path: file.path,
+    output_path: outputPath,
     format: conversionOptions.format,
-    quality: conversionOptions.quality,
-    width: conversionOptions.width,
-    height: conversionOptions.height,
-    duration: conversionOptions.duration,
-    durationMode: conversionOptions.durationMode || 'trim', // Pass duration mode
-    fps: conversionOptions.fps,
-    disableAudio: conversionOptions.disableAudio // Pass disable audio option
-  });
+    options: {
+      quality: conversionOptions.quality,
+      video: {
+        width: conversionOptions.width,
+        height: conversionOptions.height,
+        fit: conversionOptions.fit,
+        pad_color: conversionOptions.padColor,
+        anchor: conversionOptions.anchor,
+        max_dimension: conversionOptions.maxDimension,
+        ...conversionOptions.transform,
+        fps: conversionOptions.fps,
+        codec: conversionOptions.videoCodec,
+        rate_control: conversionOptions.rateControl
+          ?? (conversionOptions.bitrate ? { mode: 'abr', bitrate: parseInt(conversionOptions.bitrate, 10) } : undefined),
+        preset: conversionOptions.preset,
+        tune: conversionOptions.tune
+      },
+      audio: {
+        ...conversionOptions.audio,
+        disabled: conversionOptions.disableAudio
+      },
+      trim: {
+        duration: conversionOptions.duration,
+        mode: conversionOptions.durationMode || 'trim',
+        start: conversionOptions.startTime,
+        end: conversionOptions.endTime,
+        segments: conversionOptions.segments,
+        cut: conversionOptions.cutMode
+      },
+      speed: conversionOptions.speed,
+      playback: conversionOptions.playback,
+      remux: conversionOptions.remux
+    }
+  };
+
+  // Call the backend conversion command
+  await invoke('start_conversion', { request });
+}
+
+/**
+ * Extract an audio track from a video file
+ */
+export async function extractAudio(file: FileWithPath, format: string, audio?: AudioOptions, destinationFolder?: string): Promise<void> {
+  if (!file.path) {
+    throw new Error('Cannot convert file without path');
+  }
+
+  const outputPath = generateOutputPath(file.name, format, destinationFolder ?? file.options?.destinationFolder, file.options?.outputName);
+
+  const request: ConversionRequest = {
+    input_path: file.path,
+    output_path: outputPath,
+    format,
+    options: { audio }
+  };
+
+  await invoke('start_conversion', { request });
+}
+
+/**
+ * Join several files in order into one output
+ */
+export async function joinFiles(files: FileWithPath[], format: string, destinationFolder?: string): Promise<void> {
+  const paths = files.map(file => file.path);
+  if (paths.length < 2 || paths.some(path => !path)) {
+    throw new Error('Joining needs at least two files with paths');
+  }
+
+  const outputPath = generateOutputPath(files[0].name, format, destinationFolder ?? files[0].options?.destinationFolder, files[0].options?.outputName);
+
+  const request: ConcatRequest = {
+    input_paths: paths as string[],
+    output_path: outputPath,
+    format
+  };
+
+  await invoke('start_concat', { request });
+}
+
+/**
+ * Split a file into parts by duration, size or chapter. The parts and a
+ * manifest of their time ranges are written to the destination folder.
+ */
+export async function splitFile(
+  file: FileWithPath,
+  format: string,
+  by: SplitBy,
+  destinationFolder?: string,
+  nameTemplate?: string
+): Promise<void> {
+  if (!file.path) {
+    throw new Error('File path is required for splitting');
+  }
+
+  // Next to the input unless a folder is given
+  const outputDir = destinationFolder ?? file.options?.destinationFolder ?? file.path.replace(/[\\/][^\\/]*$/, '');
+
+  const request: SplitRequest = {
+    input_path: file.path,
+    output_dir: outputDir,
+    format,
+    by,
+    name_template: nameTemplate
+  };
+
+  await invoke('start_split', { request });
 }
 
 /**